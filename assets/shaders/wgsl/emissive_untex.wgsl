@@ -1,7 +1,11 @@
 [[block]]
 struct MatFactors {
     diffuse: vec4<f32>;
-    emissive: vec4<f32>;
+    emissive: vec3<f32>;
+    specular_coefficient: f32;
+    specular: vec3<f32>;
+    alpha_cutoff: f32;
+    ambient: vec3<f32>;
 };
 
 [[group(2), binding(0)]]
@@ -18,5 +22,10 @@ struct VertexOutput {
 fn main(
     in: VertexOutput
 ) -> [[location(0)]] vec4<f32> {
-    return mat_factors.diffuse * mat_factors.emissive;
+    let final_color = mat_factors.diffuse * vec4<f32>(mat_factors.emissive, 1.0);
+    if (final_color.a < mat_factors.alpha_cutoff) {
+        discard;
+    }
+
+    return final_color;
 }
\ No newline at end of file
@@ -7,6 +7,7 @@ struct Globals {
 [[block]]
 struct Mesh {
     model: mat4x4<f32>;
+    receive_shadows: u32;
 };
 
 [[group(0), binding(0)]]
@@ -34,7 +35,7 @@ fn main(
 
     let frag_pos = (mesh.model * vec4<f32>(in_position, 1.0));
 
-    let frag_norm = in_normal;
+    let frag_norm = (mesh.model * vec4<f32>(in_normal, 0.0)).xyz;
     let tex_coord = in_tex_coord;
 
     let cam_pos = global.cam_pos;
@@ -0,0 +1,73 @@
+[[block]]
+struct ShadowView {
+    view_proj: mat4x4<f32>;
+};
+
+[[block]]
+struct Mesh {
+    model: mat4x4<f32>;
+    receive_shadows: u32;
+};
+
+[[block]]
+struct MatFactors {
+    diffuse: vec4<f32>;
+    emissive: vec3<f32>;
+    specular_coefficient: f32;
+    specular: vec3<f32>;
+    alpha_cutoff: f32;
+    ambient: vec3<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> shadow_view: ShadowView;
+
+[[group(1), binding(0)]]
+var<uniform> mesh: Mesh;
+
+// Only bound for textured cutout materials
+[[group(2), binding(0)]]
+var<uniform> mat_factors: MatFactors;
+
+[[group(2), binding(1)]]
+var tex_sampler: sampler;
+
+[[group(2), binding(2)]]
+var tex_color: texture_2d<f32>;
+
+[[group(2), binding(7)]]
+var dissolve_map: texture_2d<f32>;
+
+struct CutoutOutput {
+    [[builtin(position)]] position: vec4<f32>;
+    [[location(0)]] tex_coord: vec2<f32>;
+};
+
+// Only depth is written, into the light's shadow map
+[[stage(vertex)]]
+fn main(
+    [[location(0)]] in_position: vec3<f32>
+) -> [[builtin(position)]] vec4<f32> {
+    return (shadow_view.view_proj * mesh.model) * vec4<f32>(in_position, 1.0);
+}
+
+[[stage(vertex)]]
+fn cutout_vertex(
+    [[location(0)]] in_position: vec3<f32>,
+    [[location(2)]] in_tex_coord: vec2<f32>
+) -> CutoutOutput {
+    let position = (shadow_view.view_proj * mesh.model) * vec4<f32>(in_position, 1.0);
+    return CutoutOutput(position, in_tex_coord);
+}
+
+// Lets the light through wherever the lit shaders discard the material
+[[stage(fragment)]]
+fn cutout_fragment(
+    in: CutoutOutput
+) {
+    let base_color = mat_factors.diffuse * textureSample(tex_color, tex_sampler, in.tex_coord);
+    let alpha = base_color.a * textureSample(dissolve_map, tex_sampler, in.tex_coord).r;
+    if (alpha < mat_factors.alpha_cutoff) {
+        discard;
+    }
+}
@@ -0,0 +1,191 @@
+[[block]]
+struct MatFactors {
+    diffuse: vec4<f32>;
+    emissive: vec3<f32>;
+    specular_coefficient: f32;
+    specular: vec3<f32>;
+    alpha_cutoff: f32;
+    ambient: vec3<f32>;
+};
+
+struct Light {
+    position: vec3<f32>;
+    kind: u32;
+    direction: vec3<f32>;
+    range: f32;
+    color: vec3<f32>;
+    inner_cos: f32;
+    outer_cos: f32;
+    shadow_map: i32;
+    depth_bias: f32;
+    normal_bias: f32;
+};
+
+[[block]]
+struct Lights {
+    ambient: vec3<f32>;
+    count: u32;
+    lights: array<Light, 16>;
+    shadow_view_proj: array<mat4x4<f32>, 4>;
+};
+
+[[block]]
+struct Mesh {
+    model: mat4x4<f32>;
+    receive_shadows: u32;
+};
+
+let LIGHT_DIRECTIONAL: u32 = 0u;
+let LIGHT_SPOT: u32 = 2u;
+
+[[group(0), binding(1)]]
+var<uniform> lights: Lights;
+
+[[group(0), binding(2)]]
+var shadow_maps: texture_depth_2d_array;
+
+[[group(0), binding(3)]]
+var shadow_sampler: sampler_comparison;
+
+[[group(1), binding(0)]]
+var<uniform> mesh: Mesh;
+
+[[group(2), binding(0)]]
+var<uniform> mat_factors: MatFactors;
+
+[[group(2), binding(1)]]
+var tex_sampler: sampler;
+
+[[group(2), binding(2)]]
+var tex_color: texture_2d<f32>;
+
+[[group(2), binding(3)]]
+var ambient_map: texture_2d<f32>;
+
+[[group(2), binding(4)]]
+var specular_map: texture_2d<f32>;
+
+[[group(2), binding(6)]]
+var normal_map: texture_2d<f32>;
+
+[[group(2), binding(7)]]
+var dissolve_map: texture_2d<f32>;
+
+struct VertexOutput {
+    [[location(0)]] frag_pos: vec4<f32>;
+    [[location(1)]] frag_norm: vec3<f32>;
+    [[location(2)]] tex_coord: vec2<f32>;
+    [[location(3)]] cam_pos: vec3<f32>;
+};
+
+// How much of the light reaches `world_pos` past its shadow casters,
+// averaged over the neighbouring texels of the shadow map (percentage-closer filtering)
+fn shadow_factor(light: Light, world_pos: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if (light.shadow_map < 0 || mesh.receive_shadows == 0u) {
+        return 1.0;
+    }
+    let biased_pos = world_pos + normal * light.normal_bias;
+    let clip = lights.shadow_view_proj[light.shadow_map] * vec4<f32>(biased_pos, 1.0);
+    // Behind a spot light
+    if (clip.w <= 0.0) {
+        return 1.0;
+    }
+    let ndc = clip.xyz / clip.w;
+    // Outside of the area covered by the shadow map
+    if (abs(ndc.x) > 1.0 || abs(ndc.y) > 1.0 || ndc.z > 1.0) {
+        return 1.0;
+    }
+    let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    let size = textureDimensions(shadow_maps);
+    let texel = vec2<f32>(1.0 / f32(size.x), 1.0 / f32(size.y));
+    let depth = ndc.z - light.depth_bias;
+
+    var lit: f32 = 0.0;
+    for (var x: i32 = -1; x <= 1; x = x + 1) {
+        for (var y: i32 = -1; y <= 1; y = y + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel;
+            lit = lit + textureSampleCompareLevel(shadow_maps, shadow_sampler, uv + offset, light.shadow_map, depth);
+        }
+    }
+    return lit / 9.0;
+}
+
+// Applies the normal map in the tangent space made of the UV's screen-space derivatives
+// (so meshes don't need tangents)
+fn perturb_normal(normal: vec3<f32>, pos: vec3<f32>, uv: vec2<f32>) -> vec3<f32> {
+    let dp1 = dpdx(pos);
+    let dp2 = dpdy(pos);
+    let duv1 = dpdx(uv);
+    let duv2 = dpdy(uv);
+
+    let dp2perp = cross(dp2, normal);
+    let dp1perp = cross(normal, dp1);
+    let tangent = dp2perp * duv1.x + dp1perp * duv2.x;
+    let bitangent = dp2perp * duv1.y + dp1perp * duv2.y;
+    let len = max(dot(tangent, tangent), dot(bitangent, bitangent));
+
+    let mapped = textureSample(normal_map, tex_sampler, uv).xyz * 2.0 - 1.0;
+    // Without UVs to follow, the normal is left alone
+    if (len <= 0.0) {
+        return normal;
+    }
+    let scale = inverseSqrt(len);
+    return normalize(mat3x3<f32>(tangent * scale, bitangent * scale, normal) * mapped);
+}
+
+[[stage(fragment)]]
+fn main(
+    in: VertexOutput
+) -> [[location(0)]] vec4<f32> {
+    let normal = perturb_normal(normalize(in.frag_norm), in.frag_pos.xyz, in.tex_coord);
+    let view_dir = normalize(in.cam_pos - in.frag_pos.xyz);
+
+    var diffuse: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+    var specular: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+
+    // Blinn-Phong, summed over every active light
+    for (var i: u32 = 0u; i < lights.count; i = i + 1u) {
+        let light = lights.lights[i];
+
+        var to_light: vec3<f32> = -light.direction;
+        var attenuation: f32 = 1.0;
+        if (light.kind != LIGHT_DIRECTIONAL) {
+            let offset = light.position - in.frag_pos.xyz;
+            let dist = length(offset);
+            to_light = offset / dist;
+
+            let falloff = clamp(1.0 - dist / light.range, 0.0, 1.0);
+            attenuation = falloff * falloff;
+
+            if (light.kind == LIGHT_SPOT) {
+                let cos_angle = dot(-to_light, light.direction);
+                let edge = max(light.inner_cos - light.outer_cos, 0.0001);
+                let t = clamp((cos_angle - light.outer_cos) / edge, 0.0, 1.0);
+                attenuation = attenuation * t * t * (3.0 - 2.0 * t);
+            }
+        }
+
+        attenuation = attenuation * shadow_factor(light, in.frag_pos.xyz, normal);
+
+        let n_dot_l = max(dot(normal, to_light), 0.0);
+        let half_dir = normalize(to_light + view_dir);
+        let highlight = pow(max(dot(normal, half_dir), 0.0), mat_factors.specular_coefficient);
+
+        diffuse = diffuse + light.color * (n_dot_l * attenuation);
+        specular = specular + light.color * (select(0.0, highlight, n_dot_l > 0.0) * attenuation);
+    }
+
+    let tex_color = textureSample(tex_color, tex_sampler, in.tex_coord);
+    let base_color = mat_factors.diffuse * tex_color;
+    let ambient = lights.ambient * mat_factors.ambient * textureSample(ambient_map, tex_sampler, in.tex_coord).rgb;
+    let specular_color = mat_factors.specular * textureSample(specular_map, tex_sampler, in.tex_coord).rgb;
+    let alpha = base_color.a * textureSample(dissolve_map, tex_sampler, in.tex_coord).r;
+
+    if (alpha < mat_factors.alpha_cutoff) {
+        discard;
+    }
+
+    let lit = (ambient + diffuse) * base_color.rgb + specular * specular_color;
+
+    return vec4<f32>(lit, alpha);
+}
@@ -1,7 +1,11 @@
 [[block]]
 struct MatFactors {
     diffuse: vec4<f32>;
-    emissive: vec4<f32>;
+    emissive: vec3<f32>;
+    specular_coefficient: f32;
+    specular: vec3<f32>;
+    alpha_cutoff: f32;
+    ambient: vec3<f32>;
 };
 
 [[group(2), binding(0)]]
@@ -13,6 +17,9 @@ var tex_sampler: sampler;
 [[group(2), binding(2)]]
 var tex_color: texture_2d<f32>;
 
+[[group(2), binding(7)]]
+var dissolve_map: texture_2d<f32>;
+
 struct VertexOutput {
     [[location(0)]] frag_pos: vec4<f32>;
     [[location(1)]] frag_norm: vec3<f32>;
@@ -26,8 +33,14 @@ fn main(
 ) -> [[location(0)]] vec4<f32> {
 
     let tex_color = textureSample(tex_color, tex_sampler, in.tex_coord);
-    
+    let dissolve = textureSample(dissolve_map, tex_sampler, in.tex_coord).r;
+
     let final_color: vec4<f32> = mat_factors.diffuse * tex_color;
-    
-    return final_color;
+
+    let alpha = final_color.a * dissolve;
+    if (alpha < mat_factors.alpha_cutoff) {
+        discard;
+    }
+
+    return vec4<f32>(final_color.rgb, alpha);
 }
\ No newline at end of file
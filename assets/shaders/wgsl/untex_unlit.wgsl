@@ -1,7 +1,11 @@
 [[block]]
 struct MatFactors {
     diffuse: vec4<f32>;
-    emissive: vec4<f32>;
+    emissive: vec3<f32>;
+    specular_coefficient: f32;
+    specular: vec3<f32>;
+    alpha_cutoff: f32;
+    ambient: vec3<f32>;
 };
 
 [[group(2), binding(0)]]
@@ -16,5 +20,9 @@ struct VertexOutput {
 
 [[stage(fragment)]]
 fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    if (mat_factors.diffuse.a < mat_factors.alpha_cutoff) {
+        discard;
+    }
+
     return mat_factors.diffuse;
 }
\ No newline at end of file
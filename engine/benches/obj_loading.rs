@@ -0,0 +1,68 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::path::Path;
+
+use engine::assets::obj;
+use wavefront_obj::obj::ObjSet;
+
+/// A flat grid of `size` by `size` quads, with shared vertices like an exported terrain
+fn grid(size: usize) -> ObjSet {
+    let mut text = String::from("o Grid\n");
+    for y in 0..=size {
+        for x in 0..=size {
+            text += &format!("v {} {} 0\nvt {} {}\n", x, y, x, y);
+        }
+    }
+    text += "vn 0 0 1\ns off\n";
+    let index = |x: usize, y: usize| y * (size + 1) + x + 1;
+    for y in 0..size {
+        for x in 0..size {
+            let corners = [
+                index(x, y),
+                index(x + 1, y),
+                index(x + 1, y + 1),
+                index(x, y + 1),
+            ];
+            text += "f";
+            for i in corners {
+                text += &format!(" {}/{}/1", i, i);
+            }
+            text += "\n";
+        }
+    }
+    wavefront_obj::obj::parse(text).unwrap()
+}
+
+fn bundled_model(name: &str) -> ObjSet {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../assets/models")
+        .join(name);
+    wavefront_obj::obj::parse(std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+fn build_triangles(set: &ObjSet) {
+    for object in &set.objects {
+        for (_, geometries) in obj::group_by_material(object) {
+            black_box(obj::triangles(object, &geometries));
+        }
+    }
+}
+
+fn bench_triangles(c: &mut Criterion) {
+    let mut group = c.benchmark_group("obj triangles");
+    for name in ["player.obj", "warsztaty.obj"] {
+        let set = bundled_model(name);
+        group.bench_with_input(BenchmarkId::new("model", name), &set, |b, set| {
+            b.iter(|| build_triangles(set))
+        });
+    }
+    for size in [64, 256] {
+        let set = grid(size);
+        group.bench_with_input(BenchmarkId::new("grid", size), &set, |b, set| {
+            b.iter(|| build_triangles(set))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_triangles);
+criterion_main!(benches);
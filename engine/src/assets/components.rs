@@ -0,0 +1,307 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use legion::{storage::Component, world::Entry, Entity, IntoQuery, World};
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, EnumAccess, SeqAccess, VariantAccess},
+    Deserializer, Serialize, Serializer,
+};
+
+type DeserializeFn = dyn for<'de> Fn(
+        &mut dyn erased_serde::Deserializer<'de>,
+    ) -> Result<Box<dyn SceneComponent>, erased_serde::Error>
+    + Send
+    + Sync;
+type ExtractFn = dyn Fn(&World, Entity) -> Option<Box<dyn SceneComponent>> + Send + Sync;
+
+#[derive(Clone)]
+struct Registration {
+    name: &'static str,
+    deserialize: Arc<DeserializeFn>,
+    extract: Arc<ExtractFn>,
+}
+
+/// Components which scene objects can list by name, e.g.
+/// `components: [Collider(Capsule(2.0, 0.4)), Velocity((linear: [0.0, 0.0, 1.0], angular: [0.0, 0.0, 0.0]))]`.
+///
+/// Every registered component gets a RON name; the data in parentheses after the name
+/// is deserialized and inserted onto the entities spawned for the object.
+/// The data is written the same way as anywhere else in RON, so structs keep their own
+/// parentheses (like the Velocity above).
+#[derive(Clone, Default)]
+pub struct ComponentRegistry {
+    components: HashMap<&'static str, Registration>,
+}
+
+impl ComponentRegistry {
+    /// Registers a component which is stored in the scene file as-is
+    pub fn register<T>(&mut self, name: &'static str)
+    where
+        T: Component + Clone + Serialize + DeserializeOwned,
+    {
+        self.register_as::<T, T>(name, T::clone, |component| Some(component.clone()));
+    }
+
+    /// Registers a component which is stored in the scene file as `D`.
+    /// Useful for components that can't be (de)serialized themselves, like Colliders.
+    /// `describe` turns a component back into its scene form, if it has one.
+    pub fn register_as<D, T>(
+        &mut self,
+        name: &'static str,
+        into: fn(&D) -> T,
+        describe: fn(&T) -> Option<D>,
+    ) where
+        D: Serialize + DeserializeOwned + Send + Sync + 'static,
+        T: Component,
+    {
+        let deserialize = move |deserializer: &mut dyn erased_serde::Deserializer| {
+            let data: D = erased_serde::deserialize(deserializer)?;
+            Ok(Box::new(Stored { data, into }) as Box<dyn SceneComponent>)
+        };
+        let extract = move |world: &World, entity: Entity| {
+            let component = <&T>::query().get(world, entity).ok()?;
+            let data = describe(component)?;
+            Some(Box::new(Stored { data, into }) as Box<dyn SceneComponent>)
+        };
+        self.components.insert(
+            name,
+            Registration {
+                name,
+                deserialize: Arc::new(deserialize),
+                extract: Arc::new(extract),
+            },
+        );
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.components.contains_key(name)
+    }
+
+    /// Reads the component called `name` from an entity, in the form it's stored in a scene
+    pub(crate) fn extract(
+        &self,
+        name: &str,
+        world: &World,
+        entity: Entity,
+    ) -> Option<ComponentData> {
+        let registration = self.components.get(name)?;
+        (registration.extract)(world, entity).map(|value| ComponentData {
+            name: registration.name,
+            value,
+        })
+    }
+}
+
+/// A component stored in its scene form, along with how to turn it into the component
+trait SceneComponent: Send + Sync {
+    fn add_to(&self, entry: &mut Entry);
+    fn as_serialize(&self) -> &dyn erased_serde::Serialize;
+}
+
+struct Stored<D, T> {
+    data: D,
+    into: fn(&D) -> T,
+}
+
+impl<D, T> SceneComponent for Stored<D, T>
+where
+    D: Serialize + Send + Sync,
+    T: Component,
+{
+    fn add_to(&self, entry: &mut Entry) {
+        entry.add_component((self.into)(&self.data));
+    }
+
+    fn as_serialize(&self) -> &dyn erased_serde::Serialize {
+        &self.data
+    }
+}
+
+/// A registered component listed in a scene object, written as `Name(data)`
+pub struct ComponentData {
+    name: &'static str,
+    value: Box<dyn SceneComponent>,
+}
+
+impl ComponentData {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Inserts the component onto an entity (replacing the one it already has)
+    pub fn add_to(&self, entry: &mut Entry) {
+        self.value.add_to(entry);
+    }
+}
+
+impl Serialize for ComponentData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_variant("Component", 0, self.name, self.value.as_serialize())
+    }
+}
+
+/// Deserializes a `ComponentData` with the components of a registry.
+/// Serde can't pass context into Deserialize impls, so everything containing components
+/// is deserialized with a seed instead (see `data::SceneSeed`).
+#[derive(Clone, Copy)]
+pub(crate) struct ComponentSeed<'a>(pub &'a ComponentRegistry);
+
+impl<'de, 'a> DeserializeSeed<'de> for ComponentSeed<'a> {
+    type Value = ComponentData;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_enum("Component", &[], ComponentVisitor(self.0))
+    }
+}
+
+/// Deserializes a list of components, like `ComponentSeed`
+#[derive(Clone, Copy)]
+pub(crate) struct ComponentListSeed<'a>(pub &'a ComponentRegistry);
+
+impl<'de, 'a> DeserializeSeed<'de> for ComponentListSeed<'a> {
+    type Value = Vec<ComponentData>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> de::Visitor<'de> for ComponentListSeed<'a> {
+    type Value = Vec<ComponentData>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of registered components")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut components = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(component) = seq.next_element_seed(ComponentSeed(self.0))? {
+            components.push(component);
+        }
+        Ok(components)
+    }
+}
+
+struct ComponentVisitor<'a>(&'a ComponentRegistry);
+
+impl<'de, 'a> de::Visitor<'de> for ComponentVisitor<'a> {
+    type Value = ComponentData;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a registered component")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        let (name, variant) = data.variant_seed(ComponentName)?;
+        let registration = self
+            .0
+            .components
+            .get(name.as_str())
+            .ok_or_else(|| de::Error::custom(format!("Unknown component: {}", name)))?;
+        let value = variant.newtype_variant_seed(RegisteredSeed(registration))?;
+        Ok(ComponentData {
+            name: registration.name,
+            value,
+        })
+    }
+}
+
+/// Reads the name of a component as an identifier (a String would have to be quoted)
+struct ComponentName;
+
+impl<'de> DeserializeSeed<'de> for ComponentName {
+    type Value = String;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> de::Visitor<'de> for ComponentName {
+    type Value = String;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a component name")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Self::Value, E> {
+        Ok(name.to_owned())
+    }
+}
+
+struct RegisteredSeed<'a>(&'a Registration);
+
+impl<'de, 'a> DeserializeSeed<'de> for RegisteredSeed<'a> {
+    type Value = Box<dyn SceneComponent>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let mut erased = <dyn erased_serde::Deserializer>::erase(deserializer);
+        (self.0.deserialize)(&mut erased).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::ron_from_str_seed;
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Health {
+        points: u32,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Speed(f32);
+
+    fn registry() -> ComponentRegistry {
+        let mut registry = ComponentRegistry::default();
+        registry.register::<Health>("Health");
+        registry.register_as::<f32, Speed>("Speed", |&speed| Speed(speed), |speed| Some(speed.0));
+        registry
+    }
+
+    #[test]
+    fn test_components_round_trip() {
+        let registry = registry();
+        let components = ron_from_str_seed(
+            "[Health((points: 10)), Speed(2.5)]",
+            ComponentListSeed(&registry),
+        )
+        .unwrap();
+
+        let mut world = World::default();
+        let entity = world.push(());
+        let mut entry = world.entry(entity).unwrap();
+        for component in &components {
+            component.add_to(&mut entry);
+        }
+        assert_eq!(
+            entry.get_component::<Health>().unwrap(),
+            &Health { points: 10 }
+        );
+        assert_eq!(entry.get_component::<Speed>().unwrap(), &Speed(2.5));
+
+        let extracted: Vec<_> = ["Health", "Speed"]
+            .iter()
+            .map(|name| registry.extract(name, &world, entity).unwrap())
+            .collect();
+        assert_eq!(
+            ron::to_string(&extracted).unwrap(),
+            ron::to_string(&components).unwrap()
+        );
+        assert_eq!(
+            ron::to_string(&extracted).unwrap(),
+            "[Health((points:10)),Speed(2.5)]"
+        );
+    }
+
+    #[test]
+    fn test_unknown_components_are_errors() {
+        let registry = registry();
+        let result = ron_from_str_seed(
+            "[Health((points: 1)), Mana(3)]",
+            ComponentListSeed(&registry),
+        );
+        assert!(result.is_err());
+    }
+}
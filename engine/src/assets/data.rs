@@ -1,12 +1,87 @@
 #![allow(clippy::from_over_into)]
 
+use eyre::{eyre::eyre, Result};
+
+use std::path::PathBuf;
+
+use super::{
+    components::{ComponentData, ComponentListSeed, ComponentRegistry},
+    handle::Handle,
+};
 use crate::{
     graphics::{color, mesh::Vertex},
+    physics::Collider,
     spacetime,
 };
 
 pub struct MeshData {
     pub parts: Vec<MeshPartData>,
+    pub node: MeshNode,
+}
+
+/// Where a mesh is in its model. Only models with a node hierarchy (glTF) move their
+/// meshes around; the objects of an OBJ file all sit at the model's origin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshNode {
+    /// Relative to the parent mesh, or to the model's origin without one
+    pub transform: na::Isometry3<f32>,
+    /// Index of the parent mesh in the same model, which always comes before its children
+    pub parent: Option<usize>,
+}
+
+impl Default for MeshNode {
+    fn default() -> Self {
+        MeshNode {
+            transform: na::Isometry3::identity(),
+            parent: None,
+        }
+    }
+}
+
+impl MeshData {
+    /// Builds a collider from the geometry of every part of the mesh.
+    /// Colliders can't be scaled, so the scale (if any) gets baked into the vertices.
+    pub fn collider(
+        &self,
+        shape: MeshCollider,
+        scale: Option<&spacetime::Scale>,
+    ) -> Result<Collider> {
+        use nc::shape::{ConvexHull, ShapeHandle, TriMesh};
+
+        let scale = scale.copied().unwrap_or_else(|| na::Vector3::repeat(1.0));
+        let mut points = Vec::new();
+        let mut triangles = Vec::new();
+        for part in &self.parts {
+            let offset = points.len();
+            points.extend(
+                part.vertices
+                    .iter()
+                    .map(|v| na::Point3::from(na::Vector3::from(v.pos).component_mul(&scale))),
+            );
+            triangles.extend(part.indices.chunks_exact(3).map(|t| {
+                na::Point3::new(
+                    offset + t[0] as usize,
+                    offset + t[1] as usize,
+                    offset + t[2] as usize,
+                )
+            }));
+        }
+
+        if triangles.is_empty() {
+            return Err(eyre!(
+                "Cannot build a collider from a mesh without triangles"
+            ));
+        }
+
+        let handle = match shape {
+            MeshCollider::TriMesh => ShapeHandle::new(TriMesh::new(points, triangles, None)),
+            MeshCollider::ConvexHull => ShapeHandle::new(
+                ConvexHull::try_from_points(&points)
+                    .ok_or_else(|| eyre!("Failed to compute the convex hull of a mesh"))?,
+            ),
+        };
+        Ok(Collider::from(handle))
+    }
 }
 
 pub struct MeshPartData {
@@ -22,9 +97,62 @@ pub struct MaterialData {
     pub color_specular: color::Rgb,
     pub color_emissive: color::Rgb,
     pub alpha: f32,
-    pub lighting: bool,
-    // TODO: Add all the other maps
-    pub diffuse_map: Option<image::RgbaImage>,
+    pub alpha_mode: AlphaMode,
+    pub illumination: Illumination,
+    // Maps multiply their colors (or the alpha, for the dissolve map's red channel)
+    pub ambient_map: Option<TextureData>,
+    pub diffuse_map: Option<TextureData>,
+    pub specular_map: Option<TextureData>,
+    pub emissive_map: Option<TextureData>,
+    /// Tangent-space normals (+y is +v); bump maps holding heights are converted when loaded
+    pub normal_map: Option<TextureData>,
+    pub dissolve_map: Option<TextureData>,
+}
+
+impl MaterialData {
+    pub fn has_maps(&self) -> bool {
+        [
+            &self.ambient_map,
+            &self.diffuse_map,
+            &self.specular_map,
+            &self.emissive_map,
+            &self.normal_map,
+            &self.dissolve_map,
+        ]
+        .iter()
+        .any(|map| map.is_some())
+    }
+}
+
+/// What a material's alpha (`alpha` times the diffuse and dissolve maps') does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlphaMode {
+    /// The alpha is ignored
+    Opaque,
+    /// Cutout (for foliage, decals etc.): fragments with an alpha below this are discarded,
+    /// the others are opaque
+    Mask(f32),
+    /// Blended over what's behind, after every opaque mesh is drawn
+    Blend,
+}
+
+/// Which terms of the lighting a material gets, like MTL's `illum`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Illumination {
+    /// Just the diffuse color, whatever the lights (`illum 0`)
+    Unlit,
+    /// Ambient and diffuse lighting (`illum 1`)
+    Diffuse,
+    /// Ambient, diffuse and specular lighting (`illum 2` and up)
+    Specular,
+}
+
+/// A decoded image used by a material
+#[derive(Clone)]
+pub struct TextureData {
+    /// The image's file, which identifies its texture once it's uploaded to the GPU
+    pub path: PathBuf,
+    pub image: Handle<image::RgbaImage>,
 }
 
 impl Default for MaterialData {
@@ -35,14 +163,24 @@ impl Default for MaterialData {
             color_diffuse: color::Rgb::new(1.0, 0.0, 1.0),
             color_specular: color::Rgb::new(0.0, 0.0, 0.0),
             color_emissive: color::Rgb::new(0.0, 0.0, 0.0),
-            lighting: true,
+            illumination: Illumination::Specular,
             alpha: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            ambient_map: None,
             diffuse_map: None,
+            specular_map: None,
+            emissive_map: None,
+            normal_map: None,
+            dissolve_map: None,
         }
     }
 }
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub enum Scale {
@@ -59,6 +197,16 @@ impl Into<spacetime::Scale> for Scale {
     }
 }
 
+impl From<spacetime::Scale> for Scale {
+    fn from(scale: spacetime::Scale) -> Self {
+        if scale.x == scale.y && scale.y == scale.z {
+            Scale::All(scale.x)
+        } else {
+            Scale::Xyz(scale.x, scale.y, scale.z)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub enum Axis {
     X,
@@ -113,6 +261,28 @@ impl Into<na::Isometry3<f32>> for Position {
     }
 }
 
+impl From<na::Isometry3<f32>> for Position {
+    fn from(iso: na::Isometry3<f32>) -> Self {
+        let rotation = if iso.rotation == na::UnitQuaternion::identity() {
+            None
+        } else {
+            let (roll, pitch, yaw) = iso.rotation.euler_angles();
+            Some(Rotation::Euler(
+                roll.to_degrees(),
+                pitch.to_degrees(),
+                yaw.to_degrees(),
+            ))
+        };
+        let t = iso.translation.vector;
+        Position {
+            x: t.x,
+            y: t.y,
+            z: t.z,
+            rotation,
+        }
+    }
+}
+
 impl Into<spacetime::Position> for Position {
     fn into(self) -> spacetime::Position {
         let i: na::Isometry3<f32> = self.into();
@@ -120,15 +290,222 @@ impl Into<spacetime::Position> for Position {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// The kind of static collider generated from a model's geometry
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeshCollider {
+    /// Exact triangle mesh collision; best for level geometry
+    TriMesh,
+    /// The convex hull of all the vertices; cheaper, good for props
+    ConvexHull,
+}
+
+/// Deserialized with `ModelSeed`, since its components need a ComponentRegistry
+#[derive(Serialize)]
 pub(crate) struct Model {
+    /// Relative to the parent's position if `parent` is set
     pub pos: Position,
     pub scale: Option<Scale>,
+    /// Path of the model file: Wavefront OBJ (.obj) or glTF (.gltf or .glb).
+    /// Objects without a model only get their Position and `components`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub obj: String,
     pub parent: Option<usize>,
+    #[serde(default)]
+    pub collider: Option<MeshCollider>,
+    /// Registered components (see ComponentRegistry) inserted onto the object's entities
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<ComponentData>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Deserialized with `SceneSeed`, since its objects' components need a ComponentRegistry
+#[derive(Serialize)]
 pub(crate) struct Scene {
     pub objects: Vec<Model>,
 }
+
+/// Deserializes a `Model`, looking its components up in the registry.
+/// Missing optional fields default like they would with a derived Deserialize.
+#[derive(Clone, Copy)]
+pub(crate) struct ModelSeed<'a>(pub &'a ComponentRegistry);
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum ModelField {
+    Pos,
+    Scale,
+    Obj,
+    Parent,
+    Collider,
+    Components,
+    #[serde(other)]
+    Other,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for ModelSeed<'a> {
+    type Value = Model;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Model, D::Error> {
+        const FIELDS: &[&str] = &["pos", "scale", "obj", "parent", "collider", "components"];
+        deserializer.deserialize_struct("Model", FIELDS, self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for ModelSeed<'a> {
+    type Value = Model;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a scene object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Model, A::Error> {
+        let mut pos = None;
+        let mut scale = None;
+        let mut obj = None;
+        let mut parent = None;
+        let mut collider = None;
+        let mut components = None;
+        while let Some(field) = map.next_key()? {
+            match field {
+                ModelField::Pos => pos = Some(map.next_value()?),
+                ModelField::Scale => scale = map.next_value()?,
+                ModelField::Obj => obj = Some(map.next_value()?),
+                ModelField::Parent => parent = map.next_value()?,
+                ModelField::Collider => collider = map.next_value()?,
+                ModelField::Components => {
+                    components = Some(map.next_value_seed(ComponentListSeed(self.0))?)
+                }
+                ModelField::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(Model {
+            pos: pos.ok_or_else(|| de::Error::missing_field("pos"))?,
+            scale,
+            obj: obj.unwrap_or_default(),
+            parent,
+            collider,
+            components: components.unwrap_or_default(),
+        })
+    }
+}
+
+/// Deserializes a `Scene` (see `ModelSeed`)
+#[derive(Clone, Copy)]
+pub(crate) struct SceneSeed<'a>(pub &'a ComponentRegistry);
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum SceneField {
+    Objects,
+    #[serde(other)]
+    Other,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for SceneSeed<'a> {
+    type Value = Scene;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Scene, D::Error> {
+        deserializer.deserialize_struct("Scene", &["objects"], self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for SceneSeed<'a> {
+    type Value = Scene;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a scene")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Scene, A::Error> {
+        let mut objects = None;
+        while let Some(field) = map.next_key()? {
+            match field {
+                SceneField::Objects => objects = Some(map.next_value_seed(ModelListSeed(self.0))?),
+                SceneField::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(Scene {
+            objects: objects.ok_or_else(|| de::Error::missing_field("objects"))?,
+        })
+    }
+}
+
+struct ModelListSeed<'a>(&'a ComponentRegistry);
+
+impl<'de, 'a> DeserializeSeed<'de> for ModelListSeed<'a> {
+    type Value = Vec<Model>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Vec<Model>, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for ModelListSeed<'a> {
+    type Value = Vec<Model>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of scene objects")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<Model>, A::Error> {
+        let mut objects = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(object) = seq.next_element_seed(ModelSeed(self.0))? {
+            objects.push(object);
+        }
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quad() -> MeshData {
+        let vertex = |x: f32, y: f32| Vertex {
+            pos: [x, y, 0.0],
+            normal: [0.0, 0.0, 1.0],
+            uv: [0.0, 0.0],
+            _padding: [0.0; 6],
+        };
+        MeshData {
+            parts: vec![MeshPartData {
+                vertices: vec![
+                    vertex(-1.0, -1.0),
+                    vertex(1.0, -1.0),
+                    vertex(1.0, 1.0),
+                    vertex(-1.0, 1.0),
+                ],
+                indices: vec![0, 1, 2, 0, 2, 3],
+                material: MaterialData::default(),
+            }],
+            node: MeshNode::default(),
+        }
+    }
+
+    #[test]
+    fn test_trimesh_collider_is_scaled() {
+        let scale = na::Vector3::new(2.0, 3.0, 1.0);
+        let collider = quad()
+            .collider(MeshCollider::TriMesh, Some(&scale))
+            .unwrap();
+        let trimesh = collider
+            .handle
+            .as_shape::<nc::shape::TriMesh<f32>>()
+            .unwrap();
+
+        assert_eq!(trimesh.faces().len(), 2);
+        assert_eq!(trimesh.aabb().maxs, na::Point3::new(2.0, 3.0, 0.0));
+    }
+
+    #[test]
+    fn test_empty_mesh_has_no_collider() {
+        let mesh = MeshData {
+            parts: Vec::new(),
+            node: MeshNode::default(),
+        };
+        assert!(mesh.collider(MeshCollider::ConvexHull, None).is_err());
+    }
+}
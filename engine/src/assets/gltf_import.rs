@@ -0,0 +1,392 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use gltf::{image::Source, mesh::Mode};
+
+use super::{
+    data::{AlphaMode, Illumination, MaterialData, MeshData, MeshNode, MeshPartData, TextureData},
+    AssetLoader,
+};
+use crate::graphics::{color, mesh::Vertex};
+
+/// Turns glTF's +y up into our +z up
+fn y_up_to_z_up() -> na::Isometry3<f32> {
+    na::Isometry3::rotation(na::Vector3::x() * std::f32::consts::FRAC_PI_2)
+}
+
+/// State of a single glTF file being loaded
+struct GltfImport<'a> {
+    loader: &'a AssetLoader,
+    path: &'a Path,
+    buffers: &'a [gltf::buffer::Data],
+    meshes: Vec<MeshData>,
+}
+
+impl AssetLoader {
+    /// Loads the default scene of a glTF (.gltf or .glb) file. Every node with a mesh becomes
+    /// an object, attached to the closest of its ancestors which has one too.
+    ///
+    /// Nodes' scales get baked into their meshes, since only whole entities can be scaled
+    /// (skewing from non-uniform scales of rotated nodes is lost).
+    pub(super) fn parse_gltf(&self, path: &Path) -> Result<Vec<MeshData>> {
+        let gltf::Gltf { document, blob } = gltf::Gltf::open(path)
+            .wrap_err_with(|| format!("Error while parsing glTF file: {:?}", path))?;
+        let buffers = load_buffers(&document, path, blob)
+            .wrap_err_with(|| format!("Failed to load the buffers of {:?}", path))?;
+        let scene = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+            .ok_or_else(|| eyre!("Expected the model: {:?} to have a scene", path))?;
+
+        let mut import = GltfImport {
+            loader: self,
+            path,
+            buffers: &buffers,
+            meshes: Vec::new(),
+        };
+        for node in scene.nodes() {
+            import.add_node(node, None, y_up_to_z_up(), na::Vector3::repeat(1.0))?;
+        }
+        Ok(import.meshes)
+    }
+}
+
+impl GltfImport<'_> {
+    /// Adds the node's mesh (if any) and its children's.
+    /// `offset` is where the node's parent is relative to `parent`'s mesh and `scale` is
+    /// the parent's scale (relative to the model).
+    fn add_node(
+        &mut self,
+        node: gltf::Node,
+        parent: Option<usize>,
+        offset: na::Isometry3<f32>,
+        scale: na::Vector3<f32>,
+    ) -> Result<()> {
+        let (translation, rotation, node_scale) = node.transform().decomposed();
+        let [x, y, z, w] = rotation;
+        let transform = offset
+            * na::Isometry3::from_parts(
+                na::Vector3::from(translation).component_mul(&scale).into(),
+                na::UnitQuaternion::from_quaternion(na::Quaternion::new(w, x, y, z)),
+            );
+        let scale = scale.component_mul(&na::Vector3::from(node_scale));
+
+        let (parent, offset) = match node.mesh() {
+            Some(mesh) => {
+                let parts = mesh
+                    .primitives()
+                    .filter_map(|primitive| self.part(primitive, &scale).transpose())
+                    .collect::<Result<_>>()
+                    .wrap_err_with(|| {
+                        format!("Failed to load mesh {:?} of {:?}", mesh.name(), self.path)
+                    })?;
+                self.meshes.push(MeshData {
+                    parts,
+                    node: MeshNode { transform, parent },
+                });
+                (Some(self.meshes.len() - 1), na::Isometry3::identity())
+            }
+            // Nodes without meshes only move their children
+            None => (parent, transform),
+        };
+
+        for child in node.children() {
+            self.add_node(child, parent, offset, scale)?;
+        }
+        Ok(())
+    }
+
+    /// A primitive's geometry (scaled by `scale`) and material.
+    /// Only triangles are supported; other primitives are skipped.
+    fn part(
+        &self,
+        primitive: gltf::Primitive,
+        scale: &na::Vector3<f32>,
+    ) -> Result<Option<MeshPartData>> {
+        if primitive.mode() != Mode::Triangles {
+            log::warn!(
+                "Skipping a primitive of {:?}: {:?} aren't supported",
+                self.path,
+                primitive.mode()
+            );
+            return Ok(None);
+        }
+
+        let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+        let positions: Vec<[f32; 3]> = reader
+            .read_positions()
+            .ok_or_else(|| eyre!("A primitive has no vertex positions"))?
+            .collect();
+        let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(|n| n.collect());
+        let uvs: Option<Vec<[f32; 2]>> =
+            reader.read_tex_coords(0).map(|uv| uv.into_f32().collect());
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let vertices: Vec<Vertex> = positions
+            .iter()
+            .enumerate()
+            .map(|(i, &pos)| Vertex {
+                pos: na::Vector3::from(pos).component_mul(scale).into(),
+                // Normals are scaled inversely to stay perpendicular to the surface
+                normal: normals.as_ref().map_or([0.0; 3], |normals| {
+                    na::Vector3::from(normals[i])
+                        .component_div(scale)
+                        .normalize()
+                        .into()
+                }),
+                // Textures get flipped when they're loaded (see `load_map_img`)
+                uv: uvs
+                    .as_ref()
+                    .map_or([0.0; 2], |uvs| [uvs[i][0], 1.0 - uvs[i][1]]),
+                _padding: [0.0; 6],
+            })
+            .collect();
+        let (vertices, indices) = match normals {
+            Some(_) => (vertices, indices),
+            // glTF asks for flat normals when a primitive has none
+            None => flat_shaded(&vertices, &indices),
+        };
+
+        Ok(Some(MeshPartData {
+            vertices,
+            indices,
+            material: self.material(primitive.material())?,
+        }))
+    }
+
+    /// Approximates a metallic-roughness material with our Blinn-Phong one
+    fn material(&self, material: gltf::Material) -> Result<MaterialData> {
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, alpha] = pbr.base_color_factor();
+        let base_color = na::Vector3::new(r, g, b);
+        // Dielectrics reflect about 4% of the light, metals reflect their own color
+        let specular = na::Vector3::repeat(0.04).lerp(&base_color, pbr.metallic_factor());
+        let roughness = pbr.roughness_factor().max(0.01);
+
+        Ok(MaterialData {
+            specular_coefficient: (2.0 / roughness.powi(4) - 2.0).clamp(1.0, 1024.0),
+            // The ambient light already gets multiplied by the diffuse color
+            color_ambient: color::Rgb::new(1.0, 1.0, 1.0),
+            color_diffuse: color::Rgb::new(r, g, b),
+            color_specular: color::Rgb::new(specular.x, specular.y, specular.z),
+            color_emissive: material.emissive_factor().into(),
+            alpha,
+            alpha_mode: match material.alpha_mode() {
+                gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                gltf::material::AlphaMode::Mask => {
+                    AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5))
+                }
+                gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+            },
+            illumination: match material.unlit() {
+                true => Illumination::Unlit,
+                false => Illumination::Specular,
+            },
+            diffuse_map: pbr
+                .base_color_texture()
+                .map(|info| self.texture(info.texture().source()))
+                .transpose()?,
+            emissive_map: material
+                .emissive_texture()
+                .map(|info| self.texture(info.texture().source()))
+                .transpose()?,
+            normal_map: material
+                .normal_texture()
+                .map(|normal| self.texture(normal.texture().source()))
+                .transpose()?,
+            // Core glTF has no ambient or specular colors, and keeps the alpha in the base color
+            ambient_map: None,
+            specular_map: None,
+            dissolve_map: None,
+        })
+    }
+
+    /// Loads an image from its own file, or one embedded in the model
+    fn texture(&self, image: gltf::Image) -> Result<TextureData> {
+        let uri = match image.source() {
+            Source::Uri { uri, .. } if !uri.starts_with("data:") => {
+                return self
+                    .loader
+                    .load_map_img(self.path.parent().unwrap().join(uri));
+            }
+            Source::Uri { uri, .. } => Some(uri),
+            Source::View { .. } => None,
+        };
+
+        // Embedded images are told apart by their index in the model
+        let path = PathBuf::from(format!("{}#image{}", self.path.display(), image.index()));
+        let decoded = self.loader.images.get_or_load(path.clone(), || {
+            let bytes = match (uri, image.source()) {
+                (Some(uri), _) => decode_data_uri(uri)?,
+                (None, Source::View { view, .. }) => {
+                    let buffer = &self.buffers[view.buffer().index()];
+                    buffer[view.offset()..view.offset() + view.length()].to_vec()
+                }
+                (None, Source::Uri { .. }) => unreachable!(),
+            };
+            let img = image::load_from_memory(&bytes).wrap_err_with(|| {
+                format!(
+                    "Failed to decode image {} of {:?}",
+                    image.index(),
+                    self.path
+                )
+            })?;
+            Ok(img.flipv().to_rgba8())
+        })?;
+        Ok(TextureData {
+            path,
+            image: decoded,
+        })
+    }
+}
+
+/// Reads the data of every buffer, from the .glb's binary chunk, a data URI or a file
+/// next to the model
+fn load_buffers(
+    document: &gltf::Document,
+    path: &Path,
+    mut blob: Option<Vec<u8>>,
+) -> Result<Vec<gltf::buffer::Data>> {
+    document
+        .buffers()
+        .map(|buffer| {
+            let mut bytes = match buffer.source() {
+                gltf::buffer::Source::Bin => blob
+                    .take()
+                    .ok_or_else(|| eyre!("Missing the binary chunk"))?,
+                gltf::buffer::Source::Uri(uri) if uri.starts_with("data:") => decode_data_uri(uri)?,
+                gltf::buffer::Source::Uri(uri) => {
+                    let file = path.parent().unwrap().join(uri);
+                    std::fs::read(&file).wrap_err_with(|| format!("Failed to read {:?}", file))?
+                }
+            };
+            if bytes.len() < buffer.length() {
+                return Err(eyre!(
+                    "Buffer {} is {} bytes long, expected {}",
+                    buffer.index(),
+                    bytes.len(),
+                    buffer.length()
+                ));
+            }
+            // The binary chunk may be padded
+            bytes.truncate(buffer.length());
+            Ok(gltf::buffer::Data(bytes))
+        })
+        .collect()
+}
+
+/// Decodes a base64 `data:` URI
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>> {
+    let (_, data) = uri
+        .split_once(";base64,")
+        .ok_or_else(|| eyre!("Only base64 data URIs are supported"))?;
+    Ok(base64::decode(data)?)
+}
+
+/// Gives every triangle its own vertices, with the triangle's normal
+fn flat_shaded(vertices: &[Vertex], indices: &[u32]) -> (Vec<Vertex>, Vec<u32>) {
+    let flat: Vec<Vertex> = indices
+        .chunks_exact(3)
+        .flat_map(|triangle| {
+            let [a, b, c] = [0, 1, 2].map(|i| vertices[triangle[i] as usize]);
+            let (pa, pb, pc) = (
+                na::Vector3::from(a.pos),
+                na::Vector3::from(b.pos),
+                na::Vector3::from(c.pos),
+            );
+            let normal: [f32; 3] = (pb - pa)
+                .cross(&(pc - pa))
+                .try_normalize(f32::EPSILON)
+                .unwrap_or_else(na::Vector3::z)
+                .into();
+            [a, b, c].map(|vertex| Vertex { normal, ..vertex })
+        })
+        .collect();
+    let indices = (0..flat.len() as u32).collect();
+    (flat, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    /// A triangle (without normals) used by a node, its empty child and its grandchild
+    fn write_test_gltf(dir: &Path) {
+        let positions: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let bytes: Vec<u8> = bytemuck::cast_slice(&positions).to_vec();
+        let gltf = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "scene": 0,
+                "scenes": [{{ "nodes": [0] }}],
+                "nodes": [
+                    {{ "mesh": 0, "translation": [0, 1, 0], "children": [1] }},
+                    {{ "translation": [2, 0, 0], "children": [2] }},
+                    {{ "mesh": 0, "scale": [2, 2, 2] }}
+                ],
+                "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }} }}] }}],
+                "accessors": [{{
+                    "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                    "min": [0, 0, 0], "max": [1, 1, 0]
+                }}],
+                "bufferViews": [{{ "buffer": 0, "byteLength": {len} }}],
+                "buffers": [{{
+                    "byteLength": {len},
+                    "uri": "data:application/octet-stream;base64,{data}"
+                }}]
+            }}"#,
+            len = bytes.len(),
+            data = base64::encode(&bytes),
+        );
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("triangle.gltf"), gltf).unwrap();
+    }
+
+    #[test]
+    fn test_gltf_node_hierarchy() {
+        let dir = std::env::temp_dir().join(format!("gltf-import-{}", std::process::id()));
+        write_test_gltf(&dir);
+        let loader = AssetLoader::new(dir.clone());
+        let meshes = loader.load_model("triangle.gltf").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The empty node is folded into its child's transform
+        assert_eq!(meshes.len(), 2);
+        let (root, child) = (&meshes[0], &meshes[1]);
+        assert_eq!(root.node.parent, None);
+        assert_eq!(child.node.parent, Some(0));
+        // glTF's y up is our z up
+        assert_relative_eq!(
+            root.node.transform.translation.vector,
+            na::Vector3::new(0.0, 0.0, 1.0),
+            epsilon = 1e-6
+        );
+        assert_relative_eq!(
+            child.node.transform.translation.vector,
+            na::Vector3::new(2.0, 0.0, 0.0),
+            epsilon = 1e-6
+        );
+
+        // Flat normals are generated, and the scale is baked into the vertices
+        let part = &child.parts[0];
+        assert_eq!(part.indices, vec![0, 1, 2]);
+        assert_eq!(part.vertices[1].pos, [2.0, 0.0, 0.0]);
+        assert!(part.vertices.iter().all(|v| v.normal == [0.0, 0.0, 1.0]));
+        // Primitives without a material get glTF's default one
+        assert_eq!(part.material.color_diffuse, color::Rgb::new(1.0, 1.0, 1.0));
+        assert_eq!(part.material.illumination, Illumination::Specular);
+    }
+
+    #[test]
+    fn test_unknown_model_formats_are_errors() {
+        let loader = AssetLoader::new(std::env::temp_dir().join("nonexistent-assets"));
+        assert!(loader.load_model("models/thing.fbx").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    ops::Deref,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+use eyre::Result;
+
+type Entries<K, T> = HashMap<K, Weak<Entry<T>>>;
+
+struct Entry<T> {
+    asset: T,
+    /// Removes the entry from its cache once the last handle is dropped
+    release: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl<T> Drop for Entry<T> {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+/// A shared, reference counted asset. Handles are cheap to clone; the asset gets
+/// freed (and removed from its AssetCache) when the last handle to it is dropped.
+pub struct Handle<T> {
+    entry: Arc<Entry<T>>,
+}
+
+impl<T> Handle<T> {
+    /// Wraps an asset which doesn't belong to any cache
+    pub fn new(asset: T) -> Self {
+        Self {
+            entry: Arc::new(Entry {
+                asset,
+                release: None,
+            }),
+        }
+    }
+
+    /// Whether both handles point to the same asset
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        Arc::ptr_eq(&a.entry, &b.entry)
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            entry: self.entry.clone(),
+        }
+    }
+}
+
+impl<T> Deref for Handle<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.entry.asset
+    }
+}
+
+/// Makes sure every asset (identified by a key, usually its path) is loaded only once
+/// for as long as there are Handles to it
+pub struct AssetCache<T, K = PathBuf> {
+    entries: Arc<Mutex<Entries<K, T>>>,
+}
+
+/// Clones share their assets
+impl<T, K> Clone for AssetCache<T, K> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<T, K> Default for AssetCache<T, K> {
+    fn default() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T, K> AssetCache<T, K>
+where
+    T: Send + Sync + 'static,
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// A handle to the asset, if it's loaded
+    pub fn get(&self, key: &K) -> Option<Handle<T>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?.upgrade()?;
+        Some(Handle { entry })
+    }
+
+    /// Returns a handle to the already loaded asset, or loads it with `load`.
+    /// Failed loads aren't cached, so they get retried the next time.
+    pub fn get_or_load(&self, key: K, load: impl FnOnce() -> Result<T>) -> Result<Handle<T>> {
+        if let Some(handle) = self.get(&key) {
+            return Ok(handle);
+        }
+        // The lock isn't held while loading, so `load` can use the cache too
+        let asset = load()?;
+
+        let cache = Arc::downgrade(&self.entries);
+        let release_key = key.clone();
+        let release = move || {
+            if let Some(cache) = cache.upgrade() {
+                if let Ok(mut entries) = cache.lock() {
+                    // The key might already belong to an asset that was loaded again
+                    if entries
+                        .get(&release_key)
+                        .is_some_and(|entry| entry.strong_count() == 0)
+                    {
+                        entries.remove(&release_key);
+                    }
+                }
+            }
+        };
+        let entry = Arc::new(Entry {
+            asset,
+            release: Some(Box::new(release)),
+        });
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, Arc::downgrade(&entry));
+        Ok(Handle { entry })
+    }
+
+    /// Number of loaded assets
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre::eyre::eyre;
+
+    #[test]
+    fn test_assets_are_loaded_once() {
+        let cache = AssetCache::<String>::default();
+        let mut loads = 0;
+        let mut load = |key: &str| {
+            cache.get_or_load(key.into(), || {
+                loads += 1;
+                Ok(key.to_uppercase())
+            })
+        };
+
+        let a = load("a").unwrap();
+        let also_a = load("a").unwrap();
+        let b = load("b").unwrap();
+        assert_eq!(loads, 2);
+        assert!(Handle::ptr_eq(&a, &also_a));
+        assert_eq!((a.as_str(), b.as_str()), ("A", "B"));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_assets_are_freed_with_last_handle() {
+        let cache = AssetCache::<u32, (String, usize)>::default();
+        let key = ("model.obj".to_string(), 0);
+        let handle = cache.get_or_load(key.clone(), || Ok(1)).unwrap();
+        let clone = handle.clone();
+
+        drop(handle);
+        assert_eq!(cache.get(&key).as_deref(), Some(&1));
+        drop(clone);
+        assert!(cache.get(&key).is_none());
+        assert!(cache.is_empty());
+
+        // It gets loaded again when needed
+        let reloaded = cache.get_or_load(key, || Ok(2)).unwrap();
+        assert_eq!(*reloaded, 2);
+    }
+
+    #[test]
+    fn test_failed_loads_are_not_cached() {
+        let cache = AssetCache::<u32>::default();
+        let key = PathBuf::from("missing.png");
+        assert!(cache
+            .get_or_load(key.clone(), || Err(eyre!("Not found")))
+            .is_err());
+        assert!(cache.is_empty());
+        assert_eq!(*cache.get_or_load(key, || Ok(3)).unwrap(), 3);
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use eyre::{eyre::WrapErr, Result};
+use legion::World;
+
+use super::{
+    data::{MeshData, Scene},
+    AssetLoader, Handle,
+};
+use crate::{
+    graphics::{mesh::RenderModel, GraphicsShared},
+    state::Scoped,
+};
+
+/// Most worker threads a single SceneLoad uses for loading models
+const MAX_WORKERS: usize = 4;
+/// How many models get uploaded to the GPU during a single `SceneLoad::poll`
+const UPLOADS_PER_POLL: usize = 1;
+
+/// How far along a SceneLoad is. Counts the scene file and every model file it uses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+impl Progress {
+    /// Progress in range [0, 1], by file size when the sizes are known
+    pub fn fraction(&self) -> f32 {
+        if self.bytes_total > 0 {
+            self.bytes_done as f32 / self.bytes_total as f32
+        } else if self.files_total > 0 {
+            self.files_done as f32 / self.files_total as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Results sent back by the worker threads
+enum Loaded {
+    Scene(Result<Scene>, u64),
+    Model(String, Result<Handle<Vec<MeshData>>>),
+}
+
+/// A scene being loaded in the background (see `AssetLoader::start_loading_scene`).
+///
+/// Files get read, parsed and decoded on worker threads, while `poll` uploads the
+/// results to the GPU a bit at a time and finally spawns the scene's entities.
+pub struct SceneLoad {
+    loader: AssetLoader,
+    path: String,
+    scoped: Option<Scoped>,
+
+    sender: mpsc::Sender<Loaded>,
+    receiver: mpsc::Receiver<Loaded>,
+    scene: Option<Scene>,
+    /// Models which are still being loaded by the workers
+    loading: HashSet<String>,
+    /// Loaded models waiting to be uploaded to the GPU
+    to_upload: VecDeque<(String, Handle<Vec<MeshData>>)>,
+    // Keeps the loaded assets around until the scene is spawned
+    mesh_data: Vec<Handle<Vec<MeshData>>>,
+    render_models: Vec<Handle<RenderModel>>,
+
+    progress: Progress,
+    done: bool,
+}
+
+impl SceneLoad {
+    pub(super) fn start(loader: AssetLoader, path: &str, scoped: Option<Scoped>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        let scene_loader = loader.clone();
+        let scene_path = path.to_owned();
+        let scene_sender = sender.clone();
+        thread::spawn(move || {
+            let size = std::fs::metadata(scene_loader.root_path().join(&scene_path))
+                .map_or(0, |metadata| metadata.len());
+            let scene = scene_loader.load_scene_data(&scene_path);
+            // The receiver is gone if the load got cancelled
+            let _ = scene_sender.send(Loaded::Scene(scene, size));
+        });
+
+        SceneLoad {
+            loader,
+            path: path.to_owned(),
+            scoped,
+            sender,
+            receiver,
+            scene: None,
+            loading: HashSet::new(),
+            to_upload: VecDeque::new(),
+            mesh_data: Vec::new(),
+            render_models: Vec::new(),
+            progress: Progress {
+                files_total: 1,
+                ..Default::default()
+            },
+            done: false,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Collects the workers' results and does a part of the work that has to happen on
+    /// the main thread. Call it every frame until it returns `Ok(true)`, which means
+    /// the scene's entities have been added to the world.
+    pub fn poll(&mut self, world: &mut World, graphics: &GraphicsShared) -> Result<bool> {
+        if self.done {
+            return Ok(true);
+        }
+
+        while let Ok(loaded) = self.receiver.try_recv() {
+            match loaded {
+                Loaded::Scene(scene, size) => {
+                    let scene = scene?;
+                    self.progress.files_done += 1;
+                    self.progress.bytes_done += size;
+                    self.progress.bytes_total += size;
+                    self.start_loading_models(&scene);
+                    self.scene = Some(scene);
+                }
+                Loaded::Model(path, mesh_data) => {
+                    let mesh_data =
+                        mesh_data.wrap_err_with(|| format!("Failed to load model {:?}", path))?;
+                    self.loading.remove(&path);
+                    self.to_upload.push_back((path, mesh_data));
+                }
+            }
+        }
+
+        if !self.to_upload.is_empty() {
+            let mut encoder = graphics
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            for _ in 0..UPLOADS_PER_POLL {
+                let (path, mesh_data) = match self.to_upload.pop_front() {
+                    Some(loaded) => loaded,
+                    None => break,
+                };
+                let model = self
+                    .loader
+                    .upload_model(graphics, &mut encoder, &path, &mesh_data)?;
+                self.render_models.push(model);
+                self.mesh_data.push(mesh_data);
+
+                self.progress.files_done += 1;
+                self.progress.bytes_done += self.loader.file_size(&path);
+            }
+            graphics.queue.submit(Some(encoder.finish()));
+            // Spawn the scene during the next poll, so this one doesn't take too long
+            return Ok(false);
+        }
+
+        match &self.scene {
+            Some(scene) if self.loading.is_empty() => {
+                self.loader
+                    .instantiate_scene(world, graphics, scene, self.scoped)
+                    .wrap_err_with(|| format!("Failed to spawn scene {:?}", self.path))?;
+                self.done = true;
+                // The assets are owned by the spawned entities now
+                self.scene = None;
+                self.mesh_data.clear();
+                self.render_models.clear();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Queues every model the scene uses for loading on worker threads
+    fn start_loading_models(&mut self, scene: &Scene) {
+        let paths: VecDeque<String> = scene
+            .objects
+            .iter()
+            .map(|object| object.obj.clone())
+            .filter(|obj| !obj.is_empty())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        self.progress.files_total += paths.len();
+        self.progress.bytes_total += paths
+            .iter()
+            .map(|path| self.loader.file_size(path))
+            .sum::<u64>();
+        self.loading.extend(paths.iter().cloned());
+
+        let workers = paths.len().min(MAX_WORKERS);
+        let jobs = Arc::new(Mutex::new(paths));
+        for _ in 0..workers {
+            let (loader, jobs, sender) = (self.loader.clone(), jobs.clone(), self.sender.clone());
+            thread::spawn(move || loop {
+                let path = match jobs.lock().map(|mut jobs| jobs.pop_front()) {
+                    Ok(Some(path)) => path,
+                    _ => break,
+                };
+                let mesh_data = loader.load_model(&path);
+                if sender.send(Loaded::Model(path, mesh_data)).is_err() {
+                    // Nobody is waiting for the results anymore
+                    break;
+                }
+            });
+        }
+    }
+}
+
+impl AssetLoader {
+    /// Starts loading a scene on background threads. The returned SceneLoad has to be
+    /// polled (on the main thread) to finish loading.
+    pub fn start_loading_scene(&self, path: &str, scoped: Option<Scoped>) -> SceneLoad {
+        SceneLoad::start(self.clone(), path, scoped)
+    }
+
+    fn file_size(&self, path: &str) -> u64 {
+        std::fs::metadata(self.root_path().join(path)).map_or(0, |metadata| metadata.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_fraction() {
+        let mut progress = Progress::default();
+        assert_eq!(progress.fraction(), 0.0);
+
+        progress.files_total = 4;
+        progress.files_done = 1;
+        assert_eq!(progress.fraction(), 0.25);
+
+        // File sizes are more accurate than file counts
+        progress.bytes_total = 1000;
+        progress.bytes_done = 900;
+        assert_eq!(progress.fraction(), 0.9);
+    }
+
+    #[test]
+    fn test_missing_scene_is_an_error() {
+        let loader = AssetLoader::new(std::env::temp_dir().join("nonexistent-assets"));
+        let load = loader.start_loading_scene("scenes/missing.ron", None);
+        let result = load.receiver.recv().unwrap();
+        assert!(matches!(result, Loaded::Scene(Err(_), _)));
+        assert!(!load.is_done());
+    }
+}
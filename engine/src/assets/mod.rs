@@ -1,34 +1,71 @@
+mod components;
 pub mod data;
-
-use data::{MaterialData, Scene};
+mod gltf_import;
+mod handle;
+mod loading;
+pub mod obj;
+mod scene;
+mod watcher;
+pub use components::{ComponentData, ComponentRegistry};
+pub use handle::{AssetCache, Handle};
+pub use loading::{Progress, SceneLoad};
+pub use scene::ModelSource;
+pub use watcher::AssetWatcher;
+
+use data::{MaterialData, MeshData, MeshNode, Model, Scene, TextureData};
 use eyre::{eyre::eyre, eyre::WrapErr, Result};
-use legion::World;
+use legion::{Entity, IntoQuery, World};
+use serde::de::DeserializeSeed;
 use std::path::{Path, PathBuf};
 
 use log::debug;
 
 use crate::{
-    graphics::{color, mesh::RenderMesh, mesh::Vertex, GraphicsShared},
-    spacetime::{self, Child},
+    graphics::{
+        color,
+        mesh::{RenderMesh, RenderModel},
+        DirectionalLight, GraphicsShared, PointLight, Shadows, SpotLight, Texture,
+    },
+    physics::{Collider, ColliderShape, Velocity},
+    spacetime,
     state::Scoped,
 };
 
 use wavefront_obj as wobj;
-use wobj::{
-    mtl::{Material, MtlSet},
-    obj::{Primitive, VTNIndex},
-};
+use wobj::mtl::{Illumination, Material, MtlSet};
 
 use self::data::MeshPartData;
 
+/// The alpha below which fragments of MTL materials with a dissolve map are discarded
+const MTL_ALPHA_CUTOFF: f32 = 0.5;
+
+/// Clones share the loaded assets, so a clone can be used to load assets on another thread
+#[derive(Clone)]
 pub struct AssetLoader {
     root_path: PathBuf,
+    components: ComponentRegistry,
+
+    // Loaded assets, shared for as long as something holds a Handle to them
+    models: AssetCache<Vec<MeshData>>,
+    images: AssetCache<image::RgbaImage>,
+    textures: AssetCache<Texture>,
+    render_models: AssetCache<RenderModel>,
 }
 
 impl AssetLoader {
     pub fn root_path(&self) -> &Path {
         self.root_path.as_path()
     }
+
+    /// Components that scene objects can list; the engine's own components are registered already
+    pub fn components(&self) -> &ComponentRegistry {
+        &self.components
+    }
+
+    pub fn components_mut(&mut self) -> &mut ComponentRegistry {
+        &mut self.components
+    }
+
     pub fn from_relative_exe_path(rel_path: &Path) -> Result<AssetLoader> {
         let exe_file_name = std::env::current_exe()?;
 
@@ -36,9 +73,30 @@ impl AssetLoader {
             .parent()
             .ok_or_else(|| eyre!("Could not find executable's parent directory"))?;
 
-        Ok(AssetLoader {
-            root_path: exe_path.join(rel_path),
-        })
+        Ok(AssetLoader::new(exe_path.join(rel_path)))
+    }
+
+    pub fn new(root_path: PathBuf) -> AssetLoader {
+        let mut components = ComponentRegistry::default();
+        components.register_as::<ColliderShape, Collider>(
+            "Collider",
+            |&shape| shape.into(),
+            ColliderShape::of,
+        );
+        components.register::<Velocity>("Velocity");
+        components.register::<DirectionalLight>("DirectionalLight");
+        components.register::<PointLight>("PointLight");
+        components.register::<SpotLight>("SpotLight");
+        components.register::<Shadows>("Shadows");
+
+        AssetLoader {
+            root_path,
+            components,
+            models: AssetCache::default(),
+            images: AssetCache::default(),
+            textures: AssetCache::default(),
+            render_models: AssetCache::default(),
+        }
     }
 
     // We could do `P: AsRef<Path>` here, but then every call would look like this:
@@ -46,12 +104,14 @@ impl AssetLoader {
     // It will be possible to remove the extra parameter when https://github.com/rust-lang/rust/issues/63066
     // is resolved (see second-last checkbox)
     pub fn load<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
-        let str = self.load_str(self.root_path.join(&path))?;
+        let str = self.load_str(self.root_path.join(path))?;
         ron::from_str(str.as_str())
             .wrap_err_with(|| format!("Error while deserializing file {:?}: ", path))
     }
 
     // See Self::load
+    /// Loads a scene and spawns its objects all at once (see `start_loading_scene` for
+    /// loading in the background)
     pub fn load_scene(
         &self,
         world: &mut World,
@@ -59,83 +119,156 @@ impl AssetLoader {
         path: &str,
         scoped: Option<Scoped>,
     ) -> Result<()> {
-        let scene = self.load::<Scene>(path)?;
+        let scene = self.load_scene_data(path)?;
+        self.instantiate_scene(world, graphics, &scene, scoped)
+    }
+
+    /// Replaces the entities spawned from scene files (see `load_scene`) with the objects
+    /// of the scene at `path`, e.g. after the file changed. Other entities are kept.
+    /// If the scene can't be loaded, the world is left as it was.
+    pub fn reload_scene(
+        &self,
+        world: &mut World,
+        graphics: &GraphicsShared,
+        path: &str,
+        scoped: Option<Scoped>,
+    ) -> Result<()> {
+        // Spawn into a separate world first, so a broken scene doesn't get half-loaded
+        let mut loaded = World::default();
+        self.load_scene(&mut loaded, graphics, path, scoped)?;
+
+        let old: Vec<Entity> = <(Entity, &ModelSource)>::query()
+            .iter(world)
+            .map(|(entity, _)| *entity)
+            .collect();
+        for entity in old {
+            world.remove(entity);
+        }
+        world.move_from(&mut loaded, &legion::query::any());
+        Ok(())
+    }
+
+    fn load_scene_data(&self, path: &str) -> Result<Scene> {
+        let str = self.load_str(self.root_path.join(path))?;
+        ron_from_str_seed(str.as_str(), data::SceneSeed(&self.components))
+            .wrap_err_with(|| format!("Error while deserializing file {:?}: ", path))
+    }
 
+    fn instantiate_scene(
+        &self,
+        world: &mut World,
+        graphics: &GraphicsShared,
+        scene: &Scene,
+        scoped: Option<Scoped>,
+    ) -> Result<()> {
         // Create a (temporary) CommandEncoder for loading data to GPU
         let mut encoder = graphics
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
-        let mut index_entity = Vec::new();
-
-        // TODO: Remove duplicated code here
-
-        for (i, object) in scene
-            .objects
-            .iter()
-            .enumerate()
-            .filter(|(_, m)| m.parent.is_none())
-        {
-            self.load_obj_set(&object.obj)?
-                .into_iter()
-                .map(|mesh_data| {
-                    RenderMesh::from_parts(
-                        mesh_data.parts,
-                        &graphics.mesh_layouts,
-                        &graphics.device,
-                        &mut encoder,
-                    )
-                })
-                .for_each(|render_mesh| {
-                    let pos: spacetime::Position = object.pos.into();
-                    debug!("pos: {:?}", pos);
-                    let ent = match (object.scale, scoped) {
-                        (Some(scale), Some(scope)) => world.push((pos, scale, render_mesh, scope)),
-                        (Some(scale), None) => world.push((pos, scale, render_mesh)),
-                        (None, Some(scope)) => world.push((pos, render_mesh, scope)),
-                        (None, None) => world.push((pos, render_mesh)),
-                    };
-                    index_entity.push((i, ent))
-                });
-        }
-
-        for object in scene.objects.iter().filter(|m| m.parent.is_some()) {
-            let parent_entity = index_entity
-                .iter()
-                .find(|(i, _)| *i == object.parent.unwrap())
-                .map(|(_, e)| e)
-                .ok_or_else(|| eyre!("Incorrect parent index found"))?;
-            self.load_obj_set(&object.obj)?
-                .into_iter()
-                .map(|mesh_data| {
-                    RenderMesh::from_parts(
-                        mesh_data.parts,
-                        &graphics.mesh_layouts,
-                        &graphics.device,
-                        &mut encoder,
-                    )
-                })
-                .for_each(|render_mesh| {
-                    let pos: spacetime::Position = object.pos.into();
-                    let child = Child {
-                        // TODO: Implement child offset
-                        offset: na::Isometry3::identity().into(),
-                        parent: *parent_entity,
-                    };
-                    match (object.scale, scoped) {
-                        (Some(scale), Some(scope)) => {
-                            world.push((pos, scale, render_mesh, child, scope))
-                        }
-                        (Some(scale), None) => world.push((pos, scale, render_mesh, child)),
-                        (None, Some(scope)) => world.push((pos, render_mesh, child, scope)),
-                        (None, None) => world.push((pos, render_mesh, child)),
-                    };
-                });
-        }
+        // Keep the models' data around while loading the scene, so objects
+        // using the same model don't have to load it again for their colliders
+        let mut loaded = Vec::new();
+        scene::instantiate(world, scene, scoped, |world, object| {
+            self.spawn_model(world, graphics, &mut encoder, object, &mut loaded)
+        })?;
         graphics.queue.submit(Some(encoder.finish()));
         Ok(())
     }
 
+    /// Writes every entity that was spawned from a scene object (see `load_scene`)
+    /// to a scene file, so the scene can be edited in-game.
+    /// Returns the absolute path of the written file. Note that the root path is usually the
+    /// copy of the assets next to the executable, which gets overwritten by the next build
+    /// (unless the assets are loaded from somewhere else).
+    pub fn save_scene(&self, world: &World, path: &str) -> Result<PathBuf> {
+        let scene = scene::scene_from_world(world, &self.components);
+        let str = ron::ser::to_string_pretty(&scene, ron::ser::PrettyConfig::new())
+            .wrap_err("Error while serializing scene")?;
+        let path = self.root_path.join(path);
+        std::fs::write(&path, str).wrap_err_with(|| format!("Could not write file: {:?}", path))?;
+        Ok(std::fs::canonicalize(&path).unwrap_or(path))
+    }
+
+    /// Pushes an entity for every object in the model's file.
+    /// Entities using the same model share its GPU buffers and textures.
+    fn spawn_model(
+        &self,
+        world: &mut World,
+        graphics: &GraphicsShared,
+        encoder: &mut wgpu::CommandEncoder,
+        object: &Model,
+        loaded: &mut Vec<Handle<Vec<MeshData>>>,
+    ) -> Result<Vec<(Entity, MeshNode)>> {
+        let mut load_model = || -> Result<Handle<Vec<MeshData>>> {
+            let mesh_data = self.load_model(&object.obj)?;
+            loaded.push(mesh_data.clone());
+            Ok(mesh_data)
+        };
+
+        let mut mesh_data = None;
+        let model = match self.render_models.get(&self.root_path.join(&object.obj)) {
+            Some(model) => model,
+            None => {
+                let data = load_model()?;
+                let model = self.upload_model(graphics, encoder, &object.obj, &data)?;
+                mesh_data = Some(data);
+                model
+            }
+        };
+
+        // Colliders are built from the geometry, which isn't needed otherwise
+        let colliders = match object.collider {
+            Some(shape) => {
+                let mesh_data = match mesh_data {
+                    Some(mesh_data) => mesh_data,
+                    None => load_model()?,
+                };
+                let scale: Option<spacetime::Scale> = object.scale.map(|s| s.into());
+                mesh_data
+                    .iter()
+                    .map(|mesh| mesh.collider(shape, scale.as_ref()).map(Some))
+                    .collect::<Result<Vec<_>>>()
+                    .wrap_err_with(|| format!("Failed to create a collider for {:?}", object.obj))?
+            }
+            None => model.objects.iter().map(|_| None).collect(),
+        };
+
+        let mut entities = Vec::new();
+        for (i, collider) in colliders.into_iter().enumerate() {
+            let render_mesh =
+                RenderMesh::new(model.clone(), i, &graphics.mesh_layouts, &graphics.device);
+            let ent = world.push((render_mesh,));
+            if let Some(collider) = collider {
+                world.entry(ent).unwrap().add_component(collider);
+            }
+            entities.push((ent, model.nodes[i]));
+        }
+        Ok(entities)
+    }
+
+    /// Uploads a model's meshes and textures to the GPU (only once, while the
+    /// returned Handle is kept)
+    pub fn upload_model(
+        &self,
+        graphics: &GraphicsShared,
+        encoder: &mut wgpu::CommandEncoder,
+        path: &str,
+        mesh_data: &[MeshData],
+    ) -> Result<Handle<RenderModel>> {
+        self.render_models
+            .get_or_load(self.root_path.join(path), || {
+                RenderModel::new(
+                    mesh_data,
+                    &graphics.device,
+                    encoder,
+                    &graphics.mesh_layouts,
+                    &self.textures,
+                )
+            })
+            .wrap_err_with(|| format!("Failed to upload model {:?}", path))
+    }
+
     pub fn load_str(&self, path: impl AsRef<Path>) -> Result<String> {
         std::fs::read_to_string(self.root_path.join(&path))
             .wrap_err_with(|| format!("File not found: {:?}", path.as_ref()))
@@ -146,16 +279,84 @@ impl AssetLoader {
             .wrap_err_with(|| format!("Failed to open image: {:?}", path.as_ref()))
     }
 
-    fn load_map_img(&self, path: impl AsRef<Path>) -> Result<image::RgbaImage> {
-        let img = self
-            .load_texture(path)?
-            // (?): fixes incorrect texture coords when loading obj models
-            .flipv();
+    /// Loads an image used by a material (only once, however many materials use it)
+    fn load_map_img(&self, path: impl AsRef<Path>) -> Result<TextureData> {
+        let path = self.root_path.join(path);
+        let image = self.images.get_or_load(path.clone(), || {
+            let img = self
+                .load_texture(&path)?
+                // (?): fixes incorrect texture coords when loading obj models
+                .flipv();
+
+            // Convert the image to Rgba
+            Ok(match img {
+                image::DynamicImage::ImageRgba8(img) => img,
+                img => img.to_rgba8(),
+            })
+        })?;
+        Ok(TextureData { path, image })
+    }
+
+    /// Converts an MTL material; maps which fail to load are left out
+    fn mtl_material(&self, mat: &Material, obj_parent: &Path) -> MaterialData {
+        let map = |path: &Option<String>| {
+            path.as_ref()
+                .and_then(|path| self.load_map_img(obj_parent.join(path)).ok())
+        };
+        MaterialData {
+            specular_coefficient: mat.specular_coefficient as f32,
+            illumination: match mat.illumination {
+                Illumination::Ambient => data::Illumination::Unlit,
+                Illumination::AmbientDiffuse => data::Illumination::Diffuse,
+                // An exponent of 0 (which Blender writes for fully rough materials)
+                // would make the whole surface shine
+                _ if mat.specular_coefficient <= 0.0 => data::Illumination::Diffuse,
+                Illumination::AmbientDiffuseSpecular | Illumination::ReflectionRayTrace => {
+                    data::Illumination::Specular
+                }
+            },
+            color_ambient: mat.color_ambient.into(),
+            color_diffuse: mat.color_diffuse.into(),
+            color_specular: mat.color_specular.into(),
+            color_emissive: mat
+                .color_emissive
+                .map_or(color::Rgb::default(), |m| m.into()),
+            alpha: mat.alpha as f32,
+            // MTL has no alpha modes; dissolve maps are mostly used for cutouts
+            alpha_mode: match (mat.alpha < 1.0, &mat.dissolve_map) {
+                (true, _) => data::AlphaMode::Blend,
+                (false, Some(_)) => data::AlphaMode::Mask(MTL_ALPHA_CUTOFF),
+                (false, None) => data::AlphaMode::Opaque,
+            },
+            ambient_map: map(&mat.ambient_map),
+            diffuse_map: map(&mat.diffuse_map),
+            specular_map: map(&mat.specular_map),
+            // wavefront_obj doesn't read `map_Ke`
+            emissive_map: None,
+            normal_map: mat
+                .bump_map
+                .as_ref()
+                .and_then(|path| self.load_bump_map(obj_parent.join(path)).ok()),
+            dissolve_map: map(&mat.dissolve_map),
+        }
+    }
 
-        // Convert the image to Rgba
-        Ok(match img {
-            image::DynamicImage::ImageRgba8(img) => img,
-            img => img.to_rgba8(),
+    /// Loads an MTL bump map, which is either a normal map or a grayscale height map.
+    /// Height maps get converted to normal maps (see `obj::height_to_normal_map`).
+    fn load_bump_map(&self, path: impl AsRef<Path>) -> Result<TextureData> {
+        let map = self.load_map_img(path)?;
+        if !obj::is_grayscale(&map.image) {
+            return Ok(map);
+        }
+        let mut normal_path = map.path.clone().into_os_string();
+        normal_path.push("#normal");
+        let normal_path = PathBuf::from(normal_path);
+        let image = self.images.get_or_load(normal_path.clone(), || {
+            Ok(obj::height_to_normal_map(&map.image))
+        })?;
+        Ok(TextureData {
+            path: normal_path,
+            image,
         })
     }
 
@@ -175,14 +376,29 @@ impl AssetLoader {
         )
     }
 
-    pub fn load_obj_set(&self, path: impl AsRef<Path>) -> Result<Vec<data::MeshData>> {
-        let obj_path = self.root_path.join(&path);
+    /// Loads every object in a model file, picking the format by the file's extension
+    /// (only once, for as long as the returned Handle is kept)
+    pub fn load_model(&self, path: impl AsRef<Path>) -> Result<Handle<Vec<MeshData>>> {
+        let model_path = self.root_path.join(&path);
+        let extension = model_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+        self.models
+            .get_or_load(model_path.clone(), || match extension.as_deref() {
+                Some("obj") => self.parse_obj_set(&model_path),
+                Some("gltf" | "glb") => self.parse_gltf(&model_path),
+                _ => Err(eyre!("Unsupported model format: {:?}", model_path)),
+            })
+    }
+
+    fn parse_obj_set(&self, obj_path: &Path) -> Result<Vec<MeshData>> {
         let obj_parent = obj_path.parent().unwrap();
-        let obj_file = std::fs::read_to_string(&obj_path)
-            .wrap_err_with(|| format!("Mesh not found: {:?}", &obj_path))?;
+        let obj_file = std::fs::read_to_string(obj_path)
+            .wrap_err_with(|| format!("Mesh not found: {:?}", obj_path))?;
 
         // A set of objects; a single wavefront OBJ file can contain multiple objects
-        let object_set = wobj::obj::parse(&obj_file.as_str()).wrap_err_with(|| {
+        let object_set = wobj::obj::parse(obj_file.as_str()).wrap_err_with(|| {
             format!("Error while parsing object set from file: {:?}", obj_path)
         })?;
 
@@ -192,7 +408,7 @@ impl AssetLoader {
         } else {
             Err(eyre!(
                 "Expected the model: {:?} to have at least one material",
-                self.root_path.join(&path)
+                obj_path
             ))
         }?;
 
@@ -204,92 +420,58 @@ impl AssetLoader {
             //let normals = object.normals;
             //let tex_vertices = object.tex_vertices;
 
-            let mut mesh_parts: Vec<MeshPartData> = Vec::with_capacity(object.geometry.len());
-            // For every geometry in an object
-            // TODO: Group geometries by material
-            for geometry in object.geometry.iter() {
-                // Create new mesh part
-                let material: Option<&Material> = match &geometry.material_name {
-                    Some(name) => material_set.materials.iter().find(|m| &m.name == name),
-                    None => None,
-                };
+            let groups = obj::group_by_material(object);
+            let mut mesh_parts: Vec<MeshPartData> = Vec::with_capacity(groups.len());
+            // Every material of an object gets a mesh part
+            for (material_name, geometries) in groups {
+                let material: Option<&Material> = material_name
+                    .and_then(|name| material_set.materials.iter().find(|m| m.name == name));
 
-                let mat_data = material.map_or(MaterialData::default(), |mat| MaterialData {
-                    specular_coefficient: mat.specular_coefficient as f32,
-                    // A quick hack to get things working
-                    lighting: mat.specular_coefficient != 0.0,
-                    color_ambient: mat.color_ambient.into(),
-                    color_diffuse: mat.color_diffuse.into(),
-                    color_specular: mat.color_specular.into(),
-                    color_emissive: mat
-                        .color_emissive
-                        .map_or(color::Rgb::default(), |m| m.into()),
-                    alpha: mat.alpha as f32,
-                    // ambient_map: mat.ambient_map.map(|path| self.load_map_img(&path).unwrap()),
-                    diffuse_map: mat
-                        .diffuse_map
-                        .as_ref()
-                        .and_then(|path| self.load_map_img(obj_parent.join(&path)).ok()),
+                let mat_data = material.map_or_else(MaterialData::default, |mat| {
+                    self.mtl_material(mat, obj_parent)
                 });
 
-                // If we create a index buffer with u32s it doesn't render correctly
-                let mut mesh_indices: Vec<u32> = Vec::new();
-                let mut mesh_vertices: Vec<Vertex> = Vec::new();
-
-                // Inserts a wobj::obj::Vertex to the Vecs above
-                let mut insert_vertex = |vtni: VTNIndex| {
-                    if let (posi, Some(uvi), Some(ni)) = vtni {
-                        let vertex = Vertex {
-                            pos: {
-                                let wobj::obj::Vertex { x, y, z } = object.vertices[posi];
-                                [x as f32, y as f32, z as f32]
-                            },
-                            uv: {
-                                let wobj::obj::TVertex { u, v, .. } = object.tex_vertices[uvi];
-                                [u as f32, v as f32]
-                            },
-                            normal: {
-                                let wobj::obj::Normal { x, y, z } = object.normals[ni];
-                                [x as f32, y as f32, z as f32]
-                            },
-                            _padding: [0.0; 6],
-                        };
-                        if let Some((i, _)) = mesh_vertices
-                            .iter()
-                            .enumerate()
-                            .find(|(_, v)| v == &&vertex)
-                        {
-                            mesh_indices.push(i as u32);
-                        } else {
-                            // Push a new index
-                            mesh_indices.push(mesh_vertices.len() as u32);
-                            mesh_vertices.push(vertex);
-                        }
-                    }
-                };
-
-                for shape in geometry.shapes.iter() {
-                    match shape.primitive {
-                        Primitive::Point(vtni) => insert_vertex(vtni),
-                        Primitive::Line(vtni0, vtni1) => {
-                            insert_vertex(vtni0);
-                            insert_vertex(vtni1);
-                        }
-                        Primitive::Triangle(vtni0, vtni1, vtni2) => {
-                            insert_vertex(vtni0);
-                            insert_vertex(vtni1);
-                            insert_vertex(vtni2);
-                        }
-                    }
-                }
+                let (mesh_vertices, mesh_indices) = obj::triangles(object, &geometries);
                 mesh_parts.push(MeshPartData {
                     vertices: mesh_vertices,
                     indices: mesh_indices,
                     material: mat_data,
                 })
             }
-            objects.push(data::MeshData { parts: mesh_parts });
+            objects.push(data::MeshData {
+                parts: mesh_parts,
+                node: MeshNode::default(),
+            });
         }
         Ok(objects)
     }
 }
+
+/// Like `ron::from_str`, for data which needs a seed to be deserialized (like scenes,
+/// which need the component registry)
+pub(crate) fn ron_from_str_seed<'de, S: DeserializeSeed<'de>>(
+    str: &'de str,
+    seed: S,
+) -> ron::Result<S::Value> {
+    let mut deserializer = ron::de::Deserializer::from_str(str)?;
+    let value = seed.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+/// The repository's assets, with stand-ins for the scene components only the game registers
+#[cfg(test)]
+pub(crate) fn test_loader() -> AssetLoader {
+    /// The game's spawn points (only their presence matters to the engine)
+    #[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+    enum SpawnState {
+        Normal,
+        Noclip,
+        Spectator,
+        Dead,
+    }
+
+    let mut loader = AssetLoader::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("../assets"));
+    loader.components_mut().register::<SpawnState>("SpawnPoint");
+    loader
+}
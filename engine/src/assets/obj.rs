@@ -0,0 +1,411 @@
+//! Turning the geometry of Wavefront OBJ objects into vertex and index buffers, and MTL bump
+//! maps into normal maps
+
+use std::collections::{hash_map::Entry, HashMap};
+
+use wavefront_obj::obj::{Geometry, Object, Primitive, VTNIndex};
+
+use crate::graphics::mesh::Vertex;
+
+/// Where the normal of a triangle's corner comes from
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum NormalSource {
+    /// Listed in the file
+    Index(usize),
+    /// Averaged over the faces in the smoothing group which share the corner's position
+    Smooth(u32),
+    /// The normal of the triangle with this index, for faces outside smoothing groups
+    Flat(usize),
+}
+
+/// Gathers the geometries of an object by their material, in the order the materials are
+/// first used, so each material gets a single mesh part (and draw call)
+pub fn group_by_material(object: &Object) -> Vec<(Option<&str>, Vec<&Geometry>)> {
+    let mut groups: Vec<(Option<&str>, Vec<&Geometry>)> = Vec::new();
+    for geometry in &object.geometry {
+        let material = geometry.material_name.as_deref();
+        match groups.iter_mut().find(|(name, _)| *name == material) {
+            Some((_, geometries)) => geometries.push(geometry),
+            None => groups.push((material, vec![geometry])),
+        }
+    }
+    groups
+}
+
+/// Builds the vertices and indices of the geometries' triangles. Corners with the same position,
+/// UV and normal share a vertex, found by hashing their indices (so loading is linear in the
+/// number of faces).
+///
+/// Corners without a normal get one computed from the faces: smoothed over the faces with
+/// the same smoothing group (`s 1`) or flat (`s off`). Corners without UVs get (0, 0).
+/// Points and lines are skipped, since meshes are drawn as triangle lists.
+pub fn triangles(object: &Object, geometries: &[&Geometry]) -> (Vec<Vertex>, Vec<u32>) {
+    let position = |i: usize| {
+        let wavefront_obj::obj::Vertex { x, y, z } = object.vertices[i];
+        na::Vector3::new(x as f32, y as f32, z as f32)
+    };
+
+    let shape_count = geometries.iter().map(|g| g.shapes.len()).sum();
+    let mut face_normals: Vec<na::Vector3<f32>> = Vec::with_capacity(shape_count);
+    // Sums of (area weighted) face normals, by position and smoothing group
+    let mut smooth_normals: HashMap<(usize, u32), na::Vector3<f32>> = HashMap::new();
+    let mut corners: Vec<(VTNIndex, NormalSource)> = Vec::with_capacity(shape_count * 3);
+    let mut skipped = 0;
+
+    for shape in geometries.iter().flat_map(|g| &g.shapes) {
+        let triangle = match shape.primitive {
+            Primitive::Triangle(a, b, c) => [a, b, c],
+            Primitive::Point(_) | Primitive::Line(..) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        let face = face_normals.len();
+        let [a, b, c] = triangle.map(|(pos, _, _)| position(pos));
+        face_normals.push((b - a).cross(&(c - a)));
+        // Any nonzero group will do; faces in several groups are rare
+        let group = shape.smoothing_groups.iter().copied().find(|&g| g != 0);
+
+        for vtni in triangle {
+            let source = match (vtni.2, group) {
+                (Some(normal), _) => NormalSource::Index(normal),
+                (None, Some(group)) => {
+                    *smooth_normals
+                        .entry((vtni.0, group))
+                        .or_insert_with(na::Vector3::zeros) += face_normals[face];
+                    NormalSource::Smooth(group)
+                }
+                (None, None) => NormalSource::Flat(face),
+            };
+            corners.push((vtni, source));
+        }
+    }
+    if skipped > 0 {
+        log::warn!(
+            "Skipped {} points and lines of object {}",
+            skipped,
+            object.name
+        );
+    }
+
+    // Most positions end up in about one vertex, though the object's other geometries
+    // may use most of them
+    let capacity = object.vertices.len().min(corners.len());
+    let mut vertices: Vec<Vertex> = Vec::with_capacity(capacity);
+    let mut indices: Vec<u32> = Vec::with_capacity(corners.len());
+    let mut vertex_indices: HashMap<(usize, Option<usize>, NormalSource), u32> =
+        HashMap::with_capacity(capacity);
+
+    for ((pos, uv, _), source) in corners {
+        let index = match vertex_indices.entry((pos, uv, source)) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let normal = match source {
+                    NormalSource::Index(i) => {
+                        let wavefront_obj::obj::Normal { x, y, z } = object.normals[i];
+                        na::Vector3::new(x as f32, y as f32, z as f32)
+                    }
+                    NormalSource::Smooth(group) => smooth_normals[&(pos, group)],
+                    NormalSource::Flat(face) => face_normals[face],
+                };
+                vertices.push(Vertex {
+                    pos: position(pos).into(),
+                    uv: uv.map_or([0.0; 2], |i| {
+                        let wavefront_obj::obj::TVertex { u, v, .. } = object.tex_vertices[i];
+                        [u as f32, v as f32]
+                    }),
+                    normal: match source {
+                        // Normals from the file are used as they are
+                        NormalSource::Index(_) => normal.into(),
+                        _ => normal
+                            .try_normalize(f32::EPSILON)
+                            .unwrap_or_else(na::Vector3::z)
+                            .into(),
+                    },
+                    _padding: [0.0; 6],
+                });
+                *entry.insert(vertices.len() as u32 - 1)
+            }
+        };
+        indices.push(index);
+    }
+    (vertices, indices)
+}
+
+/// How steep height maps are: a height going from 0 to 1 between neighbouring texels rises
+/// by this many texels
+const BUMP_STRENGTH: f32 = 8.0;
+
+/// Whether every pixel is a shade of gray, as in height maps (normal maps are mostly blue)
+pub(super) fn is_grayscale(image: &image::RgbaImage) -> bool {
+    image.pixels().all(|p| p[0] == p[1] && p[1] == p[2])
+}
+
+/// Computes a tangent-space normal map from the red channel of a height map.
+/// The image's rows go along +v (as the loader flips images).
+pub(super) fn height_to_normal_map(heights: &image::RgbaImage) -> image::RgbaImage {
+    let (width, height) = heights.dimensions();
+    let h = |x: u32, y: u32| heights.get_pixel(x, y)[0] as f32 / 255.0;
+    image::RgbaImage::from_fn(width, height, |x, y| {
+        // Central differences, clamped at the edges
+        let du = h((x + 1).min(width - 1), y) - h(x.saturating_sub(1), y);
+        let dv = h(x, (y + 1).min(height - 1)) - h(x, y.saturating_sub(1));
+        let normal =
+            na::Vector3::new(-du * BUMP_STRENGTH / 2.0, -dv * BUMP_STRENGTH / 2.0, 1.0).normalize();
+        let encode = |n: f32| ((n * 0.5 + 0.5) * 255.0).round() as u8;
+        image::Rgba([encode(normal.x), encode(normal.y), encode(normal.z), 255])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::{
+        data::{AlphaMode, Illumination},
+        AssetLoader,
+    };
+    use approx::assert_relative_eq;
+
+    /// Two triangles folded along their shared edge, with the given extra lines
+    fn folded_quad(extra: &str) -> Object {
+        let obj = format!(
+            "o Quad\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 1\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+             vn 0 0 1\n{}",
+            extra
+        );
+        let mut set = wavefront_obj::obj::parse(obj).unwrap();
+        set.objects.remove(0)
+    }
+
+    #[test]
+    fn test_shared_corners_share_vertices() {
+        let object = folded_quad("f 1/1/1 2/2/1 3/3/1\nf 1/1/1 3/3/1 4/4/1\n");
+        let (vertices, indices) = triangles(&object, &[&object.geometry[0]]);
+        // Polygons start at their last corner: (3, 1, 2) and (4, 1, 3)
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(indices, vec![0, 1, 2, 3, 1, 0]);
+        assert_eq!(vertices[0].uv, [1.0, 1.0]);
+        assert_eq!(vertices[3].normal, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_flat_normals_and_default_uvs() {
+        let object = folded_quad("s off\nf 1 2 3\nf 1 3 4\n");
+        let (vertices, indices) = triangles(&object, &[&object.geometry[0]]);
+        // The shared corners differ by their normals
+        assert_eq!(vertices.len(), 6);
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 5]);
+        assert!(vertices.iter().all(|v| v.uv == [0.0, 0.0]));
+        assert!(vertices[..3].iter().all(|v| v.normal == [0.0, 0.0, 1.0]));
+        let folded = na::Vector3::new(1.0, -1.0, 1.0).normalize();
+        for vertex in &vertices[3..] {
+            assert_relative_eq!(na::Vector3::from(vertex.normal), folded, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_smooth_normals() {
+        let object = folded_quad("s 1\nf 1//1 2 3\nf 1 3 4\n");
+        let (vertices, indices) = triangles(&object, &[&object.geometry[0]]);
+        // The first corner has its own normal; the corner at position 3 is shared
+        assert_eq!(vertices.len(), 5);
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 0]);
+        assert_eq!(vertices[1].normal, [0.0, 0.0, 1.0]);
+        assert_eq!(vertices[2].normal, [0.0, 0.0, 1.0]);
+        // Averaged over both faces, weighted by their areas
+        let shared =
+            (na::Vector3::new(0.0, 0.0, 1.0) + na::Vector3::new(1.0, -1.0, 1.0)).normalize();
+        assert_relative_eq!(
+            na::Vector3::from(vertices[0].normal),
+            shared,
+            epsilon = 1e-6
+        );
+        // The other face lists its own normal at position 1
+        assert_relative_eq!(
+            na::Vector3::from(vertices[4].normal),
+            na::Vector3::new(1.0, -1.0, 1.0).normalize(),
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn test_points_and_lines_are_skipped() {
+        let object = folded_quad("l 1\nl 1 2\nf 1 2 3\n");
+        let (vertices, indices) = triangles(&object, &[&object.geometry[0]]);
+        assert_eq!(vertices.len(), 3);
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_geometries_are_grouped_by_material() {
+        let object = folded_quad("usemtl A\nf 1 2 3\nusemtl B\nf 1 3 4\nusemtl A\nf 2 3 4\n");
+        assert_eq!(object.geometry.len(), 3);
+        let groups = group_by_material(&object);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].0, Some("A"));
+        assert_eq!(groups[0].1.len(), 2);
+        assert_eq!(groups[1].0, Some("B"));
+
+        let (vertices, indices) = triangles(&object, &groups[0].1);
+        assert_eq!(indices.len(), 6);
+        assert_eq!(vertices.len(), 6);
+    }
+
+    #[test]
+    fn test_height_maps_become_normal_maps() {
+        // Rising along +u on the left half, flat on the right
+        let heights = image::RgbaImage::from_fn(4, 2, |x, _| {
+            let h = [0, 64, 128, 128][x as usize];
+            image::Rgba([h, h, h, 255])
+        });
+        assert!(is_grayscale(&heights));
+        let normals = height_to_normal_map(&heights);
+        assert!(!is_grayscale(&normals));
+
+        let flat = normals.get_pixel(3, 0);
+        assert_eq!(flat.0, [128, 128, 255, 255]);
+        // Leaning back towards -u, without tilting along v
+        let slope = normals.get_pixel(1, 0);
+        assert!(slope[0] < 128);
+        assert_eq!(slope[1], 128);
+    }
+
+    #[test]
+    fn test_mtl_illumination_and_bump_maps() {
+        let dir = std::env::temp_dir().join(format!("obj-mtl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let material = |name: &str, ns: f32, illum: u32, maps: &str| {
+            format!(
+                "newmtl {}\nNs {}\nKa 1 1 1\nKd 1 1 1\nKs 0.5 0.5 0.5\nd 1\nillum {}\n{}\n",
+                name, ns, illum, maps
+            )
+        };
+        let mtl = [
+            material("Rough", 0.0, 2, "map_bump bump.png"),
+            material("Matte", 10.0, 1, ""),
+            material("Flat", 10.0, 0, ""),
+            material("Shiny", 10.0, 2, ""),
+        ]
+        .concat();
+        std::fs::write(dir.join("test.mtl"), mtl).unwrap();
+        std::fs::write(
+            dir.join("test.obj"),
+            "mtllib test.mtl\no Test\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\n\
+             usemtl Rough\nf 1//1 2//1 3//1\nusemtl Matte\nf 1//1 3//1 4//1\n\
+             usemtl Flat\nf 2//1 3//1 4//1\nusemtl Shiny\nf 1//1 2//1 4//1\n",
+        )
+        .unwrap();
+        let bump = image::RgbaImage::from_fn(4, 4, |x, _| {
+            image::Rgba([x as u8 * 60, x as u8 * 60, x as u8 * 60, 255])
+        });
+        bump.save(dir.join("bump.png")).unwrap();
+
+        let loader = AssetLoader::new(dir.clone());
+        let meshes = loader.load_model("test.obj").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let materials: Vec<_> = meshes[0].parts.iter().map(|p| &p.material).collect();
+        // An exponent of 0 turns the highlights off
+        assert_eq!(materials[0].illumination, Illumination::Diffuse);
+        assert_eq!(materials[1].illumination, Illumination::Diffuse);
+        assert_eq!(materials[2].illumination, Illumination::Unlit);
+        assert_eq!(materials[3].illumination, Illumination::Specular);
+
+        // The height map became a normal map
+        let normal_map = materials[0].normal_map.as_ref().unwrap();
+        assert!(normal_map
+            .path
+            .to_string_lossy()
+            .ends_with("bump.png#normal"));
+        assert!(!is_grayscale(&normal_map.image));
+        assert!(materials[1].normal_map.is_none());
+    }
+
+    #[test]
+    fn test_mtl_alpha_modes() {
+        let dir = std::env::temp_dir().join(format!("obj-alpha-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let material = |name: &str, dissolve: f32, maps: &str| {
+            format!(
+                "newmtl {}\nNs 10\nKa 1 1 1\nKd 1 1 1\nKs 0.5 0.5 0.5\nd {}\nillum 2\n{}\n",
+                name, dissolve, maps
+            )
+        };
+        let mtl = [
+            material("Glass", 0.5, ""),
+            material("Leaves", 1.0, "map_d leaves.png"),
+            material("Wall", 1.0, ""),
+        ]
+        .concat();
+        std::fs::write(dir.join("test.mtl"), mtl).unwrap();
+        std::fs::write(
+            dir.join("test.obj"),
+            "mtllib test.mtl\no Test\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\n\
+             usemtl Glass\nf 1//1 2//1 3//1\nusemtl Leaves\nf 1//1 3//1 4//1\n\
+             usemtl Wall\nf 2//1 3//1 4//1\n",
+        )
+        .unwrap();
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 255, 255, 255]))
+            .save(dir.join("leaves.png"))
+            .unwrap();
+
+        let loader = AssetLoader::new(dir.clone());
+        let meshes = loader.load_model("test.obj").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let modes: Vec<_> = meshes[0]
+            .parts
+            .iter()
+            .map(|p| p.material.alpha_mode)
+            .collect();
+        assert_eq!(
+            modes,
+            [AlphaMode::Blend, AlphaMode::Mask(0.5), AlphaMode::Opaque]
+        );
+    }
+
+    #[test]
+    fn test_bundled_models() {
+        let assets = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../assets");
+        let loader = AssetLoader::new(assets.clone());
+        // Every .obj file under the models directory, including subdirectories
+        let mut dirs = vec![assets.join("models")];
+        let mut paths = Vec::new();
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|ext| ext == "obj") {
+                    paths.push(path);
+                }
+            }
+        }
+        assert!(!paths.is_empty());
+
+        for path in &paths {
+            let meshes = loader
+                .load_model(path.strip_prefix(&assets).unwrap())
+                .unwrap();
+            let parts: Vec<_> = meshes.iter().flat_map(|mesh| &mesh.parts).collect();
+            assert!(!parts.is_empty(), "{:?}", path);
+            for part in parts {
+                assert!(!part.vertices.is_empty(), "{:?}", path);
+                assert!(!part.indices.is_empty(), "{:?}", path);
+                assert_eq!(part.indices.len() % 3, 0, "{:?}", path);
+                assert!(part.vertices.len() <= part.indices.len(), "{:?}", path);
+                assert!(
+                    part.indices
+                        .iter()
+                        .all(|&i| (i as usize) < part.vertices.len()),
+                    "{:?}",
+                    path
+                );
+                // Every vertex is used
+                let mut used = vec![false; part.vertices.len()];
+                part.indices.iter().for_each(|&i| used[i as usize] = true);
+                assert!(used.into_iter().all(|used| used), "{:?}", path);
+            }
+        }
+    }
+}
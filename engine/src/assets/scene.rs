@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+
+use eyre::{eyre::eyre, Result};
+use legion::{Entity, IntoQuery, World};
+
+use super::{
+    components::ComponentRegistry,
+    data::{MeshCollider, MeshNode, Model, Scene},
+};
+use crate::{
+    spacetime::{self, Child},
+    state::Scoped,
+};
+
+/// Remembers which scene object an entity was spawned from, so the scene can be saved again
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelSource {
+    /// Path of the model file, relative to the assets directory
+    pub obj: String,
+    /// Index of the entity among the objects in the model file
+    pub part: usize,
+    /// Where the entity is relative to the object, or to its parent part (see `MeshNode`)
+    pub offset: na::Isometry3<f32>,
+    pub collider: Option<MeshCollider>,
+    /// Names of the registered components the object listed
+    pub components: Vec<&'static str>,
+}
+
+/// Spawns the objects of a scene. `spawn_parts` pushes an entity for every part of
+/// an object's model, along with where the part is in the model; their Position, Scale,
+/// parenting, components etc. get set up here. Objects without a model get a single entity.
+pub(crate) fn instantiate<F>(
+    world: &mut World,
+    scene: &Scene,
+    scoped: Option<Scoped>,
+    mut spawn_parts: F,
+) -> Result<()>
+where
+    F: FnMut(&mut World, &Model) -> Result<Vec<(Entity, MeshNode)>>,
+{
+    // The first entity spawned for each object, which its children get attached to
+    let mut object_entities: HashMap<usize, Entity> = HashMap::new();
+
+    for i in spawn_order(&scene.objects)? {
+        let object = &scene.objects[i];
+        let parts = if object.obj.is_empty() {
+            vec![(world.push(()), MeshNode::default())]
+        } else {
+            spawn_parts(world, object)?
+        };
+
+        let local: spacetime::Position = object.pos.into();
+        let (position, child) = match object.parent {
+            Some(parent) => {
+                let parent_entity = *object_entities
+                    .get(&parent)
+                    .ok_or_else(|| eyre!("Parent object {} has no entities", parent))?;
+                let parent_position = *world
+                    .entry(parent_entity)
+                    .unwrap()
+                    .get_component::<spacetime::Position>()?;
+                // The object's position is relative to its parent
+                let child = Child {
+                    parent: parent_entity,
+                    offset: local,
+                };
+                (parent_position * local, Some(child))
+            }
+            None => (local, None),
+        };
+        let scale: Option<spacetime::Scale> = object.scale.map(|s| s.into());
+        // The parts' offsets get scaled along with their meshes
+        let scaled = |offset: na::Isometry3<f32>| match scale {
+            Some(scale) => na::Isometry3::from_parts(
+                offset.translation.vector.component_mul(&scale).into(),
+                offset.rotation,
+            ),
+            None => offset,
+        };
+
+        let mut part_positions: Vec<spacetime::Position> = Vec::with_capacity(parts.len());
+        for (part, &(ent, node)) in parts.iter().enumerate() {
+            let offset = scaled(node.transform);
+            // Parts of a model with a node hierarchy are attached to their parent part
+            let (position, child) = match node.parent {
+                Some(parent) if parent < part => (
+                    part_positions[parent] * offset.into(),
+                    Some(Child {
+                        parent: parts[parent].0,
+                        offset: offset.into(),
+                    }),
+                ),
+                Some(parent) => {
+                    return Err(eyre!(
+                        "Part {} of {:?} comes before its parent part {}",
+                        part,
+                        object.obj,
+                        parent
+                    ))
+                }
+                None => (
+                    position * offset.into(),
+                    child.map(|child| Child {
+                        offset: child.offset * offset.into(),
+                        ..child
+                    }),
+                ),
+            };
+            part_positions.push(position);
+
+            let mut entry = world.entry(ent).unwrap();
+            entry.add_component(position);
+            entry.add_component(ModelSource {
+                obj: object.obj.clone(),
+                part,
+                offset,
+                collider: object.collider,
+                components: object.components.iter().map(|c| c.name()).collect(),
+            });
+            for component in &object.components {
+                component.add_to(&mut entry);
+            }
+            if let Some(scale) = scale {
+                entry.add_component(scale);
+            }
+            if let Some(scope) = scoped {
+                entry.add_component(scope);
+            }
+            if let Some(child) = child {
+                entry.add_component(child);
+            }
+        }
+
+        if let Some(&(first, _)) = parts.first() {
+            object_entities.insert(i, first);
+        }
+    }
+    Ok(())
+}
+
+/// Builds a Scene out of every entity that was spawned from a scene object
+pub(crate) fn scene_from_world(world: &World, registry: &ComponentRegistry) -> Scene {
+    let mut query = <(
+        Entity,
+        &ModelSource,
+        &spacetime::Position,
+        Option<&spacetime::Scale>,
+        Option<&Child>,
+    )>::query();
+    // The other parts of a model get spawned along with the first one
+    let sources: Vec<_> = query
+        .iter(world)
+        .filter(|(_, source, ..)| source.part == 0)
+        .collect();
+    let indices: HashMap<Entity, usize> = sources
+        .iter()
+        .enumerate()
+        .map(|(i, (entity, ..))| (**entity, i))
+        .collect();
+
+    let objects = sources
+        .iter()
+        .map(|(entity, source, position, scale, child)| {
+            let parent = child.and_then(|child| indices.get(&child.parent).copied());
+            // Children whose parent wasn't spawned from the scene become top-level objects
+            let pos = match (parent, child) {
+                (Some(_), Some(child)) => child.offset.future(),
+                _ => position.future(),
+            };
+            // The first part is a root of the model, offset from the object itself
+            let pos = pos * source.offset.inverse();
+            Model {
+                pos: pos.into(),
+                scale: scale.map(|s| (*s).into()),
+                obj: source.obj.clone(),
+                parent,
+                collider: source.collider,
+                components: source
+                    .components
+                    .iter()
+                    .filter_map(|name| registry.extract(name, world, **entity))
+                    .collect(),
+            }
+        })
+        .collect();
+
+    Scene { objects }
+}
+
+/// Indices of the scene's objects, ordered so that every parent gets spawned before its children
+fn spawn_order(objects: &[Model]) -> Result<Vec<usize>> {
+    let mut ordered = Vec::with_capacity(objects.len());
+    for (i, _) in objects.iter().enumerate() {
+        let mut depth = 0;
+        let mut current = i;
+        while let Some(parent) = objects[current].parent {
+            if parent >= objects.len() {
+                return Err(eyre!("Incorrect parent index found: {}", parent));
+            }
+            depth += 1;
+            if depth > objects.len() {
+                return Err(eyre!("Object {} is its own ancestor", i));
+            }
+            current = parent;
+        }
+        ordered.push((depth, i));
+    }
+    ordered.sort_by_key(|(depth, _)| *depth);
+    Ok(ordered.into_iter().map(|(_, i)| i).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::{
+        data::{self, Axis, Rotation},
+        ron_from_str_seed, AssetLoader,
+    };
+
+    fn model(parent: Option<usize>) -> Model {
+        Model {
+            pos: data::Position {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+                rotation: None,
+            },
+            scale: None,
+            obj: String::new(),
+            parent,
+            collider: None,
+            components: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parents_spawn_before_children() {
+        let objects = vec![model(Some(2)), model(None), model(Some(1)), model(Some(0))];
+        let order = spawn_order(&objects).unwrap();
+
+        let position = |i: usize| order.iter().position(|&o| o == i).unwrap();
+        assert_eq!(order.len(), objects.len());
+        assert!(position(1) < position(2));
+        assert!(position(2) < position(0));
+        assert!(position(0) < position(3));
+    }
+
+    #[test]
+    fn test_invalid_parents_are_errors() {
+        assert!(spawn_order(&[model(Some(5))]).is_err());
+        assert!(spawn_order(&[model(Some(1)), model(Some(0))]).is_err());
+    }
+
+    /// Stands in for a RenderMesh, which needs a GPU
+    struct Part;
+
+    /// Spawns two parts for every model, like a glTF file with a node and its child
+    fn spawn_two_parts(world: &mut World, _: &Model) -> Result<Vec<(Entity, MeshNode)>> {
+        let root = MeshNode {
+            transform: na::Isometry3::translation(0.0, 0.0, 1.0),
+            parent: None,
+        };
+        let child = MeshNode {
+            transform: na::Isometry3::rotation(na::Vector3::z() * 1.0),
+            parent: Some(0),
+        };
+        Ok(vec![
+            (world.push((Part,)), root),
+            (world.push((Part,)), child),
+        ])
+    }
+
+    type Snapshot = Vec<(
+        ModelSource,
+        na::Isometry3<f32>,
+        Option<spacetime::Scale>,
+        Option<String>,
+    )>;
+
+    /// Everything that describes the scene objects in the world, in a comparable form
+    fn snapshot(world: &World) -> Snapshot {
+        let mut query = <(
+            &ModelSource,
+            &spacetime::Position,
+            Option<&spacetime::Scale>,
+            Option<&Child>,
+        )>::query();
+        let mut snapshot: Snapshot = query
+            .iter(world)
+            .map(|(source, position, scale, child)| {
+                let parent = child.map(|child| {
+                    let parent = <&ModelSource>::query().get(world, child.parent).unwrap();
+                    parent.obj.clone()
+                });
+                (source.clone(), *position.future(), scale.copied(), parent)
+            })
+            .collect();
+        snapshot.sort_by(|a, b| (&a.0.obj, a.0.part).cmp(&(&b.0.obj, b.0.part)));
+        snapshot
+    }
+
+    #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Tag(String);
+
+    #[test]
+    fn test_scene_round_trip() {
+        let mut registry = ComponentRegistry::default();
+        registry.register::<Tag>("Tag");
+
+        let pos = |x, y, z, rotation| data::Position { x, y, z, rotation };
+        // An object without a model, only carrying components
+        let marker = ron_from_str_seed(
+            "(pos: (x: 0.0, y: 1.0, z: 0.0, rotation: None), scale: None, \
+             parent: Some(2), components: [Tag((\"marker\"))])",
+            data::ModelSeed(&registry),
+        )
+        .unwrap();
+        let mut scene = Scene {
+            objects: vec![
+                Model {
+                    pos: pos(1.0, 2.0, 3.0, Some(Rotation::Axis(Axis::Z, 90.0))),
+                    scale: Some(data::Scale::All(2.0)),
+                    obj: "models/root.obj".into(),
+                    parent: None,
+                    collider: Some(MeshCollider::ConvexHull),
+                    components: Vec::new(),
+                },
+                Model {
+                    pos: pos(0.0, 0.0, 1.0, Some(Rotation::Euler(10.0, 20.0, 30.0))),
+                    scale: None,
+                    obj: "models/grandchild.obj".into(),
+                    parent: Some(2),
+                    collider: None,
+                    components: Vec::new(),
+                },
+                Model {
+                    pos: pos(1.0, 0.0, 0.0, None),
+                    scale: Some(data::Scale::Xyz(1.0, 2.0, 3.0)),
+                    obj: "models/child.obj".into(),
+                    parent: Some(0),
+                    collider: Some(MeshCollider::TriMesh),
+                    components: Vec::new(),
+                },
+            ],
+        };
+        scene.objects.push(marker);
+
+        let mut world = World::default();
+        instantiate(&mut world, &scene, None, spawn_two_parts).unwrap();
+
+        let saved = ron::to_string(&scene_from_world(&world, &registry)).unwrap();
+        let loaded = ron_from_str_seed(&saved, data::SceneSeed(&registry)).unwrap();
+        assert_eq!(loaded.objects.len(), scene.objects.len());
+
+        let mut reloaded_world = World::default();
+        instantiate(&mut reloaded_world, &loaded, None, spawn_two_parts).unwrap();
+
+        let (original, reloaded) = (snapshot(&world), snapshot(&reloaded_world));
+        assert_eq!(original.len(), 7);
+        assert_eq!(original.len(), reloaded.len());
+        for (a, b) in original.iter().zip(reloaded.iter()) {
+            assert_eq!(a.0, b.0);
+            assert!(approx::relative_eq!(a.1, b.1, epsilon = 1.0e-4));
+            assert_eq!(a.2, b.2);
+            assert_eq!(a.3, b.3);
+        }
+
+        let tags: Vec<Tag> = <&Tag>::query().iter(&reloaded_world).cloned().collect();
+        assert_eq!(tags, vec![Tag("marker".into())]);
+    }
+
+    #[test]
+    #[ignore = "needs a wgpu adapter"]
+    fn test_load_save_load_scene() {
+        let mut resources = legion::Resources::default();
+        resources.insert(crate::assets::test_loader());
+        let graphics = futures::executor::block_on(crate::graphics::setup_headless(
+            64,
+            48,
+            &mut World::default(),
+            &mut resources,
+        ))
+        .unwrap();
+        let loader = resources.get::<AssetLoader>().unwrap().clone();
+        let registry = loader.components();
+
+        let mut world = World::default();
+        loader
+            .load_scene(&mut world, &graphics.shared, "scenes/test.ron", None)
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("saved-scene-{}.ron", std::process::id()));
+        let saved_path = loader.save_scene(&world, path.to_str().unwrap()).unwrap();
+        assert!(saved_path.is_absolute());
+        let mut reloaded_world = World::default();
+        let reloaded = loader.load_scene(
+            &mut reloaded_world,
+            &graphics.shared,
+            path.to_str().unwrap(),
+            None,
+        );
+        std::fs::remove_file(&path).unwrap();
+        reloaded.unwrap();
+
+        let (original, reloaded) = (snapshot(&world), snapshot(&reloaded_world));
+        assert!(!original.is_empty());
+        assert_eq!(original.len(), reloaded.len());
+        for (a, b) in original.iter().zip(reloaded.iter()) {
+            assert_eq!(a.0, b.0);
+            assert!(approx::relative_eq!(a.1, b.1, epsilon = 1.0e-4));
+            assert_eq!(a.2, b.2);
+            assert_eq!(a.3, b.3);
+        }
+
+        // Every object keeps its components
+        let components = |world: &World| -> Vec<String> {
+            let mut components: Vec<String> = scene_from_world(world, registry)
+                .objects
+                .iter()
+                .map(|object| ron::to_string(&(&object.obj, &object.components)).unwrap())
+                .collect();
+            components.sort();
+            components
+        };
+        let saved = components(&world);
+        assert_eq!(saved.len(), 6);
+        assert_eq!(saved, components(&reloaded_world));
+    }
+
+    #[test]
+    #[ignore = "needs a wgpu adapter"]
+    fn test_reload_scene() {
+        let mut resources = legion::Resources::default();
+        resources.insert(crate::assets::test_loader());
+        let graphics = futures::executor::block_on(crate::graphics::setup_headless(
+            64,
+            48,
+            &mut World::default(),
+            &mut resources,
+        ))
+        .unwrap();
+        let loader = resources.get::<AssetLoader>().unwrap().clone();
+
+        let mut world = World::default();
+        loader
+            .load_scene(&mut world, &graphics.shared, "scenes/test.ron", None)
+            .unwrap();
+        // Not spawned from the scene, like the player
+        let other = world.push((spacetime::Position::from(na::Isometry3::identity()),));
+        let scene_entities = |world: &World| -> Vec<Entity> {
+            <(Entity, &ModelSource)>::query()
+                .iter(world)
+                .map(|(entity, _)| *entity)
+                .collect()
+        };
+        let original = scene_entities(&world);
+
+        // A broken scene keeps the old world
+        let path = std::env::temp_dir().join(format!("broken-scene-{}.ron", std::process::id()));
+        std::fs::write(&path, "(objects: [(pos: ").unwrap();
+        let result =
+            loader.reload_scene(&mut world, &graphics.shared, path.to_str().unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(scene_entities(&world), original);
+
+        loader
+            .reload_scene(&mut world, &graphics.shared, "scenes/test.ron", None)
+            .unwrap();
+        let reloaded = scene_entities(&world);
+        assert_eq!(reloaded.len(), original.len());
+        assert!(original.iter().all(|entity| world.entry(*entity).is_none()));
+        assert!(world.entry(other).is_some());
+        assert_eq!(snapshot(&world).len(), reloaded.len());
+    }
+}
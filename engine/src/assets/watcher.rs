@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use super::AssetLoader;
+
+/// Modification times of every file in a directory (recursively), by path relative to it
+type Snapshot = HashMap<PathBuf, SystemTime>;
+
+/// Watches the assets directory for changed files (see `AssetLoader::watch`).
+/// Stops watching when dropped.
+pub struct AssetWatcher {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for AssetWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl AssetLoader {
+    /// Checks the assets directory for created or modified files every `interval`,
+    /// calling `on_change` (from a background thread) with the path of every changed file,
+    /// relative to the assets directory.
+    ///
+    /// Files are compared by their modification times, so no OS-specific APIs are needed.
+    pub fn watch(
+        &self,
+        interval: Duration,
+        mut on_change: impl FnMut(PathBuf) + Send + 'static,
+    ) -> AssetWatcher {
+        let stop = Arc::new(AtomicBool::new(false));
+        let root = self.root_path().to_owned();
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            let mut snapshot = scan(&root);
+            while !stopped.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+                let current = scan(&root);
+                for path in changes(&snapshot, &current) {
+                    log::debug!("Asset changed: {:?}", path);
+                    on_change(path);
+                }
+                snapshot = current;
+            }
+        });
+        AssetWatcher { stop }
+    }
+}
+
+fn scan(root: &Path) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        // Files can disappear while scanning; they'll just be missing from the snapshot
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if let (Ok(modified), Ok(path)) =
+                (metadata.modified(), entry.path().strip_prefix(root))
+            {
+                snapshot.insert(path.to_owned(), modified);
+            }
+        }
+    }
+    snapshot
+}
+
+/// Files which were created or modified between the snapshots, sorted by path
+fn changes(old: &Snapshot, new: &Snapshot) -> Vec<PathBuf> {
+    let mut changed: Vec<PathBuf> = new
+        .iter()
+        .filter(|(path, modified)| old.get(*path) != Some(modified))
+        .map(|(path, _)| path.clone())
+        .collect();
+    changed.sort();
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_created_and_modified_files_are_changes() {
+        let time = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let snapshot = |files: &[(&str, u64)]| -> Snapshot {
+            files
+                .iter()
+                .map(|&(path, secs)| (PathBuf::from(path), time(secs)))
+                .collect()
+        };
+        let old = snapshot(&[("a.ron", 1), ("shaders/b.wgsl", 1), ("removed.obj", 1)]);
+        let new = snapshot(&[("a.ron", 1), ("shaders/b.wgsl", 2), ("scenes/c.ron", 1)]);
+        assert_eq!(
+            changes(&old, &new),
+            vec![
+                PathBuf::from("scenes/c.ron"),
+                PathBuf::from("shaders/b.wgsl")
+            ]
+        );
+        assert!(changes(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_scan_finds_nested_files() {
+        let root = std::env::temp_dir().join(format!("asset-watcher-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("settings")).unwrap();
+        std::fs::write(root.join("settings/game.ron"), "()").unwrap();
+        std::fs::write(root.join("top.ron"), "()").unwrap();
+
+        let snapshot = scan(&root);
+        std::fs::remove_dir_all(&root).unwrap();
+        let mut paths: Vec<_> = snapshot.keys().cloned().collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("settings/game.ron"), PathBuf::from("top.ron")]
+        );
+    }
+}
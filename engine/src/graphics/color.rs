@@ -1,7 +1,9 @@
 #![allow(dead_code)]
 #![allow(clippy::from_over_into)]
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct Rgb<T = f32> {
     r: T,
     g: T,
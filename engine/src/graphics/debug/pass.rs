@@ -22,13 +22,15 @@ pub struct DebugPass {
     pub vertex_buf: wgpu::Buffer,
 
     pub pipeline: wgpu::RenderPipeline,
+
+    /// Size of the render target in pixels, for getting the thickness of lines on screen
+    target_size: (u32, u32),
 }
 
 impl DebugPass {
     pub fn new(
         device: &wgpu::Device,
         surface_config: &wgpu::SurfaceConfiguration,
-        _window: &winit::window::Window,
         _queue: &wgpu::Queue,
         _world: &mut World,
         resources: &mut Resources,
@@ -196,6 +198,7 @@ impl DebugPass {
             line_uniform_buf,
             vertex_buf,
             pipeline,
+            target_size: (surface_config.width, surface_config.height),
         })
     }
 }
@@ -204,10 +207,11 @@ impl Pass for DebugPass {
     fn resize(
         &mut self,
         _graphics: &crate::graphics::GraphicsShared,
-        _surface_config: &wgpu::SurfaceConfiguration,
+        surface_config: &wgpu::SurfaceConfiguration,
         _world: &mut World,
         _resources: &mut Resources,
     ) -> Result<()> {
+        self.target_size = (surface_config.width, surface_config.height);
         Ok(())
     }
 
@@ -293,9 +297,8 @@ impl Pass for DebugPass {
                         0,
                         bytemuck::bytes_of(&super::DebugLinesUniforms {
                             screen_thickness: [
-                                // TODO: get width/height from surface_config instead
-                                lines.thickness / (graphics.window.inner_size().width as f32),
-                                lines.thickness / (graphics.window.inner_size().height as f32),
+                                lines.thickness / (self.target_size.0 as f32),
+                                lines.thickness / (self.target_size.1 as f32),
                             ],
                         }),
                     )
@@ -0,0 +1,346 @@
+use std::{num::NonZeroU32, rc::Rc};
+
+use eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use legion::{Resources, World};
+
+use super::{
+    debug::DebugPass,
+    mesh::MeshPass,
+    render_world,
+    setup::{backend_from_env, create_depth_texture, create_shared, request_device},
+    GraphicsShared, ShadowPass,
+};
+
+/// Format of the offscreen texture, which is read back as-is into an `image::RgbaImage`
+const TARGET_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Graphics without a window: frames are rendered into an offscreen texture and read back
+/// into images (for tests and screenshots). Only the world is rendered, without any UI.
+pub struct HeadlessGraphics {
+    pub device: Rc<wgpu::Device>,
+    pub queue: Rc<wgpu::Queue>,
+
+    pub shadow_pass: ShadowPass,
+    pub mesh_pass: MeshPass,
+    pub debug_pass: Option<DebugPass>,
+
+    /// Describes the offscreen texture (there's no actual surface)
+    pub surface_config: wgpu::SurfaceConfiguration,
+    target: wgpu::Texture,
+
+    /// Only kept alive for its view
+    _depth_texture: wgpu::Texture,
+    depth_texture_view: wgpu::TextureView,
+
+    pub shared: GraphicsShared,
+}
+
+/// Picks a GPU adapter, falling back to a software one
+async fn find_adapter(backend: Option<wgpu::Backends>) -> Option<wgpu::Adapter> {
+    let instance = wgpu::Instance::new(backend.unwrap_or_else(wgpu::Backends::all));
+    for force_fallback_adapter in [false, true] {
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter,
+            })
+            .await;
+        if adapter.is_some() {
+            return adapter;
+        }
+    }
+    None
+}
+
+/// Sets up graphics rendering `width` x `height` images, like `setup` does for a window.
+/// Uses a software adapter (like llvmpipe) when there's no GPU.
+pub async fn setup_headless(
+    width: u32,
+    height: u32,
+    world: &mut World,
+    resources: &mut Resources,
+) -> Result<HeadlessGraphics> {
+    let backend = backend_from_env();
+    let adapter = find_adapter(backend).await.ok_or_else(|| {
+        eyre!(
+            "Couldn't find any graphics adapter (not even a software one) for backend: {:?}",
+            backend
+        )
+    })?;
+    log::info!("Rendering offscreen with {:?}", adapter.get_info());
+
+    let (device, queue) = request_device(&adapter).await?;
+
+    let surface_config = wgpu::SurfaceConfiguration {
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        format: TARGET_FORMAT,
+        width,
+        height,
+        present_mode: wgpu::PresentMode::Fifo,
+    };
+    let target = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("offscreen target"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: surface_config.format,
+        usage: surface_config.usage,
+    });
+
+    // Initialize render passes
+    let mesh_bind_group_layout = Rc::new(MeshPass::create_mesh_bind_group_layout(&device));
+    let shadow_pass = ShadowPass::new(&device, mesh_bind_group_layout.clone(), world, resources)?;
+    let mesh_pass = MeshPass::new(
+        &device,
+        &surface_config,
+        mesh_bind_group_layout,
+        &shadow_pass,
+        world,
+        resources,
+    )?;
+    let debug_pass = DebugPass::new(&device, &surface_config, &queue, world, resources)?;
+
+    let device = Rc::new(device);
+    let queue = Rc::new(queue);
+
+    let shared = create_shared(&device, &queue, None, &mesh_pass);
+    resources.insert(shared.clone());
+
+    let (depth_texture, depth_texture_view) = create_depth_texture(&device, &surface_config);
+
+    Ok(HeadlessGraphics {
+        device,
+        queue,
+        shadow_pass,
+        mesh_pass,
+        debug_pass: Some(debug_pass),
+        surface_config,
+        target,
+        shared,
+        _depth_texture: depth_texture,
+        depth_texture_view,
+    })
+}
+
+impl HeadlessGraphics {
+    /// Renders a frame and waits for it to be read back
+    pub fn render(&mut self, world: &World, resources: &Resources) -> Result<image::RgbaImage> {
+        let (width, height) = (self.surface_config.width, self.surface_config.height);
+        // Rows of a buffer copied from a texture have to be aligned
+        let unpadded_bytes_per_row = 4 * width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+        let readback_buf = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("offscreen readback buffer"),
+            size: (bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let mut target_view = self
+            .target
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        render_world(
+            &self.shared,
+            &mut self.shadow_pass,
+            &mut self.mesh_pass,
+            self.debug_pass.as_mut(),
+            &mut encoder,
+            &mut target_view,
+            &self.depth_texture_view,
+            world,
+            resources,
+        );
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &self.target,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &readback_buf,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(bytes_per_row),
+                    rows_per_image: NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        // Wait for the frame and copy it out of the buffer, without the padding
+        let slice = readback_buf.slice(..);
+        let mapped = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapped).wrap_err("Failed to read the frame back")?;
+        let pixels = slice
+            .get_mapped_range()
+            .chunks(bytes_per_row as usize)
+            .flat_map(|row| &row[..unpadded_bytes_per_row as usize])
+            .copied()
+            .collect();
+        readback_buf.unmap();
+
+        image::RgbaImage::from_raw(width, height, pixels)
+            .ok_or_else(|| eyre!("The frame read back has the wrong size"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assets::{test_loader, AssetLoader},
+        graphics::{Camera, MainCamera},
+    };
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+
+    /// Reference rendering of `scenes/test.ron`, made with the software adapter (llvmpipe).
+    /// With `UPDATE_GOLDEN` set the test writes it instead, to be checked and committed.
+    const GOLDEN_TEST_SCENE: &str = "golden/test_scene.png";
+    /// Software and hardware rasterizers differ slightly along edges, so channels may be off
+    /// by this much...
+    const GOLDEN_CHANNEL_TOLERANCE: u8 = 8;
+    /// ...and this fraction of the pixels may be off by more
+    const GOLDEN_PIXEL_TOLERANCE: f32 = 0.01;
+
+    /// Headless graphics with the repository's assets, or None (so the test is skipped)
+    /// when there's no adapter at all, not even a software one
+    fn setup(
+        width: u32,
+        height: u32,
+        world: &mut World,
+        resources: &mut Resources,
+    ) -> Option<HeadlessGraphics> {
+        let backend = backend_from_env();
+        if futures::executor::block_on(find_adapter(backend)).is_none() {
+            eprintln!(
+                "Skipping the test, there's no graphics adapter for backend: {:?}",
+                backend
+            );
+            return None;
+        }
+        resources.insert(test_loader());
+        Some(futures::executor::block_on(setup_headless(width, height, world, resources)).unwrap())
+    }
+
+    fn main_camera(width: u32, height: u32, position: na::Isometry3<f32>) -> MainCamera {
+        MainCamera {
+            camera: Camera::new(
+                width as f32 / height as f32,
+                45_f32.to_radians(),
+                0.001,
+                1000.0,
+            ),
+            position: position.into(),
+        }
+    }
+
+    #[test]
+    fn test_renders_offscreen() {
+        let mut world = World::default();
+        let mut resources = Resources::default();
+        let mut graphics = match setup(WIDTH, HEIGHT, &mut world, &mut resources) {
+            Some(graphics) => graphics,
+            None => return,
+        };
+
+        // Nothing to see without a camera, only the clear color
+        let empty = graphics.render(&world, &resources).unwrap();
+        assert_eq!(empty.dimensions(), (WIDTH, HEIGHT));
+        let background = *empty.get_pixel(0, 0);
+        assert!(empty.pixels().all(|pixel| *pixel == background));
+
+        // A model right in front of the camera covers the middle of the frame
+        let loader = resources.get::<AssetLoader>().unwrap().clone();
+        let shared = graphics.shared.clone();
+        let scene = "(objects: [Model(pos: Position(x: 0.0, y: 8.0, z: 0.0, rotation: None), \
+                     scale: None, obj: \"models/dice.obj\", parent: None)])";
+        let path = std::env::temp_dir().join(format!("headless-{}.ron", std::process::id()));
+        std::fs::write(&path, scene).unwrap();
+        let loaded = loader.load_scene(&mut world, &shared, path.to_str().unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        resources.insert(main_camera(WIDTH, HEIGHT, na::Isometry3::identity()));
+
+        let frame = graphics.render(&world, &resources).unwrap();
+        assert_ne!(*frame.get_pixel(WIDTH / 2, HEIGHT / 2), background);
+        assert_eq!(*frame.get_pixel(0, 0), background);
+    }
+
+    #[test]
+    fn test_scene_matches_golden_image() {
+        let (width, height) = (160, 120);
+        let mut world = World::default();
+        let mut resources = Resources::default();
+        let mut graphics = match setup(width, height, &mut world, &mut resources) {
+            Some(graphics) => graphics,
+            None => return,
+        };
+
+        let loader = resources.get::<AssetLoader>().unwrap().clone();
+        let shared = graphics.shared.clone();
+        loader
+            .load_scene(&mut world, &shared, "scenes/test.ron", None)
+            .unwrap();
+        // Behind the spawn point, looking along +y at the models
+        resources.insert(main_camera(
+            width,
+            height,
+            na::Isometry3::translation(1.0, -7.0, 2.0),
+        ));
+        let frame = graphics.render(&world, &resources).unwrap();
+
+        let golden_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(GOLDEN_TEST_SCENE);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::create_dir_all(golden_path.parent().unwrap()).unwrap();
+            frame.save(&golden_path).unwrap();
+            panic!(
+                "Wrote the golden image {:?}; check it and run the test again",
+                golden_path
+            );
+        }
+        let golden = image::open(&golden_path)
+            .wrap_err_with(|| format!("Failed to open the golden image {:?}", golden_path))
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(frame.dimensions(), golden.dimensions());
+
+        let differing = frame
+            .pixels()
+            .zip(golden.pixels())
+            .filter(|(a, b)| {
+                a.0.iter()
+                    .zip(b.0.iter())
+                    .any(|(a, b)| a.abs_diff(*b) > GOLDEN_CHANNEL_TOLERANCE)
+            })
+            .count();
+        let fraction = differing as f32 / (width * height) as f32;
+        assert!(
+            fraction <= GOLDEN_PIXEL_TOLERANCE,
+            "{:.1}% of the pixels differ from {:?}",
+            fraction * 100.0,
+            golden_path
+        );
+    }
+}
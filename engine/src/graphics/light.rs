@@ -0,0 +1,272 @@
+use bytemuck::{Pod, Zeroable};
+use legion::{IntoQuery, World};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    color::Rgb,
+    shadow::{self, ShadowBias, MAX_SHADOW_MAPS},
+};
+use crate::spacetime::Position;
+
+/// How many lights can light the scene at once; the rest are ignored
+pub const MAX_LIGHTS: usize = 16;
+
+// Lights shine along the +y axis of their Position (the way cameras look)
+
+/// Light coming from infinitely far away in a single direction, like the sun
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct DirectionalLight {
+    pub color: Rgb,
+    pub intensity: f32,
+    #[serde(default)]
+    pub shadow_bias: ShadowBias,
+}
+
+/// Light shining from a point in every direction, fading out until `range`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PointLight {
+    pub color: Rgb,
+    pub intensity: f32,
+    pub range: f32,
+}
+
+/// Light shining from a point in a cone, fading out until `range`.
+/// Angles (in degrees) are measured from the middle of the cone; the light fades
+/// between the inner and the outer one.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SpotLight {
+    pub color: Rgb,
+    pub intensity: f32,
+    pub range: f32,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    #[serde(default)]
+    pub shadow_bias: ShadowBias,
+}
+
+/// Light reaching every surface equally, so nothing is completely black.
+/// A resource; there's a dim default when it's missing.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AmbientLight(pub Rgb);
+
+impl Default for AmbientLight {
+    fn default() -> Self {
+        AmbientLight(Rgb::new(0.1, 0.1, 0.1))
+    }
+}
+
+const DIRECTIONAL: u32 = 0;
+const POINT: u32 = 1;
+const SPOT: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, PartialEq)]
+pub(crate) struct GpuLight {
+    // Alignment 16, size 12
+    pub position: [f32; 3],
+    // Alignment 4, size 4
+    pub kind: u32,
+    // Alignment 16, size 12
+    pub direction: [f32; 3],
+    // Alignment 4, size 4
+    pub range: f32,
+    // Alignment 16, size 12 (already multiplied by the intensity)
+    pub color: [f32; 3],
+    // Alignment 4, size 4 (cosines of the spot light's angles)
+    pub inner_cos: f32,
+    pub outer_cos: f32,
+    // Alignment 4, size 4 (layer in the shadow maps, -1 without shadows)
+    pub shadow_map: i32,
+    pub depth_bias: f32,
+    pub normal_bias: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub(crate) struct LightUniforms {
+    // Alignment 16, size 12
+    pub ambient: [f32; 3],
+    // Alignment 4, size 4
+    pub count: u32,
+    // Alignment 16, size 64 * MAX_LIGHTS
+    pub lights: [GpuLight; MAX_LIGHTS],
+    // Alignment 16, size 64 * MAX_SHADOW_MAPS (light space of every shadow map)
+    pub shadow_view_proj: [[[f32; 4]; 4]; MAX_SHADOW_MAPS],
+}
+
+impl LightUniforms {
+    /// Collects the lights in the world (at most MAX_LIGHTS) at their interpolated Positions.
+    /// The first MAX_SHADOW_MAPS directional and spot lights cast shadows; directional lights'
+    /// shadows cover the area around `focus` (usually the camera).
+    pub fn gather(
+        world: &World,
+        ambient: AmbientLight,
+        focus: &na::Point3<f32>,
+        lerp: f32,
+    ) -> Self {
+        let light = |kind, position: &Position, color: Rgb, intensity: f32| {
+            let iso = position.current(lerp);
+            let color: [f32; 3] = color.into();
+            GpuLight {
+                position: iso.translation.vector.into(),
+                kind,
+                direction: (iso.rotation * na::Vector3::y()).into(),
+                range: f32::INFINITY,
+                color: na::Vector3::from(color).scale(intensity).into(),
+                inner_cos: -1.0,
+                outer_cos: -1.0,
+                shadow_map: -1,
+                depth_bias: 0.0,
+                normal_bias: 0.0,
+            }
+        };
+
+        let mut directional_query = <(&Position, &DirectionalLight)>::query();
+        let mut point_query = <(&Position, &PointLight)>::query();
+        let mut spot_query = <(&Position, &SpotLight)>::query();
+        let directional = directional_query.iter(world).map(|(pos, l)| GpuLight {
+            depth_bias: l.shadow_bias.depth,
+            normal_bias: l.shadow_bias.normal,
+            ..light(DIRECTIONAL, pos, l.color, l.intensity)
+        });
+        let point = point_query.iter(world).map(|(pos, l)| GpuLight {
+            range: l.range,
+            ..light(POINT, pos, l.color, l.intensity)
+        });
+        let spot = spot_query.iter(world).map(|(pos, l)| GpuLight {
+            range: l.range,
+            inner_cos: l.inner_angle.to_radians().cos(),
+            outer_cos: l.outer_angle.to_radians().cos(),
+            depth_bias: l.shadow_bias.depth,
+            normal_bias: l.shadow_bias.normal,
+            ..light(SPOT, pos, l.color, l.intensity)
+        });
+
+        let mut uniforms = LightUniforms {
+            ambient: ambient.0.into(),
+            count: 0,
+            lights: [GpuLight::zeroed(); MAX_LIGHTS],
+            shadow_view_proj: [na::Matrix4::identity().into(); MAX_SHADOW_MAPS],
+        };
+        for (slot, light) in uniforms
+            .lights
+            .iter_mut()
+            .zip(directional.chain(point).chain(spot))
+        {
+            *slot = light;
+            uniforms.count += 1;
+        }
+
+        // Hand out the shadow maps (directional lights come first)
+        let mut shadow_maps = 0;
+        for light in &mut uniforms.lights[..uniforms.count as usize] {
+            if shadow_maps == MAX_SHADOW_MAPS {
+                break;
+            }
+            let position = na::Point3::from(light.position);
+            let direction = na::Vector3::from(light.direction);
+            let view_proj = match light.kind {
+                DIRECTIONAL => shadow::directional_view_proj(&direction, focus),
+                SPOT => shadow::spot_view_proj(
+                    &position,
+                    &direction,
+                    light.outer_cos.acos().to_degrees(),
+                    light.range,
+                ),
+                _ => continue,
+            };
+            light.shadow_map = shadow_maps as i32;
+            uniforms.shadow_view_proj[shadow_maps] = view_proj.into();
+            shadow_maps += 1;
+        }
+        uniforms
+    }
+
+    /// Light space transforms of the shadow maps in use
+    pub fn shadow_view_projs(&self) -> impl Iterator<Item = [[f32; 4]; 4]> + '_ {
+        self.lights[..self.count as usize]
+            .iter()
+            .filter(|light| light.shadow_map >= 0)
+            .map(move |light| self.shadow_view_proj[light.shadow_map as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_gather_lights() {
+        let mut world = World::default();
+        let white = Rgb::new(1.0, 1.0, 1.0);
+        // Pointing down
+        let down =
+            na::UnitQuaternion::from_axis_angle(&na::Vector3::x_axis(), -90_f32.to_radians());
+        world.push((
+            Position::from(na::Isometry3::from_parts(
+                na::Translation3::identity(),
+                down,
+            )),
+            DirectionalLight {
+                color: white,
+                intensity: 0.5,
+                shadow_bias: ShadowBias::default(),
+            },
+        ));
+        world.push((
+            Position::from(na::Isometry3::translation(1.0, 2.0, 3.0)),
+            SpotLight {
+                color: white,
+                intensity: 1.0,
+                range: 10.0,
+                inner_angle: 0.0,
+                outer_angle: 90.0,
+                shadow_bias: ShadowBias::default(),
+            },
+        ));
+        // Lights without a Position aren't anywhere
+        world.push((PointLight {
+            color: white,
+            intensity: 1.0,
+            range: 1.0,
+        },));
+
+        let uniforms =
+            LightUniforms::gather(&world, AmbientLight::default(), &na::Point3::origin(), 1.0);
+        assert_eq!(uniforms.count, 2);
+        let sun = uniforms.lights[0];
+        assert_eq!(sun.kind, DIRECTIONAL);
+        assert_eq!(sun.color, [0.5, 0.5, 0.5]);
+        assert_relative_eq!(
+            na::Vector3::from(sun.direction),
+            -na::Vector3::z(),
+            epsilon = 1e-6
+        );
+        let spot = uniforms.lights[1];
+        assert_eq!(spot.kind, SPOT);
+        assert_eq!(spot.position, [1.0, 2.0, 3.0]);
+        assert_relative_eq!(spot.inner_cos, 1.0);
+        assert_relative_eq!(spot.outer_cos, 0.0, epsilon = 1e-6);
+        // Both of them cast shadows
+        assert_eq!((sun.shadow_map, spot.shadow_map), (0, 1));
+        assert_eq!(uniforms.shadow_view_projs().count(), 2);
+
+        // Only MAX_LIGHTS fit
+        for _ in 0..MAX_LIGHTS {
+            world.push((
+                Position::from(na::Isometry3::identity()),
+                PointLight {
+                    color: white,
+                    intensity: 1.0,
+                    range: 1.0,
+                },
+            ));
+        }
+        let uniforms =
+            LightUniforms::gather(&world, AmbientLight::default(), &na::Point3::origin(), 1.0);
+        assert_eq!(uniforms.count as usize, MAX_LIGHTS);
+        // The spot light didn't fit (and point lights don't cast shadows)
+        assert_eq!(uniforms.shadow_view_projs().count(), 1);
+    }
+}
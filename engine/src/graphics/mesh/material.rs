@@ -2,10 +2,13 @@ use bytemuck::{Pod, Zeroable};
 use eyre::{eyre::eyre, Result};
 use wgpu::util::DeviceExt;
 
-#[derive(Debug)]
+/// Which pipeline draws a material; draws are sorted in this order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum MaterialShading {
     Untextured,
     Textured,
+    /// Textured, with normals perturbed by a normal map
+    NormalMapped,
     UntexturedUnlit,
     TexturedUnlit,
     TexturedEmissive,
@@ -15,14 +18,36 @@ pub enum MaterialShading {
 use MaterialShading::*;
 
 use super::render_mesh::RenderMeshLayouts;
+use crate::{
+    assets::{
+        data::{AlphaMode, Illumination, MaterialData},
+        Handle,
+    },
+    graphics::Texture,
+};
 
 impl MaterialShading {
+    /// The shading which draws `material`. Emissive materials glow whether they're lit or not;
+    /// "textured" shadings are used by materials with any map.
+    pub fn of(material: &MaterialData) -> Self {
+        let is_emissive = material.color_emissive != [0.0, 0.0, 0.0].into();
+        match (material.illumination, material.has_maps(), is_emissive) {
+            (_, false, true) => UntexturedEmissive,
+            (_, true, true) => TexturedEmissive,
+            (Illumination::Unlit, false, false) => UntexturedUnlit,
+            (Illumination::Unlit, true, false) => TexturedUnlit,
+            (_, true, false) if material.normal_map.is_some() => NormalMapped,
+            (_, true, false) => Textured,
+            (_, false, false) => Untextured,
+        }
+    }
+
     pub fn _is_lit(&self) -> bool {
         matches!(self, UntexturedUnlit | TexturedUnlit)
     }
     pub fn is_textured(&self) -> bool {
         match self {
-            Textured | TexturedUnlit | TexturedEmissive => true,
+            Textured | NormalMapped | TexturedUnlit | TexturedEmissive => true,
             Untextured | UntexturedUnlit | UntexturedEmissive => false,
         }
     }
@@ -38,25 +63,68 @@ pub struct MaterialFactors {
     pub diffuse: [f32; 4],
     // Alignment 16, size 12
     pub emissive: [f32; 3],
-    // Pad to 32
-    pub _padding: [f32; 1],
+    // Alignment 4, size 4 (the Blinn-Phong exponent)
+    pub specular_coefficient: f32,
+    // Alignment 16, size 12
+    pub specular: [f32; 3],
+    // Alignment 4, size 4 (fragments with a lower alpha are discarded)
+    pub alpha_cutoff: f32,
+    // Alignment 16, size 12 (multiplies the ambient light, along with the diffuse color)
+    pub ambient: [f32; 3],
+    // Pad to 64
+    pub _padding2: [f32; 1],
+}
+
+/// The maps of a textured material; the ones it doesn't have get bound as
+/// `RenderMeshLayouts::default_map`
+#[derive(Default)]
+pub struct MaterialMaps {
+    pub ambient: Option<Handle<Texture>>,
+    pub diffuse: Option<Handle<Texture>>,
+    pub specular: Option<Handle<Texture>>,
+    pub emissive: Option<Handle<Texture>>,
+    pub normal: Option<Handle<Texture>>,
+    pub dissolve: Option<Handle<Texture>>,
+}
+
+impl MaterialMaps {
+    /// The maps in the order of their bindings (starting at 2)
+    fn iter(&self) -> [&Option<Handle<Texture>>; 6] {
+        [
+            &self.diffuse,
+            &self.ambient,
+            &self.specular,
+            &self.emissive,
+            &self.normal,
+            &self.dissolve,
+        ]
+    }
 }
 
 pub struct MeshMaterial {
     pub shading: MaterialShading,
+    pub alpha_mode: AlphaMode,
     pub factors: MaterialFactors,
 
     // Even if we only set it once when initializing the material,
     // we have to store it so it doesn't get dropped
     pub _factors_buf: wgpu::Buffer,
+    /// Shared with every other material using the same images
+    pub maps: MaterialMaps,
     pub bind_group: wgpu::BindGroup,
 }
 
 impl MeshMaterial {
+    /// Transparent materials are blended, after the opaque ones are drawn
+    pub fn is_transparent(&self) -> bool {
+        self.alpha_mode == AlphaMode::Blend
+    }
+
     pub fn new(
         shading: MaterialShading,
+        alpha_mode: AlphaMode,
         factors: MaterialFactors,
-        texture: Option<wgpu::Texture>,
+        maps: MaterialMaps,
         device: &wgpu::Device,
         layouts: &RenderMeshLayouts,
     ) -> Result<Self> {
@@ -69,7 +137,7 @@ impl MeshMaterial {
         let bind_group = if !shading.is_textured() {
             device.create_bind_group(&wgpu::BindGroupDescriptor {
                 label: None,
-                layout: &layouts.material.untextured.part_bind_group_layout,
+                layout: &layouts.untextured_part,
                 entries: &[wgpu::BindGroupEntry {
                     binding: 0,
                     resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
@@ -81,42 +149,79 @@ impl MeshMaterial {
                 }],
             })
         } else {
-            let texture_view = texture
-                .ok_or_else(|| eyre!("Cannot create a textured material without a texture"))?
-                .create_view(&wgpu::TextureViewDescriptor::default());
-
-            let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+            if maps.iter().iter().all(|map| map.is_none()) {
+                return Err(eyre!("Cannot create a textured material without a texture"));
+            }
 
+            let mut entries = vec![
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &factors_buf,
+                        offset: 0,
+                        // FIXME
+                        size: None,
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&layouts.sampler),
+                },
+            ];
+            for (binding, map) in (2..).zip(maps.iter()) {
+                let texture = map.as_deref().unwrap_or(&*layouts.default_map);
+                entries.push(wgpu::BindGroupEntry {
+                    binding,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                });
+            }
             device.create_bind_group(&wgpu::BindGroupDescriptor {
                 label: None,
-                layout: &layouts.material.textured.part_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                            buffer: &factors_buf,
-                            offset: 0,
-                            // FIXME
-                            size: None,
-                        }),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&sampler),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: wgpu::BindingResource::TextureView(&texture_view),
-                    },
-                ],
+                layout: &layouts.textured_part,
+                entries: &entries,
             })
         };
 
         Ok(MeshMaterial {
             factors,
             shading,
+            alpha_mode,
             _factors_buf: factors_buf,
+            maps,
             bind_group,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::{data::TextureData, AssetCache};
+
+    #[test]
+    fn test_shading_of_materials() {
+        let images: AssetCache<image::RgbaImage> = AssetCache::default();
+        let map = || TextureData {
+            path: "map.png".into(),
+            image: images
+                .get_or_load("map.png".into(), || Ok(image::RgbaImage::new(1, 1)))
+                .unwrap(),
+        };
+
+        let mut material = MaterialData::default();
+        assert_eq!(MaterialShading::of(&material), Untextured);
+        material.illumination = Illumination::Unlit;
+        assert_eq!(MaterialShading::of(&material), UntexturedUnlit);
+        // Any map makes a material textured
+        material.specular_map = Some(map());
+        assert_eq!(MaterialShading::of(&material), TexturedUnlit);
+        material.illumination = Illumination::Diffuse;
+        assert_eq!(MaterialShading::of(&material), Textured);
+        material.normal_map = Some(map());
+        assert_eq!(MaterialShading::of(&material), NormalMapped);
+        // Unlit emissive materials glow too
+        material.illumination = Illumination::Unlit;
+        material.color_emissive = [1.0, 0.5, 0.0].into();
+        assert_eq!(MaterialShading::of(&material), TexturedEmissive);
+    }
+}
@@ -1,9 +1,10 @@
 mod material;
 mod pipeline;
+pub(super) use pipeline::{capture_errors, textured_part_bind_group_layout};
 mod render_mesh;
-pub use render_mesh::{RenderMesh, RenderMeshLayouts, RenderMeshPart};
+pub use render_mesh::{RenderMesh, RenderMeshLayouts, RenderMeshPart, RenderModel};
 mod pass;
-pub use pass::MeshPass;
+pub use pass::{MeshPass, MeshPassStats};
 
 // Alignment table: https://gpuweb.github.io/gpuweb/wgsl/#alignment-and-size
 
@@ -37,5 +38,10 @@ impl Vertex {
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)]
 pub(super) struct MeshUniforms {
+    // Alignment 16, size 64
     pub(crate) model: [[f32; 4]; 4],
+    // Alignment 4, size 4 (whether shadows fall onto the mesh, see `Shadows`)
+    pub(crate) receive_shadows: u32,
+    // Pad to 80
+    pub _padding: [u32; 3],
 }
@@ -1,60 +1,181 @@
+use std::cmp::Ordering;
+
 use eyre::{eyre::anyhow, Result};
 use legion::{IntoQuery, Resources, World};
 use spacetime::PhysicsTimer;
 use wgpu::util::DeviceExt;
 
-use crate::graphics::{Camera, GraphicsShared, MainCamera, Pass};
+use crate::graphics::{
+    light::LightUniforms, AmbientLight, Camera, GraphicsShared, MainCamera, Pass, ShadowPass,
+    Shadows,
+};
 use crate::{assets::AssetLoader, spacetime};
 
-use super::render_mesh::RenderMesh;
 use super::{material::MaterialShading, pipeline::MeshPipeline};
+use super::{
+    render_mesh::{RenderMesh, RenderMeshPart},
+    MeshUniforms,
+};
 use crate::graphics::GlobalUniforms;
 
 pub struct MeshPassPipelines {
     pub untextured: MeshPipeline,
     pub untextured_unlit: MeshPipeline,
     pub textured: MeshPipeline,
+    pub normal_mapped: MeshPipeline,
     pub textured_unlit: MeshPipeline,
     pub textured_emissive: MeshPipeline,
     pub untextured_emissive: MeshPipeline,
 }
 
+impl MeshPassPipelines {
+    fn iter_mut(&mut self) -> [(MaterialShading, &mut MeshPipeline); 7] {
+        [
+            (MaterialShading::Untextured, &mut self.untextured),
+            (MaterialShading::UntexturedUnlit, &mut self.untextured_unlit),
+            (MaterialShading::Textured, &mut self.textured),
+            (MaterialShading::NormalMapped, &mut self.normal_mapped),
+            (MaterialShading::TexturedUnlit, &mut self.textured_unlit),
+            (
+                MaterialShading::TexturedEmissive,
+                &mut self.textured_emissive,
+            ),
+            (
+                MaterialShading::UntexturedEmissive,
+                &mut self.untextured_emissive,
+            ),
+        ]
+    }
+
+    fn get(&self, shading: MaterialShading) -> &MeshPipeline {
+        match shading {
+            MaterialShading::Untextured => &self.untextured,
+            MaterialShading::UntexturedUnlit => &self.untextured_unlit,
+            MaterialShading::Textured => &self.textured,
+            MaterialShading::NormalMapped => &self.normal_mapped,
+            MaterialShading::TexturedUnlit => &self.textured_unlit,
+            MaterialShading::TexturedEmissive => &self.textured_emissive,
+            MaterialShading::UntexturedEmissive => &self.untextured_emissive,
+        }
+    }
+}
+
+/// What a draw binds. Opaque draws get sorted by it, so the state which is the most expensive
+/// to change (the pipeline) changes the least often.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DrawKey {
+    /// Whether the blended pipeline is used; transparent parts are drawn after every opaque one
+    transparent: bool,
+    pipeline: MaterialShading,
+    /// Address of the material, shared by every RenderMesh drawing the same model
+    material: usize,
+    /// Address of the part, which holds the vertex and index buffers
+    mesh: usize,
+}
+
+impl DrawKey {
+    fn new(part: &RenderMeshPart) -> Self {
+        DrawKey {
+            transparent: part.material.is_transparent(),
+            pipeline: part.material.shading,
+            material: &part.material as *const _ as usize,
+            mesh: part as *const _ as usize,
+        }
+    }
+}
+
+/// How much work the last frame's MeshPass::render did
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MeshPassStats {
+    pub draw_calls: u32,
+    pub pipeline_switches: u32,
+    pub material_switches: u32,
+    /// Vertex and index buffer switches
+    pub mesh_switches: u32,
+}
+
+/// Which of the bound state a draw has to change
+struct StateChanges {
+    pipeline: bool,
+    material: bool,
+    mesh: bool,
+}
+
+impl MeshPassStats {
+    /// Counts a draw made right after `previous`
+    fn record(&mut self, previous: Option<&DrawKey>, key: &DrawKey) -> StateChanges {
+        let changes = StateChanges {
+            pipeline: previous
+                .is_none_or(|p| (p.transparent, p.pipeline) != (key.transparent, key.pipeline)),
+            material: previous.is_none_or(|p| p.material != key.material),
+            mesh: previous.is_none_or(|p| p.mesh != key.mesh),
+        };
+        self.draw_calls += 1;
+        self.pipeline_switches += changes.pipeline as u32;
+        self.material_switches += changes.material as u32;
+        self.mesh_switches += changes.mesh as u32;
+        changes
+    }
+}
+
+/// Opaque draws come first, sorted by their key. Transparent ones come after them, farthest from
+/// the camera first, so each blends over everything behind it.
+fn draw_order((a, a_distance): (&DrawKey, f32), (b, b_distance): (&DrawKey, f32)) -> Ordering {
+    match (a.transparent, b.transparent) {
+        (false, false) => a.cmp(b),
+        (true, true) => b_distance
+            .partial_cmp(&a_distance)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.cmp(b)),
+        (a_transparent, b_transparent) => a_transparent.cmp(&b_transparent),
+    }
+}
+
 pub struct MeshPass {
     pub global_bind_group_layout: wgpu::BindGroupLayout,
     pub global_bind_group: wgpu::BindGroup,
 
     pub global_uniform_buf: wgpu::Buffer,
+    /// Every light in the world, gathered each frame
+    pub light_uniform_buf: wgpu::Buffer,
     pub mesh_bind_group_layout: std::rc::Rc<wgpu::BindGroupLayout>,
 
-    pub pipelines: std::rc::Rc<MeshPassPipelines>,
+    pub pipelines: MeshPassPipelines,
+    pub stats: MeshPassStats,
 }
 
 impl MeshPass {
+    /// Set 1 of the mesh pipelines (and of the ShadowPass's), bound once for every RenderMesh
+    pub fn create_mesh_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                // Mesh matrix (na::Matrix4 / mat4) and whether it receives shadows
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(
+                            std::mem::size_of::<MeshUniforms>() as wgpu::BufferAddress,
+                        ),
+                    },
+                    count: None,
+                },
+            ],
+        })
+    }
+
+    /// Lit meshes sample the shadow maps rendered by `shadow_pass`
     pub fn new(
         device: &wgpu::Device,
         surface_config: &wgpu::SurfaceConfiguration,
+        mesh_bind_group_layout: std::rc::Rc<wgpu::BindGroupLayout>,
+        shadow_pass: &ShadowPass,
         _world: &mut World,
         resources: &mut Resources,
     ) -> Result<MeshPass> {
-        // Set 1
-        let mesh_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: None,
-                entries: &[
-                    // Mesh matrix (na::Matrix4 / mat4)
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::VERTEX,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                ],
-            });
-
         // Set 0
         let global_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -74,6 +195,40 @@ impl MeshPass {
                         },
                         count: None,
                     },
+                    // Lights
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                std::mem::size_of::<LightUniforms>() as wgpu::BufferAddress,
+                            ),
+                        },
+                        count: None,
+                    },
+                    // Shadow maps
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Depth,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    // Shadow map sampler
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: true,
+                            filtering: true,
+                        },
+                        count: None,
+                    },
                 ],
             });
 
@@ -90,18 +245,39 @@ impl MeshPass {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        let light_uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Light uniform buffer"),
+            size: std::mem::size_of::<LightUniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
         let global_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout: &global_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                    buffer: &global_uniform_buf,
-                    offset: 0,
-                    // FIXME
-                    size: None,
-                }),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &global_uniform_buf,
+                        offset: 0,
+                        // FIXME
+                        size: None,
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: light_uniform_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&shadow_pass.map_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&shadow_pass.sampler),
+                },
+            ],
         });
 
         // For loading shaders
@@ -134,6 +310,14 @@ impl MeshPass {
                     &mesh_bind_group_layout,
                     &asset_loader,
                 ),
+                normal_mapped: MeshPipeline::shaded(
+                    MaterialShading::NormalMapped,
+                    device,
+                    surface_config,
+                    &global_bind_group_layout,
+                    &mesh_bind_group_layout,
+                    &asset_loader,
+                ),
                 textured_unlit: MeshPipeline::shaded(
                     MaterialShading::TexturedUnlit,
                     device,
@@ -165,12 +349,41 @@ impl MeshPass {
             global_bind_group,
             global_bind_group_layout,
             global_uniform_buf,
-            pipelines: std::rc::Rc::new(pipelines),
-            mesh_bind_group_layout: std::rc::Rc::new(mesh_bind_group_layout),
+            light_uniform_buf,
+            pipelines,
+            mesh_bind_group_layout,
+            stats: MeshPassStats::default(),
         };
 
         Ok(mesh_pass)
     }
+
+    /// Recompiles every pipeline with the current shader files (see `MeshPipeline::reload`).
+    /// Returns the first error; pipelines which failed to reload keep their old shaders.
+    pub fn reload_shaders(
+        &mut self,
+        device: &wgpu::Device,
+        surface_config: &wgpu::SurfaceConfiguration,
+        asset_loader: &AssetLoader,
+    ) -> Result<()> {
+        let mut result = Ok(());
+        for (ty, pipeline) in self.pipelines.iter_mut() {
+            let reloaded = pipeline.reload(
+                &ty,
+                device,
+                surface_config,
+                &self.global_bind_group_layout,
+                &self.mesh_bind_group_layout,
+                asset_loader,
+            );
+            if let Err(error) = reloaded {
+                if result.is_ok() {
+                    result = Err(error.wrap_err(format!("Failed to reload the {:?} pipeline", ty)));
+                }
+            }
+        }
+        result
+    }
 }
 
 impl Pass for MeshPass {
@@ -205,7 +418,7 @@ impl Pass for MeshPass {
             .unwrap_or(1.0);
 
         // Upload global uniforms
-        if let Some(main_cam) = resources.get::<MainCamera>() {
+        let cam_pos = if let Some(main_cam) = resources.get::<MainCamera>() {
             let cam_pos = main_cam.position.current(lerp);
             let view_proj = main_cam.camera.projection()
                 * main_cam.camera.view(
@@ -223,28 +436,65 @@ impl Pass for MeshPass {
                 0,
                 bytemuck::bytes_of(&global_uniforms),
             );
+            let ambient = resources
+                .get::<AmbientLight>()
+                .map_or_else(AmbientLight::default, |ambient| *ambient);
+            graphics.queue.write_buffer(
+                &self.light_uniform_buf,
+                0,
+                bytemuck::bytes_of(&LightUniforms::gather(
+                    world,
+                    ambient,
+                    &cam_pos.translation.vector.into(),
+                    lerp,
+                )),
+            );
+            cam_pos.translation.vector
         } else {
             // No camera present; can't render
             return;
-        }
+        };
 
         // Select every entity with a RenderMesh, position and maybe a scale
         // TODO: update buffers only if the position or scale have been changed (maybe_changed filter)
-        let mut mesh_query =
-            <(&RenderMesh, &spacetime::Position, Option<&spacetime::Scale>)>::query();
+        let mut mesh_query = <(
+            &RenderMesh,
+            &spacetime::Position,
+            Option<&spacetime::Scale>,
+            Option<&Shadows>,
+        )>::query();
 
         // Upload mesh model transform matrices to every model's buffer
-        for (rmesh, position, maybe_scale) in mesh_query.iter(world) {
+        for (rmesh, position, maybe_scale, maybe_shadows) in mesh_query.iter(world) {
             let mut transform = position.current(lerp).to_homogeneous();
             if let Some(scale) = maybe_scale {
                 transform = transform.prepend_nonuniform_scaling(scale);
             }
-            let transform: [[f32; 4]; 4] = transform.into();
+            let uniforms = MeshUniforms {
+                model: transform.into(),
+                receive_shadows: maybe_shadows.is_none_or(|shadows| shadows.receive) as u32,
+                _padding: [0; 3],
+            };
             graphics
                 .queue
-                .write_buffer(&rmesh.uniform_buf, 0, bytemuck::bytes_of(&transform));
+                .write_buffer(&rmesh.uniform_buf, 0, bytemuck::bytes_of(&uniforms));
         }
 
+        // Every part of every RenderMesh with its squared distance from the camera, sorted to
+        // change as little state as possible and to blend transparent parts in the right order
+        let mut draws: Vec<(DrawKey, f32, &RenderMesh, &RenderMeshPart)> = mesh_query
+            .iter(world)
+            .flat_map(|(mesh, position, _, _)| {
+                let distance = (position.current(lerp).translation.vector - cam_pos).norm_squared();
+                mesh.parts()
+                    .iter()
+                    .map(move |part| (DrawKey::new(part), distance, mesh, part))
+            })
+            .collect();
+        draws.sort_unstable_by(|(a, a_distance, _, _), (b, b_distance, _, _)| {
+            draw_order((a, *a_distance), (b, *b_distance))
+        });
+
         // Begin rendering
 
         // Render every mesh
@@ -273,35 +523,108 @@ impl Pass for MeshPass {
                 }),
             });
 
-            // Select every RenderMesh in the world
             render_pass.set_bind_group(0, &self.global_bind_group, &[]);
-            for (mesh, _, _) in mesh_query.iter(world) {
-                render_pass.set_bind_group(1, &mesh.bind_group, &[]);
-                for part in &mesh.parts {
-                    // Set the correct pipeline before rendering
-                    render_pass.set_pipeline(match part.material.shading {
-                        MaterialShading::Untextured => &self.pipelines.untextured.pipeline,
-                        MaterialShading::UntexturedUnlit => {
-                            &self.pipelines.untextured_unlit.pipeline
-                        }
-                        MaterialShading::Textured => &self.pipelines.textured.pipeline,
-                        MaterialShading::TexturedUnlit => &self.pipelines.textured_unlit.pipeline,
-                        MaterialShading::TexturedEmissive => {
-                            &self.pipelines.textured_emissive.pipeline
-                        }
-                        MaterialShading::UntexturedEmissive => {
-                            &self.pipelines.untextured_emissive.pipeline
-                        }
+            let mut stats = MeshPassStats::default();
+            let mut previous = None;
+            for (key, _, mesh, part) in &draws {
+                let changes = stats.record(previous, key);
+                if changes.pipeline {
+                    let pipeline = self.pipelines.get(key.pipeline);
+                    render_pass.set_pipeline(if key.transparent {
+                        &pipeline.blended
+                    } else {
+                        &pipeline.pipeline
                     });
-
+                }
+                if changes.material {
                     render_pass.set_bind_group(2, &part.material.bind_group, &[]);
+                }
+                if changes.mesh {
                     render_pass
                         .set_index_buffer(part.index_buf.slice(..), wgpu::IndexFormat::Uint32);
                     render_pass.set_vertex_buffer(0, part.vertex_buf.slice(..));
-                    render_pass.draw_indexed(0..part.index_count as u32, 0, 0..1);
                 }
+                render_pass.set_bind_group(1, &mesh.bind_group, &[]);
+                render_pass.draw_indexed(0..part.index_count, 0, 0..1);
+                previous = Some(key);
             }
+            self.stats = stats;
         }
         encoder.pop_debug_group();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_draws_switch_state_once() {
+        let key = |pipeline, material, mesh| DrawKey {
+            transparent: false,
+            pipeline,
+            material,
+            mesh,
+        };
+        // Two entities drawing the same two-part model, and a textured one
+        let mut draws = vec![
+            key(MaterialShading::Textured, 3, 30),
+            key(MaterialShading::Untextured, 1, 10),
+            key(MaterialShading::Untextured, 2, 20),
+            key(MaterialShading::Textured, 3, 30),
+            key(MaterialShading::Untextured, 1, 10),
+            key(MaterialShading::Untextured, 2, 20),
+        ];
+        draws.sort_unstable();
+
+        let mut stats = MeshPassStats::default();
+        let mut previous = None;
+        for draw in &draws {
+            stats.record(previous, draw);
+            previous = Some(draw);
+        }
+        assert_eq!(
+            stats,
+            MeshPassStats {
+                draw_calls: 6,
+                pipeline_switches: 2,
+                material_switches: 3,
+                mesh_switches: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_transparent_draws_come_last_back_to_front() {
+        let key = |transparent, material| DrawKey {
+            transparent,
+            pipeline: MaterialShading::Textured,
+            material,
+            mesh: material,
+        };
+        let near_glass = key(true, 1);
+        let far_glass = key(true, 2);
+        let wall = key(false, 3);
+        let mut draws = [
+            (near_glass, 4.0),
+            (wall, 100.0),
+            (far_glass, 25.0),
+            (wall, 1.0),
+        ];
+        draws.sort_unstable_by(|(a, a_distance), (b, b_distance)| {
+            draw_order((a, *a_distance), (b, *b_distance))
+        });
+
+        let keys: Vec<_> = draws.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, [wall, wall, far_glass, near_glass]);
+
+        // Going from opaque to blended switches the pipeline even with the same shading
+        let mut stats = MeshPassStats::default();
+        let mut previous = None;
+        for draw in &keys {
+            stats.record(previous, draw);
+            previous = Some(draw);
+        }
+        assert_eq!(stats.pipeline_switches, 2);
+    }
+}
@@ -1,3 +1,10 @@
+use std::{
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+use eyre::{eyre::eyre, Result};
+
 use crate::{
     assets::AssetLoader,
     graphics::{WGSL_SHADERS_DIR, WGSL_SHADERS_EXT},
@@ -14,10 +21,27 @@ const MESH_VERTEX_SHADER_NAME: &str = "mesh";
 const UNTEXTURED_SHADER_NAME: &str = "untex";
 const UNTEXTURED_UNLIT_SHADER_NAME: &str = "untex_unlit";
 const TEXTURED_SHADER_NAME: &str = "tex";
+const NORMAL_MAPPED_SHADER_NAME: &str = "tex_normal";
 const TEXTURED_UNLIT_SHADER_NAME: &str = "tex_unlit";
 const UNTEXTURED_EMISSIVE_SHADER_NAME: &str = "emissive_untex";
 const TEXTURED_EMISSIVE_SHADER_NAME: &str = "emissive";
 
+/// A map of a textured material
+macro_rules! map_layout_entry {
+    ($binding:expr) => {
+        wgpu::BindGroupLayoutEntry {
+            binding: $binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        }
+    };
+}
+
 macro_rules! bind_group_layout_entries {
     (untextured) => {
         &[
@@ -61,50 +85,66 @@ macro_rules! bind_group_layout_entries {
                 },
                 count: None,
             },
-            // Diffuse texture
-            wgpu::BindGroupLayoutEntry {
-                binding: 2,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Texture {
-                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                    view_dimension: wgpu::TextureViewDimension::D2,
-                    multisampled: false,
-                },
-                count: None,
-            },
+            // Diffuse map
+            map_layout_entry!(2),
+            // Ambient map
+            map_layout_entry!(3),
+            // Specular map
+            map_layout_entry!(4),
+            // Emissive map
+            map_layout_entry!(5),
+            // Normal map
+            map_layout_entry!(6),
+            // Dissolve map
+            map_layout_entry!(7),
         ]
     };
 }
 
+/// Layout of the bind group of textured materials (see `MeshMaterial`). Layouts with the
+/// same entries are interchangeable, so every textured pipeline (and the shadow pass) can
+/// bind the same materials.
+pub(crate) fn textured_part_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: None,
+        entries: bind_group_layout_entries!(textured),
+    })
+}
+
 pub struct MeshPipeline {
-    pub part_bind_group_layout: wgpu::BindGroupLayout,
+    pub part_bind_group_layout: Rc<wgpu::BindGroupLayout>,
     pub pipeline: wgpu::RenderPipeline,
+    /// The same shaders blending over what's already drawn, without writing depth
+    /// (for transparent materials)
+    pub blended: wgpu::RenderPipeline,
 }
 
 impl MeshPipeline {
-    fn new(
+    #[allow(clippy::too_many_arguments)]
+    fn create_pipeline(
         device: &wgpu::Device,
         surface_config: &wgpu::SurfaceConfiguration,
         global_bind_group_layout: &wgpu::BindGroupLayout,
         mesh_bind_group_layout: &wgpu::BindGroupLayout,
-        part_bind_group_layout: wgpu::BindGroupLayout,
-        vs_module: wgpu::ShaderModule,
-        fs_module: wgpu::ShaderModule,
-    ) -> Self {
+        part_bind_group_layout: &wgpu::BindGroupLayout,
+        vs_module: &wgpu::ShaderModule,
+        fs_module: &wgpu::ShaderModule,
+        blended: bool,
+    ) -> wgpu::RenderPipeline {
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
             bind_group_layouts: &[
                 global_bind_group_layout,
                 mesh_bind_group_layout,
-                &part_bind_group_layout,
+                part_bind_group_layout,
             ],
             push_constant_ranges: &[],
         });
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: None,
             layout: Some(&pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &vs_module,
+                module: vs_module,
                 entry_point: "main",
                 buffers: &[wgpu::VertexBufferLayout {
                     array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
@@ -113,9 +153,13 @@ impl MeshPipeline {
                 }],
             },
             fragment: Some(wgpu::FragmentState {
-                module: &fs_module,
+                module: fs_module,
                 entry_point: "main",
-                targets: &[surface_config.format.into()],
+                targets: &[wgpu::ColorTargetState {
+                    format: surface_config.format,
+                    blend: blended.then_some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
             }),
             primitive: wgpu::PrimitiveState {
                 cull_mode: Some(wgpu::Face::Back),
@@ -123,7 +167,8 @@ impl MeshPipeline {
             },
             depth_stencil: Some(wgpu::DepthStencilState {
                 format: wgpu::TextureFormat::Depth32Float,
-                depth_write_enabled: true,
+                // Transparent meshes don't hide what's drawn behind them after them
+                depth_write_enabled: !blended,
                 depth_compare: wgpu::CompareFunction::Less,
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
@@ -131,21 +176,15 @@ impl MeshPipeline {
             }),
             // TODO: Multisample antialiasing
             multisample: wgpu::MultisampleState::default(),
-        });
-        MeshPipeline {
-            part_bind_group_layout,
-            pipeline,
-        }
+        })
     }
 
-    pub fn shaded(
-        ty: MaterialShading,
+    /// Loads and compiles the vertex and fragment shaders used for `ty`
+    fn load_shaders(
+        ty: &MaterialShading,
         device: &wgpu::Device,
-        surface_config: &wgpu::SurfaceConfiguration,
-        global_bind_group_layout: &wgpu::BindGroupLayout,
-        mesh_bind_group_layout: &wgpu::BindGroupLayout,
         asset_loader: &AssetLoader,
-    ) -> Self {
+    ) -> Result<(wgpu::ShaderModule, wgpu::ShaderModule)> {
         let vs_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
             label: None,
             source: wgpu::ShaderSource::Wgsl(
@@ -154,8 +193,7 @@ impl MeshPipeline {
                         WGSL_SHADERS_DIR,
                         MESH_VERTEX_SHADER_NAME,
                         WGSL_SHADERS_EXT
-                    ))
-                    .unwrap()
+                    ))?
                     .into(),
             ),
         });
@@ -176,6 +214,11 @@ impl MeshPipeline {
                         Textured => {
                             concatcp!(WGSL_SHADERS_DIR, TEXTURED_SHADER_NAME, WGSL_SHADERS_EXT,)
                         }
+                        NormalMapped => concatcp!(
+                            WGSL_SHADERS_DIR,
+                            NORMAL_MAPPED_SHADER_NAME,
+                            WGSL_SHADERS_EXT,
+                        ),
                         TexturedUnlit => concatcp!(
                             WGSL_SHADERS_DIR,
                             TEXTURED_UNLIT_SHADER_NAME,
@@ -191,17 +234,25 @@ impl MeshPipeline {
                             UNTEXTURED_EMISSIVE_SHADER_NAME,
                             WGSL_SHADERS_EXT,
                         ),
-                    })
-                    .unwrap()
+                    })?
                     .into(),
             ),
         });
+        Ok((vs_module, fs_module))
+    }
+
+    pub fn shaded(
+        ty: MaterialShading,
+        device: &wgpu::Device,
+        surface_config: &wgpu::SurfaceConfiguration,
+        global_bind_group_layout: &wgpu::BindGroupLayout,
+        mesh_bind_group_layout: &wgpu::BindGroupLayout,
+        asset_loader: &AssetLoader,
+    ) -> Self {
+        let (vs_module, fs_module) = Self::load_shaders(&ty, device, asset_loader).unwrap();
 
         let part_bind_group_layout = if ty.is_textured() {
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: None,
-                entries: bind_group_layout_entries!(textured),
-            })
+            textured_part_bind_group_layout(device)
         } else {
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: None,
@@ -209,14 +260,77 @@ impl MeshPipeline {
             })
         };
 
-        MeshPipeline::new(
-            device,
-            surface_config,
-            global_bind_group_layout,
-            mesh_bind_group_layout,
-            part_bind_group_layout,
-            vs_module,
-            fs_module,
-        )
+        let [pipeline, blended] = [false, true].map(|blended| {
+            Self::create_pipeline(
+                device,
+                surface_config,
+                global_bind_group_layout,
+                mesh_bind_group_layout,
+                &part_bind_group_layout,
+                &vs_module,
+                &fs_module,
+                blended,
+            )
+        });
+        MeshPipeline {
+            part_bind_group_layout: Rc::new(part_bind_group_layout),
+            pipeline,
+            blended,
+        }
+    }
+
+    /// Loads the shaders again and recompiles both pipelines.
+    /// If the new shaders fail to load or validate, the old pipeline is kept.
+    pub fn reload(
+        &mut self,
+        ty: &MaterialShading,
+        device: &wgpu::Device,
+        surface_config: &wgpu::SurfaceConfiguration,
+        global_bind_group_layout: &wgpu::BindGroupLayout,
+        mesh_bind_group_layout: &wgpu::BindGroupLayout,
+        asset_loader: &AssetLoader,
+    ) -> Result<()> {
+        let [pipeline, blended] = capture_errors(device, || {
+            Self::load_shaders(ty, device, asset_loader).map(|(vs_module, fs_module)| {
+                [false, true].map(|blended| {
+                    Self::create_pipeline(
+                        device,
+                        surface_config,
+                        global_bind_group_layout,
+                        mesh_bind_group_layout,
+                        &self.part_bind_group_layout,
+                        &vs_module,
+                        &fs_module,
+                        blended,
+                    )
+                })
+            })
+        })??;
+        self.pipeline = pipeline;
+        self.blended = blended;
+        Ok(())
+    }
+}
+
+/// Runs `f`, returning the first wgpu error it caused instead of letting the device's
+/// error handler panic.
+/// (wgpu 0.11 doesn't have error scopes, so this swaps the uncaptured error handler.)
+pub(crate) fn capture_errors<R>(device: &wgpu::Device, f: impl FnOnce() -> R) -> Result<R> {
+    let captured = Arc::new(Mutex::new(None));
+    let sink = captured.clone();
+    device.on_uncaptured_error(move |error| {
+        let mut sink = sink.lock().unwrap();
+        if sink.is_none() {
+            *sink = Some(error.to_string());
+        }
+    });
+    let result = f();
+    // Errors are fatal everywhere else, like with wgpu's default handler
+    device.on_uncaptured_error(|error| panic!("wgpu error: {}\n", error));
+
+    let error = captured.lock().unwrap().take();
+    match error {
+        Some(error) => Err(eyre!("Invalid shaders: {}", error)),
+        None => Ok(result),
     }
 }
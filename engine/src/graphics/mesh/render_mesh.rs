@@ -1,15 +1,26 @@
 use std::rc::Rc;
 
+use eyre::Result;
 use wgpu::util::DeviceExt;
 
-use crate::{assets::data::*, graphics::Graphics};
+use crate::{
+    assets::{data::*, AssetCache, Handle},
+    graphics::Texture,
+};
 
-use super::{material::*, pass::MeshPassPipelines, MeshUniforms};
+use super::{material::*, MeshUniforms};
 
 #[derive(Clone)]
 pub struct RenderMeshLayouts {
     pub mesh: Rc<wgpu::BindGroupLayout>,
-    pub material: Rc<MeshPassPipelines>,
+    /// Used by materials without a texture
+    pub untextured_part: Rc<wgpu::BindGroupLayout>,
+    /// Used by materials with a texture
+    pub textured_part: Rc<wgpu::BindGroupLayout>,
+    /// Used by every textured material
+    pub sampler: Rc<wgpu::Sampler>,
+    /// A white pixel, bound in place of the maps a textured material doesn't have
+    pub default_map: Rc<Texture>,
 }
 
 pub struct RenderMeshPart {
@@ -20,29 +31,45 @@ pub struct RenderMeshPart {
 }
 
 impl RenderMeshPart {
+    /// Uploads the part's buffers and material; textures are shared through `textures`
     pub fn new(
-        data: MeshPartData,
+        data: &MeshPartData,
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         layouts: &RenderMeshLayouts,
-    ) -> Self {
-        let is_emissive = data.material.color_emissive != [0.0, 0.0, 0.0].into();
-        let is_lit = data.material.lighting;
-        let is_textured = data.material.diffuse_map.is_some();
-
-        let shading = match (is_lit, is_textured, is_emissive) {
-            (false, false, false) => MaterialShading::UntexturedUnlit,
-            (false, true, false) => MaterialShading::TexturedUnlit,
-            (true, false, false) => MaterialShading::Untextured,
-            (true, true, false) => MaterialShading::Textured,
-            (true, false, true) => MaterialShading::UntexturedEmissive,
-            (true, true, true) => MaterialShading::TexturedEmissive,
-            (false, true, true) => panic!("Unsupported shading: unlit textured emissive"),
-            (false, false, true) => panic!("Unsupported shading: unlit untextured emissive"),
+        textures: &AssetCache<Texture>,
+    ) -> Result<Self> {
+        let mut upload = |map: &Option<TextureData>| {
+            map.as_ref()
+                .map(|map| {
+                    textures.get_or_load(map.path.clone(), || {
+                        Ok(Texture::new(device, encoder, false, &map.image))
+                    })
+                })
+                .transpose()
+        };
+        let maps = MaterialMaps {
+            ambient: upload(&data.material.ambient_map)?,
+            diffuse: upload(&data.material.diffuse_map)?,
+            specular: upload(&data.material.specular_map)?,
+            emissive: upload(&data.material.emissive_map)?,
+            normal: upload(&data.material.normal_map)?,
+            dissolve: upload(&data.material.dissolve_map)?,
+        };
+
+        let shading = MaterialShading::of(&data.material);
+        // Without specular lighting, highlights are turned off
+        let (specular, specular_coefficient) = match data.material.illumination {
+            Illumination::Specular => (
+                data.material.color_specular.into(),
+                data.material.specular_coefficient,
+            ),
+            Illumination::Diffuse | Illumination::Unlit => ([0.0; 3], 1.0),
         };
 
         let material = MeshMaterial::new(
             shading,
+            data.material.alpha_mode,
             MaterialFactors {
                 diffuse: data
                     .material
@@ -50,15 +77,19 @@ impl RenderMeshPart {
                     .alpha(data.material.alpha)
                     .into(),
                 emissive: data.material.color_emissive.into(),
-                _padding: [0.0],
+                specular_coefficient,
+                specular,
+                alpha_cutoff: match data.material.alpha_mode {
+                    AlphaMode::Mask(cutoff) => cutoff,
+                    AlphaMode::Opaque | AlphaMode::Blend => 0.0,
+                },
+                ambient: data.material.color_ambient.into(),
+                _padding2: [0.0],
             },
-            data.material
-                .diffuse_map
-                .map(|tex| Graphics::upload_texture(device, encoder, false, tex)),
+            maps,
             device,
             layouts,
-        )
-        .unwrap();
+        )?;
 
         let vertex_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
@@ -71,31 +102,65 @@ impl RenderMeshPart {
             usage: wgpu::BufferUsages::INDEX,
         });
 
-        RenderMeshPart {
+        Ok(RenderMeshPart {
             material,
             vertex_buf,
             index_buf,
             index_count: data.indices.len() as u32,
-        }
+        })
+    }
+}
+
+/// The GPU buffers and materials of every object in a model file,
+/// shared by all the RenderMeshes that draw the model
+pub struct RenderModel {
+    pub objects: Vec<Vec<RenderMeshPart>>,
+    /// Where every object is in the model
+    pub nodes: Vec<MeshNode>,
+}
+
+impl RenderModel {
+    pub fn new(
+        meshes: &[MeshData],
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        layouts: &RenderMeshLayouts,
+        textures: &AssetCache<Texture>,
+    ) -> Result<Self> {
+        let objects = meshes
+            .iter()
+            .map(|mesh| {
+                mesh.parts
+                    .iter()
+                    .map(|part| RenderMeshPart::new(part, device, encoder, layouts, textures))
+                    .collect()
+            })
+            .collect::<Result<_>>()?;
+        let nodes = meshes.iter().map(|mesh| mesh.node).collect();
+        Ok(RenderModel { objects, nodes })
     }
 }
 
 pub struct RenderMesh {
-    pub parts: Vec<RenderMeshPart>,
+    pub model: Handle<RenderModel>,
+    /// Index of the drawn object in the model
+    pub object: usize,
 
     pub uniform_buf: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
 impl RenderMesh {
-    pub fn from_parts(
-        parts: Vec<MeshPartData>,
+    pub fn new(
+        model: Handle<RenderModel>,
+        object: usize,
         layouts: &RenderMeshLayouts,
         device: &wgpu::Device,
-        encoder: &mut wgpu::CommandEncoder,
     ) -> RenderMesh {
         let model_uniform = MeshUniforms {
             model: na::Matrix4::identity().into(),
+            receive_shadows: 1,
+            _padding: [0; 3],
         };
 
         let uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -118,15 +183,15 @@ impl RenderMesh {
             }],
         });
 
-        let mut render_parts = Vec::with_capacity(parts.len());
-        for part_data in parts {
-            render_parts.push(RenderMeshPart::new(part_data, device, encoder, layouts))
-        }
-
         RenderMesh {
-            parts: render_parts,
+            model,
+            object,
             bind_group,
             uniform_buf,
         }
     }
+
+    pub fn parts(&self) -> &[RenderMeshPart] {
+        &self.model.objects[self.object]
+    }
 }
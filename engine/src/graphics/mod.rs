@@ -1,8 +1,13 @@
-use std::{num::NonZeroU32, rc::Rc};
+use std::{num::NonZeroU32, path::Path, rc::Rc};
 
 use egui_wgpu_backend::ScreenDescriptor;
-use eyre::{eyre::WrapErr, Result};
+use eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
 use legion::{Resources, World};
+
+use crate::assets::AssetLoader;
 use winit::dpi::PhysicalSize;
 
 use wgpu::util::DeviceExt;
@@ -10,14 +15,28 @@ use wgpu::util::DeviceExt;
 mod setup;
 pub use setup::setup;
 
+mod headless;
+pub use headless::{setup_headless, HeadlessGraphics};
+
 mod camera;
 pub use camera::*;
 
 pub mod color;
 
+mod texture;
+pub use texture::Texture;
+
 mod pass;
 pub use pass::Pass;
 
+mod light;
+pub use light::{AmbientLight, DirectionalLight, PointLight, SpotLight, MAX_LIGHTS};
+
+mod shadow;
+pub use shadow::{
+    ShadowBias, ShadowPass, Shadows, MAX_SHADOW_MAPS, SHADOW_DISTANCE, SHADOW_MAP_SIZE,
+};
+
 pub mod debug;
 pub mod mesh;
 
@@ -45,7 +64,8 @@ pub const WGSL_SHADERS_EXT: &str = ".wgsl";
 pub struct GraphicsShared {
     pub device: Rc<wgpu::Device>,
     pub queue: Rc<wgpu::Queue>,
-    pub window: Rc<winit::window::Window>,
+    /// None for headless graphics (see `setup_headless`)
+    pub window: Option<Rc<winit::window::Window>>,
     pub mesh_layouts: mesh::RenderMeshLayouts,
 }
 
@@ -54,6 +74,7 @@ pub struct Graphics {
     pub queue: Rc<wgpu::Queue>,
     pub window: Rc<winit::window::Window>,
 
+    pub shadow_pass: ShadowPass,
     pub mesh_pass: mesh::MeshPass,
     pub debug_pass: Option<debug::DebugPass>,
 
@@ -69,6 +90,32 @@ pub struct Graphics {
 impl Graphics {
     pub fn prepare(&mut self, _resources: &mut Resources) {}
 
+    /// Reacts to a changed asset file (see `CustomEvent::AssetChanged`):
+    /// changed WGSL shaders get recompiled, keeping the old pipelines if they're invalid
+    pub fn asset_changed(&mut self, path: &Path, resources: &Resources) -> Result<()> {
+        let is_wgsl_shader = path.starts_with(WGSL_SHADERS_DIR)
+            && path
+                .extension()
+                .is_some_and(|ext| ext == &WGSL_SHADERS_EXT[1..]);
+        if !is_wgsl_shader {
+            return Ok(());
+        }
+        let asset_loader = resources
+            .get::<AssetLoader>()
+            .ok_or_else(|| eyre!("Asset loader not found, cannot reload shaders"))?;
+        // A broken shader shouldn't keep the other pass from picking up its changes
+        let mesh = self
+            .mesh_pass
+            .reload_shaders(&self.device, &self.surface_config, &asset_loader);
+        let shadow = self
+            .shadow_pass
+            .reload_shaders(&self.device, &asset_loader)
+            .wrap_err("Failed to reload the shadow pipelines");
+        mesh.and(shadow)?;
+        log::info!("Reloaded shaders after {:?} changed", path);
+        Ok(())
+    }
+
     pub fn resize(
         &mut self,
         size: PhysicalSize<u32>,
@@ -81,26 +128,17 @@ impl Graphics {
         self.surface.configure(&self.device, &self.surface_config);
 
         // Resize the depth texture
-        self.depth_texture = self.device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("depth texture"),
-            size: wgpu::Extent3d {
-                width: self.surface_config.width,
-                height: self.surface_config.height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Depth32Float,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        });
-        self.depth_texture_view = self
-            .depth_texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        let (depth_texture, depth_texture_view) =
+            setup::create_depth_texture(&self.device, &self.surface_config);
+        self.depth_texture = depth_texture;
+        self.depth_texture_view = depth_texture_view;
 
         // Tell all the render passes to resize their internal buffers
         self.mesh_pass
             .resize(&self.shared, &self.surface_config, world, resources)?;
+        if let Some(debug_pass) = &mut self.debug_pass {
+            debug_pass.resize(&self.shared, &self.surface_config, world, resources)?;
+        }
 
         Ok(())
     }
@@ -131,57 +169,17 @@ impl Graphics {
             .create_view(&wgpu::TextureViewDescriptor::default());
         // Render onto the frame with render passes
 
-        {
-            log::debug!("Clearing frame");
-            // Clear the frame
-            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &surface_view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        // Clear the framebuffer with a color
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.01,
-                            g: 0.01,
-                            b: 0.01,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                }],
-                // Clear the depth buffer
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture_view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: false,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
-        }
-
-        log::debug!("Rendering meshes");
-        self.mesh_pass.render(
+        render_world(
             &self.shared,
+            &mut self.shadow_pass,
+            &mut self.mesh_pass,
+            self.debug_pass.as_mut(),
             &mut encoder,
             &mut surface_view,
             &self.depth_texture_view,
             world,
             resources,
         );
-        // DebugPass needs the lerp value which is present only after the MeshPass is activated
-        if let Some(debug_pass) = &mut self.debug_pass {
-            debug_pass.render(
-                &self.shared,
-                &mut encoder,
-                &mut surface_view,
-                &self.depth_texture_view,
-                world,
-                resources,
-            );
-        }
 
         if let Some((triangles, texture)) = ui {
             log::debug!("Rendering ui");
@@ -219,7 +217,7 @@ impl Graphics {
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         srgb: bool,
-        img: image::RgbaImage,
+        img: &image::RgbaImage,
     ) -> wgpu::Texture {
         // The physical size of the texture
         let (img_width, img_height) = (img.width(), img.height());
@@ -242,10 +240,21 @@ impl Graphics {
             },
             usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
         });
+        // Rows of a buffer copied to a texture have to be aligned (a 1x1 default map isn't)
+        let unpadded_bytes_per_row = 4 * img_width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+        let mut contents = vec![0; (bytes_per_row * img_height) as usize];
+        for (padded, row) in contents
+            .chunks_mut(bytes_per_row as usize)
+            .zip(img.as_raw().chunks(unpadded_bytes_per_row as usize))
+        {
+            padded[..row.len()].copy_from_slice(row);
+        }
         // Temporary buffer to copy data from into the texture
         let tmp_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
-            contents: bytemuck::cast_slice(&img.into_raw()),
+            contents: &contents,
             usage: wgpu::BufferUsages::COPY_SRC,
         });
         // Copy img's pixels from the temporary buffer into the texture buffer
@@ -254,7 +263,7 @@ impl Graphics {
                 buffer: &tmp_buf,
                 layout: wgpu::ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: NonZeroU32::new(4 * img_width),
+                    bytes_per_row: NonZeroU32::new(bytes_per_row),
                     rows_per_image: NonZeroU32::new(img_height),
                 },
             },
@@ -270,3 +279,113 @@ impl Graphics {
         texture
     }
 }
+
+/// Clears the target and renders the world onto it with every pass (but not the UI)
+#[allow(clippy::too_many_arguments)]
+fn render_world(
+    shared: &GraphicsShared,
+    shadow_pass: &mut ShadowPass,
+    mesh_pass: &mut mesh::MeshPass,
+    debug_pass: Option<&mut debug::DebugPass>,
+    encoder: &mut wgpu::CommandEncoder,
+    target_view: &mut wgpu::TextureView,
+    depth_texture_view: &wgpu::TextureView,
+    world: &World,
+    resources: &Resources,
+) {
+    {
+        log::debug!("Clearing frame");
+        // Clear the frame
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: target_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    // Clear the framebuffer with a color
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.01,
+                        g: 0.01,
+                        b: 0.01,
+                        a: 1.0,
+                    }),
+                    store: true,
+                },
+            }],
+            // Clear the depth buffer
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_texture_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    // The passes after this one load it
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+    }
+
+    log::debug!("Rendering shadow maps");
+    shadow_pass.render(
+        shared,
+        encoder,
+        target_view,
+        depth_texture_view,
+        world,
+        resources,
+    );
+
+    log::debug!("Rendering meshes");
+    mesh_pass.render(
+        shared,
+        encoder,
+        target_view,
+        depth_texture_view,
+        world,
+        resources,
+    );
+    // DebugPass needs the lerp value which is present only after the MeshPass is activated
+    if let Some(debug_pass) = debug_pass {
+        debug_pass.render(
+            shared,
+            encoder,
+            target_view,
+            depth_texture_view,
+            world,
+            resources,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wgsl_shaders_validate() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../assets")
+            .join(WGSL_SHADERS_DIR);
+        let mut validated = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path
+                .extension()
+                .is_none_or(|ext| ext != &WGSL_SHADERS_EXT[1..])
+            {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let module = naga::front::wgsl::parse_str(&source)
+                .unwrap_or_else(|e| panic!("{:?} failed to parse: {}", path, e));
+            naga::valid::Validator::new(
+                naga::valid::ValidationFlags::all(),
+                naga::valid::Capabilities::empty(),
+            )
+            .validate(&module)
+            .unwrap_or_else(|e| panic!("{:?} failed to validate: {:?}", path, e));
+            validated += 1;
+        }
+        assert!(validated > 0);
+    }
+}
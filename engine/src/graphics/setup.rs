@@ -27,26 +27,8 @@ pub async fn setup(
         .with_title("Endless Josh")
         .build(&event_loop)?;
 
-    let backend: Option<wgpu::Backends> = if let Ok(backend) = std::env::var("WGPU_BACKEND") {
-        Some(
-            match backend.to_lowercase().as_str() {
-                "vulkan" => wgpu::Backend::Vulkan,
-                "metal" => wgpu::Backend::Metal,
-                "dx12" => wgpu::Backend::Dx12,
-                "dx11" => wgpu::Backend::Dx11,
-                "gl" => wgpu::Backend::Gl,
-                "webgpu" => wgpu::Backend::BrowserWebGpu,
-                other => panic!("Unknown backend: {}", other),
-            }
-            .into(),
-        )
-    } else {
-        None
-    };
-
-    // ??
-    use wgpu::Backends;
-    let instance = wgpu::Instance::new(backend.unwrap_or_else(Backends::all));
+    let backend = backend_from_env();
+    let instance = wgpu::Instance::new(backend.unwrap_or_else(wgpu::Backends::all));
     let size = window.inner_size();
     let surface = unsafe { instance.create_surface(&window) };
     let adapter = instance
@@ -60,21 +42,7 @@ pub async fn setup(
         .await
         .ok_or_else(|| eyre!("Couldn't find a compatible graphics adapter for backend: {:?}\nIf you want to force a different backend, set the WGPU_BACKEND environmental variable.\nKeep in mind that OpenGL is not currently supported.", backend))?;
 
-    // Optional trace file
-    let trace_dir = std::env::var("WGPU_TRACE");
-
-    // Create the logical device and command queue
-    let (device, queue) = adapter
-        .request_device(
-            &wgpu::DeviceDescriptor {
-                label: None,
-                features: wgpu::Features::default(),
-                limits: wgpu::Limits::default(),
-            },
-            trace_dir.ok().as_ref().map(std::path::Path::new),
-        )
-        .await
-        .wrap_err_with(|| "Failed to create the graphics device")?;
+    let (device, queue) = request_device(&adapter).await?;
 
     let surface_config = wgpu::SurfaceConfiguration {
         usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -93,9 +61,18 @@ pub async fn setup(
     surface.configure(&device, &surface_config);
 
     // Initialize render passes
-    let mesh_pass = MeshPass::new(&device, &surface_config, world, resources)?;
+    let mesh_bind_group_layout = Rc::new(MeshPass::create_mesh_bind_group_layout(&device));
+    let shadow_pass = ShadowPass::new(&device, mesh_bind_group_layout.clone(), world, resources)?;
+    let mesh_pass = MeshPass::new(
+        &device,
+        &surface_config,
+        mesh_bind_group_layout,
+        &shadow_pass,
+        world,
+        resources,
+    )?;
     //let ui_pass = UiPass::new(&device, &surface_config, &window, &queue, world, resources)?;
-    let debug_pass = DebugPass::new(&device, &surface_config, &window, &queue, world, resources)?;
+    let debug_pass = DebugPass::new(&device, &surface_config, &queue, world, resources)?;
 
     let device = Rc::new(device);
     let queue = Rc::new(queue);
@@ -103,19 +80,102 @@ pub async fn setup(
 
     // Insert related resources
     resources.insert(event_loop.create_proxy());
-    let shared = GraphicsShared {
+    let shared = create_shared(&device, &queue, Some(window.clone()), &mesh_pass);
+    resources.insert(shared.clone());
+
+    // Depth testing
+    let (depth_texture, depth_texture_view) = create_depth_texture(&device, &surface_config);
+
+    Ok((
+        Graphics {
+            device,
+            queue,
+            window,
+            shadow_pass,
+            mesh_pass,
+            //ui_pass,
+            debug_pass: Some(debug_pass),
+            surface_config,
+            surface,
+            shared,
+            depth_texture,
+            depth_texture_view,
+        },
+        event_loop,
+    ))
+}
+
+/// The backend chosen with the WGPU_BACKEND environment variable, if any
+pub(super) fn backend_from_env() -> Option<wgpu::Backends> {
+    let backend = std::env::var("WGPU_BACKEND").ok()?;
+    Some(
+        match backend.to_lowercase().as_str() {
+            "vulkan" => wgpu::Backend::Vulkan,
+            "metal" => wgpu::Backend::Metal,
+            "dx12" => wgpu::Backend::Dx12,
+            "dx11" => wgpu::Backend::Dx11,
+            "gl" => wgpu::Backend::Gl,
+            "webgpu" => wgpu::Backend::BrowserWebGpu,
+            other => panic!("Unknown backend: {}", other),
+        }
+        .into(),
+    )
+}
+
+/// Creates the logical device and command queue
+pub(super) async fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue)> {
+    // Optional trace file
+    let trace_dir = std::env::var("WGPU_TRACE");
+
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                features: wgpu::Features::default(),
+                limits: wgpu::Limits::default(),
+            },
+            trace_dir.ok().as_ref().map(std::path::Path::new),
+        )
+        .await
+        .wrap_err_with(|| "Failed to create the graphics device")
+}
+
+pub(super) fn create_shared(
+    device: &Rc<wgpu::Device>,
+    queue: &Rc<wgpu::Queue>,
+    window: Option<Rc<winit::window::Window>>,
+    mesh_pass: &MeshPass,
+) -> GraphicsShared {
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    let white = image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+    let default_map = Texture::new(device, &mut encoder, false, &white);
+    queue.submit(Some(encoder.finish()));
+
+    GraphicsShared {
         device: device.clone(),
         queue: queue.clone(),
-        window: window.clone(),
+        window,
         // TODO: Do something about those layouts
         mesh_layouts: RenderMeshLayouts {
             mesh: mesh_pass.mesh_bind_group_layout.clone(),
-            material: mesh_pass.pipelines.clone(),
+            untextured_part: mesh_pass
+                .pipelines
+                .untextured
+                .part_bind_group_layout
+                .clone(),
+            textured_part: mesh_pass.pipelines.textured.part_bind_group_layout.clone(),
+            sampler: Rc::new(device.create_sampler(&wgpu::SamplerDescriptor::default())),
+            default_map: Rc::new(default_map),
         },
-    };
-    resources.insert(shared.clone());
+    }
+}
 
-    // Depth testing
+/// A depth texture as big as the surface
+pub(super) fn create_depth_texture(
+    device: &wgpu::Device,
+    surface_config: &wgpu::SurfaceConfiguration,
+) -> (wgpu::Texture, wgpu::TextureView) {
     let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
         label: Some("depth texture"),
         size: wgpu::Extent3d {
@@ -129,23 +189,6 @@ pub async fn setup(
         format: wgpu::TextureFormat::Depth32Float,
         usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
     });
-
     let depth_texture_view = depth_texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-    Ok((
-        Graphics {
-            device,
-            queue,
-            window,
-            mesh_pass,
-            //ui_pass,
-            debug_pass: Some(debug_pass),
-            surface_config,
-            surface,
-            shared,
-            depth_texture,
-            depth_texture_view,
-        },
-        event_loop,
-    ))
+    (depth_texture, depth_texture_view)
 }
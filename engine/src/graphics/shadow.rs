@@ -0,0 +1,595 @@
+use std::rc::Rc;
+
+use const_format::concatcp;
+use eyre::{eyre::anyhow, Result};
+use legion::{IntoQuery, Resources, World};
+use serde::{Deserialize, Serialize};
+use wgpu::util::DeviceExt;
+
+use super::{
+    light::LightUniforms,
+    mesh::{RenderMesh, Vertex},
+    AmbientLight, GraphicsShared, MainCamera, Pass, WGSL_SHADERS_DIR, WGSL_SHADERS_EXT,
+};
+use crate::{
+    assets::{data::AlphaMode, AssetLoader},
+    spacetime::{PhysicsTimer, Position},
+};
+
+const SHADOW_SHADER_NAME: &str = "shadow";
+
+/// How many lights can cast shadows at once; directional lights get theirs first
+pub const MAX_SHADOW_MAPS: usize = 4;
+/// Width and height of every shadow map
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+/// Half the width of the area around the main camera covered by directional lights' shadows
+pub const SHADOW_DISTANCE: f32 = 25.0;
+
+pub(crate) const SHADOW_MAP_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// Opts an entity's meshes out of casting or receiving shadows.
+/// Entities without it do both.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Shadows {
+    pub cast: bool,
+    pub receive: bool,
+}
+
+impl Default for Shadows {
+    fn default() -> Self {
+        Shadows {
+            cast: true,
+            receive: true,
+        }
+    }
+}
+
+/// Offsets of a light's shadow map lookups, which prevent surfaces from shadowing themselves
+/// ("shadow acne"). Too much bias makes shadows detach from their casters instead.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct ShadowBias {
+    /// Subtracted from a fragment's depth (0 to 1) in the shadow map
+    pub depth: f32,
+    /// How far (in world units) fragments are moved along their normal before the lookup
+    pub normal: f32,
+}
+
+impl Default for ShadowBias {
+    fn default() -> Self {
+        ShadowBias {
+            depth: 0.0005,
+            normal: 0.05,
+        }
+    }
+}
+
+/// Maps nalgebra's (OpenGL) clip space depth of -1..1 to wgpu's 0..1
+#[rustfmt::skip]
+const DEPTH_TO_WGPU: na::Matrix4<f32> = na::Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.5,
+    0.0, 0.0, 0.0, 1.0,
+);
+
+/// A view looking along `direction`, keeping +z up when possible
+fn look_along(eye: &na::Point3<f32>, direction: &na::Vector3<f32>) -> na::Matrix4<f32> {
+    let up = if direction.cross(&na::Vector3::z()).norm() < 1e-3 {
+        na::Vector3::y()
+    } else {
+        na::Vector3::z()
+    };
+    na::Matrix4::look_at_rh(eye, &(eye + direction), &up)
+}
+
+/// Orthographic light-space transform of a directional light, covering `SHADOW_DISTANCE`
+/// around `focus`. The area moves in whole texels, so shadow edges don't shimmer as it follows
+/// the camera.
+pub(crate) fn directional_view_proj(
+    direction: &na::Vector3<f32>,
+    focus: &na::Point3<f32>,
+) -> na::Matrix4<f32> {
+    // Casters up to twice the distance towards the light are still caught
+    let depth = 3.0 * SHADOW_DISTANCE;
+    let rotation = look_along(&na::Point3::origin(), direction);
+    let texel = 2.0 * SHADOW_DISTANCE / SHADOW_MAP_SIZE as f32;
+    let center = rotation.transform_point(focus);
+    let view = na::Matrix4::new_translation(&na::Vector3::new(
+        -(center.x / texel).round() * texel,
+        -(center.y / texel).round() * texel,
+        -center.z - 2.0 * SHADOW_DISTANCE,
+    )) * rotation;
+    let projection = na::Orthographic3::new(
+        -SHADOW_DISTANCE,
+        SHADOW_DISTANCE,
+        -SHADOW_DISTANCE,
+        SHADOW_DISTANCE,
+        0.0,
+        depth,
+    );
+    DEPTH_TO_WGPU * projection.as_matrix() * view
+}
+
+/// Perspective light-space transform of a spot light, covering its whole cone
+pub(crate) fn spot_view_proj(
+    position: &na::Point3<f32>,
+    direction: &na::Vector3<f32>,
+    outer_angle: f32,
+    range: f32,
+) -> na::Matrix4<f32> {
+    let fov = (2.0 * outer_angle).clamp(1.0, 170.0).to_radians();
+    let projection = na::Perspective3::new(1.0, fov, 0.05, range.max(0.1));
+    DEPTH_TO_WGPU * projection.as_matrix() * look_along(position, direction)
+}
+
+/// How a mesh part casts its shadow
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ShadowDraw {
+    None,
+    /// Depth only, for the whole part
+    Solid,
+    /// With the material bound, discarding fragments below the alpha cutoff
+    Cutout,
+}
+
+impl ShadowDraw {
+    /// `alpha` is the material's diffuse alpha, which is all that untextured materials have
+    fn of(alpha_mode: AlphaMode, textured: bool, alpha: f32) -> Self {
+        match alpha_mode {
+            // Transparent materials let the light through
+            AlphaMode::Blend => ShadowDraw::None,
+            AlphaMode::Mask(_) if textured => ShadowDraw::Cutout,
+            // Untextured cutouts are either drawn or discarded as a whole
+            AlphaMode::Mask(cutoff) if alpha < cutoff => ShadowDraw::None,
+            AlphaMode::Mask(_) | AlphaMode::Opaque => ShadowDraw::Solid,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowUniforms {
+    view_proj: [[f32; 4]; 4],
+}
+
+/// One shadow map being rendered into
+struct ShadowLayer {
+    view: wgpu::TextureView,
+    uniform_buf: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+/// Renders every shadow casting RenderMesh from the point of view of the lights (see
+/// `LightUniforms::gather`) into a layer of the shadow map array each.
+/// Transparent (`AlphaMode::Blend`) materials don't cast shadows, and cutout
+/// (`AlphaMode::Mask`) materials only cast them where they're drawn.
+/// The lit mesh shaders sample the array through `map_view` and `sampler`.
+pub struct ShadowPass {
+    /// Only kept alive for its views
+    _maps: wgpu::Texture,
+    /// Every shadow map, as an array
+    pub map_view: wgpu::TextureView,
+    /// Compares depths with the shadow maps, filtering between neighbouring texels
+    pub sampler: wgpu::Sampler,
+
+    layers: Vec<ShadowLayer>,
+    bind_group_layout: wgpu::BindGroupLayout,
+    mesh_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    /// The layout of textured materials, which cutout draws bind
+    part_bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+    /// Discards the fragments of textured cutout (`AlphaMode::Mask`) materials below their
+    /// alpha cutoff, like the mesh shaders do
+    cutout_pipeline: wgpu::RenderPipeline,
+}
+
+impl ShadowPass {
+    pub fn new(
+        device: &wgpu::Device,
+        mesh_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+        _world: &mut World,
+        resources: &mut Resources,
+    ) -> Result<ShadowPass> {
+        let maps = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("shadow maps"),
+            size: wgpu::Extent3d {
+                width: SHADOW_MAP_SIZE,
+                height: SHADOW_MAP_SIZE,
+                depth_or_array_layers: MAX_SHADOW_MAPS as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: SHADOW_MAP_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        let map_view = maps.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("shadow map array"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                // The light's view projection matrix
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(
+                            std::mem::size_of::<ShadowUniforms>() as wgpu::BufferAddress,
+                        ),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let layers = (0..MAX_SHADOW_MAPS as u32)
+            .map(|layer| {
+                let view = maps.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("shadow map"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                });
+                // Uploaded before rendering every frame
+                let uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Shadow uniform buffer"),
+                    contents: bytemuck::bytes_of(&ShadowUniforms {
+                        view_proj: na::Matrix4::identity().into(),
+                    }),
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                });
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: None,
+                    layout: &bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buf.as_entire_binding(),
+                    }],
+                });
+                ShadowLayer {
+                    view,
+                    uniform_buf,
+                    bind_group,
+                }
+            })
+            .collect();
+
+        let part_bind_group_layout = super::mesh::textured_part_bind_group_layout(device);
+        let [pipeline, cutout_pipeline] = {
+            let asset_loader = resources
+                .get::<AssetLoader>()
+                .ok_or_else(|| anyhow!("Asset loader not found, cannot load shaders"))?;
+            let module = Self::load_shader(device, &asset_loader)?;
+            [false, true].map(|cutout| {
+                Self::create_pipeline(
+                    device,
+                    &bind_group_layout,
+                    &mesh_bind_group_layout,
+                    &part_bind_group_layout,
+                    &module,
+                    cutout,
+                )
+            })
+        };
+
+        Ok(ShadowPass {
+            _maps: maps,
+            map_view,
+            sampler,
+            layers,
+            bind_group_layout,
+            mesh_bind_group_layout,
+            part_bind_group_layout,
+            pipeline,
+            cutout_pipeline,
+        })
+    }
+
+    fn load_shader(
+        device: &wgpu::Device,
+        asset_loader: &AssetLoader,
+    ) -> Result<wgpu::ShaderModule> {
+        Ok(device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("shadow shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                asset_loader
+                    .load_str(concatcp!(
+                        WGSL_SHADERS_DIR,
+                        SHADOW_SHADER_NAME,
+                        WGSL_SHADERS_EXT
+                    ))?
+                    .into(),
+            ),
+        }))
+    }
+
+    /// The cutout pipeline also binds the material (group 2), for its textures
+    fn create_pipeline(
+        device: &wgpu::Device,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        mesh_bind_group_layout: &wgpu::BindGroupLayout,
+        part_bind_group_layout: &wgpu::BindGroupLayout,
+        module: &wgpu::ShaderModule,
+        cutout: bool,
+    ) -> wgpu::RenderPipeline {
+        let bind_group_layouts: &[&wgpu::BindGroupLayout] = if cutout {
+            &[
+                bind_group_layout,
+                mesh_bind_group_layout,
+                part_bind_group_layout,
+            ]
+        } else {
+            &[bind_group_layout, mesh_bind_group_layout]
+        };
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+        let [position, _normal, uv] = Vertex::vertex_attrs();
+        let (label, entry_point, attributes): (_, _, &[_]) = if cutout {
+            ("shadow cutout pipeline", "cutout_vertex", &[position, uv])
+        } else {
+            // Only the position
+            ("shadow pipeline", "main", &[position])
+        };
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module,
+                entry_point,
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes,
+                }],
+            },
+            // Only depth gets written; cutouts discard fragments without any color target
+            fragment: cutout.then_some(wgpu::FragmentState {
+                module,
+                entry_point: "cutout_fragment",
+                targets: &[],
+            }),
+            primitive: wgpu::PrimitiveState {
+                // Single-sided geometry (like planes) should cast shadows from both sides
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: SHADOW_MAP_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+        })
+    }
+
+    /// Recompiles the pipelines with the current shader file.
+    /// If it fails to load or validate, the old pipelines are kept.
+    pub fn reload_shaders(
+        &mut self,
+        device: &wgpu::Device,
+        asset_loader: &AssetLoader,
+    ) -> Result<()> {
+        let [pipeline, cutout_pipeline] = super::mesh::capture_errors(device, || {
+            Self::load_shader(device, asset_loader).map(|module| {
+                [false, true].map(|cutout| {
+                    Self::create_pipeline(
+                        device,
+                        &self.bind_group_layout,
+                        &self.mesh_bind_group_layout,
+                        &self.part_bind_group_layout,
+                        &module,
+                        cutout,
+                    )
+                })
+            })
+        })??;
+        self.pipeline = pipeline;
+        self.cutout_pipeline = cutout_pipeline;
+        Ok(())
+    }
+}
+
+impl Pass for ShadowPass {
+    fn resize(
+        &mut self,
+        _graphics: &GraphicsShared,
+        _surface_config: &wgpu::SurfaceConfiguration,
+        _world: &mut World,
+        _resources: &mut Resources,
+    ) -> Result<()> {
+        // Shadow maps don't depend on the surface
+        Ok(())
+    }
+
+    fn render(
+        &mut self,
+        graphics: &GraphicsShared,
+        encoder: &mut wgpu::CommandEncoder,
+        _target_view: &mut wgpu::TextureView,
+        _depth_texture_view: &wgpu::TextureView,
+        world: &World,
+        resources: &Resources,
+    ) {
+        let lerp = resources
+            .get::<PhysicsTimer>()
+            .map(|t| t.lerp() as f32)
+            .unwrap_or(1.0);
+        // Directional lights' shadows follow the camera
+        let focus = match resources.get::<MainCamera>() {
+            Some(main_cam) => main_cam.position.current(lerp).translation.vector.into(),
+            // No camera present; nothing gets rendered
+            None => return,
+        };
+        // The lights are gathered the same way by the MeshPass, so the shadow maps line up
+        let lights = LightUniforms::gather(world, AmbientLight::default(), &focus, lerp);
+
+        // Model matrices are uploaded by the MeshPass;
+        // queued writes happen before any of the frame's commands run
+        let mut mesh_query = <(&RenderMesh, &Position, Option<&Shadows>)>::query();
+
+        encoder.push_debug_group("shadow pass");
+        for (layer, view_proj) in self.layers.iter().zip(lights.shadow_view_projs()) {
+            graphics.queue.write_buffer(
+                &layer.uniform_buf,
+                0,
+                bytemuck::bytes_of(&ShadowUniforms { view_proj }),
+            );
+
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("shadow map"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &layer.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &layer.bind_group, &[]);
+            let mut cutout_bound = false;
+            for (mesh, _, shadows) in mesh_query.iter(world) {
+                if shadows.is_some_and(|shadows| !shadows.cast) {
+                    continue;
+                }
+                render_pass.set_bind_group(1, &mesh.bind_group, &[]);
+                for part in mesh.parts() {
+                    let material = &part.material;
+                    let cutout = match ShadowDraw::of(
+                        material.alpha_mode,
+                        material.shading.is_textured(),
+                        material.factors.diffuse[3],
+                    ) {
+                        ShadowDraw::None => continue,
+                        ShadowDraw::Solid => false,
+                        ShadowDraw::Cutout => true,
+                    };
+                    if cutout != cutout_bound {
+                        // The light's and mesh's bind groups stay bound
+                        render_pass.set_pipeline(if cutout {
+                            &self.cutout_pipeline
+                        } else {
+                            &self.pipeline
+                        });
+                        cutout_bound = cutout;
+                    }
+                    if cutout {
+                        render_pass.set_bind_group(2, &material.bind_group, &[]);
+                    }
+                    render_pass
+                        .set_index_buffer(part.index_buf.slice(..), wgpu::IndexFormat::Uint32);
+                    render_pass.set_vertex_buffer(0, part.vertex_buf.slice(..));
+                    render_pass.draw_indexed(0..part.index_count, 0, 0..1);
+                }
+            }
+        }
+        encoder.pop_debug_group();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    fn project(view_proj: &na::Matrix4<f32>, point: na::Point3<f32>) -> na::Point3<f32> {
+        na::Point3::from_homogeneous(view_proj * point.to_homogeneous()).unwrap()
+    }
+
+    #[test]
+    fn test_directional_view_proj() {
+        let down = -na::Vector3::z();
+        let focus = na::Point3::new(3.0, -2.0, 1.0);
+        let view_proj = directional_view_proj(&down, &focus);
+
+        // The focus is in the middle of the shadow map (up to a texel)...
+        let texel = 2.0 / SHADOW_MAP_SIZE as f32;
+        let center = project(&view_proj, focus);
+        assert_relative_eq!(center.x, 0.0, epsilon = texel);
+        assert_relative_eq!(center.y, 0.0, epsilon = texel);
+        // ...and points closer to the light are closer in the map
+        let above = project(&view_proj, focus + na::Vector3::z());
+        assert!(above.z < center.z);
+        assert!((0.0..=1.0).contains(&above.z) && (0.0..=1.0).contains(&center.z));
+
+        // Far casters towards the light still fit
+        let high = project(&view_proj, focus + na::Vector3::z() * SHADOW_DISTANCE * 1.5);
+        assert!(high.z >= 0.0);
+    }
+
+    #[test]
+    fn test_spot_view_proj() {
+        let position = na::Point3::new(0.0, 0.0, 5.0);
+        let view_proj = spot_view_proj(&position, &na::Vector3::y(), 30.0, 10.0);
+
+        let ahead = project(&view_proj, na::Point3::new(0.0, 5.0, 5.0));
+        assert_relative_eq!(ahead.x, 0.0, epsilon = 1e-5);
+        assert_relative_eq!(ahead.y, 0.0, epsilon = 1e-5);
+        assert!((0.0..=1.0).contains(&ahead.z));
+        // The edge of the cone is the edge of the map
+        let edge = project(
+            &view_proj,
+            na::Point3::new(5.0 * 30_f32.to_radians().tan(), 5.0, 5.0),
+        );
+        assert_relative_eq!(edge.x.abs(), 1.0, epsilon = 1e-4);
+        // Beyond the range is beyond the far plane
+        let far = project(&view_proj, na::Point3::new(0.0, 11.0, 5.0));
+        assert!(far.z > 1.0);
+    }
+
+    #[test]
+    fn test_shadow_draws_of_alpha_modes() {
+        assert_eq!(
+            ShadowDraw::of(AlphaMode::Opaque, true, 0.0),
+            ShadowDraw::Solid
+        );
+        assert_eq!(
+            ShadowDraw::of(AlphaMode::Blend, false, 1.0),
+            ShadowDraw::None
+        );
+        assert_eq!(
+            ShadowDraw::of(AlphaMode::Blend, true, 1.0),
+            ShadowDraw::None
+        );
+        // Textures decide per fragment...
+        assert_eq!(
+            ShadowDraw::of(AlphaMode::Mask(0.5), true, 1.0),
+            ShadowDraw::Cutout
+        );
+        // ...while untextured materials only have their diffuse alpha
+        assert_eq!(
+            ShadowDraw::of(AlphaMode::Mask(0.5), false, 1.0),
+            ShadowDraw::Solid
+        );
+        assert_eq!(
+            ShadowDraw::of(AlphaMode::Mask(0.5), false, 0.2),
+            ShadowDraw::None
+        );
+    }
+}
@@ -0,0 +1,20 @@
+use super::Graphics;
+
+/// A texture uploaded to the GPU, along with the view its users bind
+pub struct Texture {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+}
+
+impl Texture {
+    pub fn new(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        srgb: bool,
+        img: &image::RgbaImage,
+    ) -> Self {
+        let texture = Graphics::upload_texture(device, encoder, srgb, img);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Texture { texture, view }
+    }
+}
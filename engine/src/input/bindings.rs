@@ -0,0 +1,30 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Action, Axis, GamepadSettings};
+
+/// Maps named axes and actions (like "forward" or "jump") to the physical inputs which
+/// control them, so they can be rebound without a recompile.
+/// Loaded from `settings/bindings.ron` and queried by name through `InputState`.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct InputBindings {
+    #[serde(default)]
+    pub axes: HashMap<String, Vec<Axis>>,
+    #[serde(default)]
+    pub actions: HashMap<String, Vec<Action>>,
+    #[serde(default)]
+    pub gamepad: GamepadSettings,
+}
+
+impl InputBindings {
+    /// Inputs bound to the named axis (none if it isn't bound)
+    pub fn axis(&self, name: &str) -> &[Axis] {
+        self.axes.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Inputs bound to the named action (none if it isn't bound)
+    pub fn action(&self, name: &str) -> &[Action] {
+        self.actions.get(name).map_or(&[], Vec::as_slice)
+    }
+}
@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+
+/// Analog inputs of a gamepad. Sticks go from -1.0 to 1.0 (positive is right or up),
+/// triggers from 0.0 to 1.0.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl GamepadAxis {
+    const COUNT: usize = 6;
+
+    /// The other axis of the same stick, if it is a stick axis
+    fn stick_pair(self) -> Option<GamepadAxis> {
+        use GamepadAxis::*;
+        match self {
+            LeftStickX => Some(LeftStickY),
+            LeftStickY => Some(LeftStickX),
+            RightStickX => Some(RightStickY),
+            RightStickY => Some(RightStickX),
+            LeftTrigger | RightTrigger => None,
+        }
+    }
+}
+
+/// Named after their position, so layouts of different brands map the same way
+/// (South is A on an Xbox controller and Cross on a PlayStation one)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    /// Pressed when the trigger is pulled far enough (see `GamepadSettings::trigger_threshold`)
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+/// Input from a gamepad. Produced from the connected gamepads when the `gamepad` feature
+/// is enabled, but can be fed to `InputState::apply` from anywhere (e.g. to test without
+/// a gamepad).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum GamepadEvent {
+    ButtonPressed(GamepadButton),
+    ButtonReleased(GamepadButton),
+    /// The new raw value of an axis
+    AxisChanged(GamepadAxis, f32),
+}
+
+/// How raw gamepad axis values are turned into the values of bound axes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct GamepadSettings {
+    /// Sticks closer to the center than this (in range [0, 1]) count as centered
+    pub stick_deadzone: f32,
+    /// Trigger values below this count as released
+    pub trigger_deadzone: f32,
+    /// Exponent of the response curve; 1.0 is linear, higher values give finer
+    /// control around the center
+    pub response_curve: f32,
+    /// How far a trigger has to be pulled to press its GamepadButton
+    pub trigger_threshold: f32,
+}
+
+impl Default for GamepadSettings {
+    fn default() -> Self {
+        GamepadSettings {
+            stick_deadzone: 0.15,
+            trigger_deadzone: 0.05,
+            response_curve: 1.0,
+            trigger_threshold: 0.5,
+        }
+    }
+}
+
+/// Raw values of every GamepadAxis
+#[derive(Default, Debug, Clone, Copy)]
+pub(super) struct AxisValues([f32; GamepadAxis::COUNT]);
+
+impl AxisValues {
+    pub fn raw(&self, axis: GamepadAxis) -> f32 {
+        self.0[axis as usize]
+    }
+
+    pub fn set(&mut self, axis: GamepadAxis, value: f32) {
+        self.0[axis as usize] = value;
+    }
+
+    /// The value of an axis with the deadzone and response curve applied.
+    /// The deadzone of a stick is radial (it looks at both of the stick's axes),
+    /// so diagonal movement isn't cut off.
+    pub fn get(&self, axis: GamepadAxis, settings: &GamepadSettings) -> f32 {
+        let value = self.raw(axis);
+        let (magnitude, deadzone) = match axis.stick_pair() {
+            Some(pair) => (
+                na::Vector2::new(value, self.raw(pair)).norm(),
+                settings.stick_deadzone,
+            ),
+            None => (value.abs(), settings.trigger_deadzone),
+        };
+        if magnitude <= deadzone || magnitude == 0.0 {
+            return 0.0;
+        }
+        // Rescale what's outside the deadzone to the full range and apply the curve
+        let scaled = ((magnitude.min(1.0) - deadzone) / (1.0 - deadzone))
+            .powf(settings.response_curve.max(f32::EPSILON));
+        value / magnitude * scaled
+    }
+}
+
+#[cfg(feature = "gamepad")]
+pub use backend::Gamepads;
+
+#[cfg(feature = "gamepad")]
+mod backend {
+    use eyre::{eyre::eyre, Result};
+    use gilrs::{Axis, Button, EventType, Gilrs};
+    use legion::Resources;
+
+    use super::*;
+    use crate::input::{InputEvent, InputState};
+
+    /// The connected gamepads; every one of them controls the same InputState
+    pub struct Gamepads {
+        gilrs: Gilrs,
+    }
+
+    impl Gamepads {
+        pub fn new() -> Result<Self> {
+            let gilrs = Gilrs::new().map_err(|e| eyre!("Gamepads are unavailable: {}", e))?;
+            Ok(Gamepads { gilrs })
+        }
+
+        /// Feeds the events of every gamepad since the last call to the InputState.
+        /// Call it once per frame, after `input::prepare`.
+        pub fn poll(&mut self, resources: &mut Resources) {
+            let mut input_state = resources.get_mut::<InputState>().unwrap();
+            while let Some(event) = self.gilrs.next_event() {
+                let gamepad_event = match event.event {
+                    EventType::ButtonPressed(button, _) => {
+                        convert_button(button).map(GamepadEvent::ButtonPressed)
+                    }
+                    EventType::ButtonReleased(button, _) => {
+                        convert_button(button).map(GamepadEvent::ButtonReleased)
+                    }
+                    // Analog triggers report their value as a button
+                    EventType::ButtonChanged(Button::LeftTrigger2, value, _) => {
+                        Some(GamepadEvent::AxisChanged(GamepadAxis::LeftTrigger, value))
+                    }
+                    EventType::ButtonChanged(Button::RightTrigger2, value, _) => {
+                        Some(GamepadEvent::AxisChanged(GamepadAxis::RightTrigger, value))
+                    }
+                    EventType::AxisChanged(axis, value, _) => {
+                        convert_axis(axis).map(|axis| GamepadEvent::AxisChanged(axis, value))
+                    }
+                    EventType::Connected => {
+                        log::info!("Gamepad connected: {}", self.gilrs.gamepad(event.id).name());
+                        None
+                    }
+                    EventType::Disconnected => {
+                        log::info!("Gamepad disconnected");
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(gamepad_event) = gamepad_event {
+                    input_state.apply(&InputEvent::Gamepad(gamepad_event));
+                }
+            }
+        }
+    }
+
+    fn convert_button(button: Button) -> Option<GamepadButton> {
+        Some(match button {
+            Button::South => GamepadButton::South,
+            Button::East => GamepadButton::East,
+            Button::North => GamepadButton::North,
+            Button::West => GamepadButton::West,
+            Button::LeftTrigger => GamepadButton::LeftBumper,
+            Button::RightTrigger => GamepadButton::RightBumper,
+            Button::Select => GamepadButton::Select,
+            Button::Start => GamepadButton::Start,
+            Button::Mode => GamepadButton::Mode,
+            Button::LeftThumb => GamepadButton::LeftThumb,
+            Button::RightThumb => GamepadButton::RightThumb,
+            Button::DPadUp => GamepadButton::DPadUp,
+            Button::DPadDown => GamepadButton::DPadDown,
+            Button::DPadLeft => GamepadButton::DPadLeft,
+            Button::DPadRight => GamepadButton::DPadRight,
+            // The triggers are pressed according to GamepadSettings instead
+            _ => return None,
+        })
+    }
+
+    fn convert_axis(axis: Axis) -> Option<GamepadAxis> {
+        Some(match axis {
+            Axis::LeftStickX => GamepadAxis::LeftStickX,
+            Axis::LeftStickY => GamepadAxis::LeftStickY,
+            Axis::RightStickX => GamepadAxis::RightStickX,
+            Axis::RightStickY => GamepadAxis::RightStickY,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_stick_deadzone_is_radial() {
+        let settings = GamepadSettings {
+            stick_deadzone: 0.2,
+            ..Default::default()
+        };
+        let mut values = AxisValues::default();
+        values.set(GamepadAxis::LeftStickX, 0.1);
+        values.set(GamepadAxis::LeftStickY, 0.1);
+        assert_eq!(values.get(GamepadAxis::LeftStickX, &settings), 0.0);
+
+        // Small on each axis, but outside the deadzone together
+        values.set(GamepadAxis::LeftStickX, 0.3);
+        values.set(GamepadAxis::LeftStickY, 0.3);
+        let x = values.get(GamepadAxis::LeftStickX, &settings);
+        assert!(x > 0.0);
+        assert_relative_eq!(x, values.get(GamepadAxis::LeftStickY, &settings));
+
+        // Full tilt still reaches 1.0
+        values.set(GamepadAxis::LeftStickX, -1.0);
+        values.set(GamepadAxis::LeftStickY, 0.0);
+        assert_relative_eq!(values.get(GamepadAxis::LeftStickX, &settings), -1.0);
+    }
+
+    #[test]
+    fn test_response_curve() {
+        let settings = GamepadSettings {
+            trigger_deadzone: 0.0,
+            response_curve: 2.0,
+            ..Default::default()
+        };
+        let mut values = AxisValues::default();
+        values.set(GamepadAxis::RightTrigger, 0.5);
+        assert_relative_eq!(values.get(GamepadAxis::RightTrigger, &settings), 0.25);
+        values.set(GamepadAxis::RightTrigger, 1.0);
+        assert_relative_eq!(values.get(GamepadAxis::RightTrigger, &settings), 1.0);
+    }
+}
@@ -2,45 +2,116 @@ use nalgebra as na;
 
 use legion::Resources;
 
-use winit::event::VirtualKeyCode;
+use serde::{Deserialize, Serialize};
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode};
+
+mod bindings;
+pub use bindings::InputBindings;
+
+mod gamepad;
+#[cfg(feature = "gamepad")]
+pub use gamepad::Gamepads;
+pub use gamepad::{GamepadAxis, GamepadButton, GamepadEvent, GamepadSettings};
+
+mod recording;
+pub use recording::{InputPlayer, InputRecorder, RecordedFrame, Recording};
 
 mod state;
 pub use state::InputState;
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Axis {
     KeyboardAxis(VirtualKeyCode, VirtualKeyCode),
-    // TODO: GamepadAxis
+    /// Vertical scrolling; positive when scrolling up (away from the user)
+    MouseWheelAxis,
+    GamepadAxis(GamepadAxis),
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Action {
     KeyboardAction(VirtualKeyCode),
-    // TODO: GamepadAction, MouseAction
+    MouseAction(MouseButton),
+    GamepadAction(GamepadButton),
 }
 
-// TODO: Move to an AxisBindings struct and load it from a config file
-pub const FWD_AXIS: Axis = Axis::KeyboardAxis(VirtualKeyCode::W, VirtualKeyCode::S);
-pub const SIDE_AXIS: Axis = Axis::KeyboardAxis(VirtualKeyCode::D, VirtualKeyCode::A);
-pub const UP_AXIS: Axis = Axis::KeyboardAxis(VirtualKeyCode::Space, VirtualKeyCode::LControl);
-
-// TODO: Move to an ActionBindings struct and load it from a config file
-pub const SPRINT_ACTION: Action = Action::KeyboardAction(VirtualKeyCode::LShift);
+/// Anything that changes the InputState. Every event goes through `InputState::apply`,
+/// so the input of a frame can be recorded and replayed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    Key(VirtualKeyCode, ElementState),
+    MouseButton(MouseButton, ElementState),
+    /// Raw mouse movement (not affected by the cursor's acceleration)
+    MouseMotion(na::Vector2<f32>),
+    /// Lines scrolled
+    Scroll(na::Vector2<f32>),
+    /// New position of the cursor, in physical pixels
+    CursorMoved(na::Vector2<f32>),
+    Gamepad(GamepadEvent),
+}
 
-// TODO: Consider moving this to InputState and fetching it once in the event loop
+/// Starts a new frame of input (see `InputState::new_frame`); call it before handling
+/// the frame's events
 pub fn prepare(resources: &mut Resources) {
     let mut state = resources.get_mut::<InputState>().unwrap();
-    state.mouse_delta = na::zero();
+    state.new_frame();
 }
 
 pub fn handle_keyboard_input(input: winit::event::KeyboardInput, resources: &mut Resources) {
     if let Some(vkeycode) = input.virtual_keycode {
         let mut input_state = resources.get_mut::<InputState>().unwrap();
 
-        input_state.handle_key_event(&vkeycode, &input.state);
+        input_state.apply(&InputEvent::Key(vkeycode, input.state));
+    }
+}
+
+/// Pixels of scrolling (with touchpads) treated as a single line of a mouse wheel
+const PIXELS_PER_SCROLL_LINE: f32 = 20.0;
+
+/// `captured_by_ui` should be set when the UI used the event (e.g. a click on a window),
+/// so it doesn't reach the game. Releases always get through, so buttons never get stuck.
+pub fn handle_mouse_input(
+    state: ElementState,
+    button: MouseButton,
+    captured_by_ui: bool,
+    resources: &mut Resources,
+) {
+    if captured_by_ui && state == ElementState::Pressed {
+        return;
+    }
+    let mut input_state = resources.get_mut::<InputState>().unwrap();
+    input_state.apply(&InputEvent::MouseButton(button, state));
+}
+
+/// See `handle_mouse_input` for `captured_by_ui`
+pub fn handle_mouse_wheel(
+    delta: MouseScrollDelta,
+    captured_by_ui: bool,
+    resources: &mut Resources,
+) {
+    if captured_by_ui {
+        return;
     }
+    // Lines scrolled
+    let delta = match delta {
+        MouseScrollDelta::LineDelta(x, y) => na::Vector2::new(x, y),
+        MouseScrollDelta::PixelDelta(delta) => {
+            na::Vector2::new(delta.x as f32, delta.y as f32) / PIXELS_PER_SCROLL_LINE
+        }
+    };
+    let mut state = resources.get_mut::<InputState>().unwrap();
+    state.apply(&InputEvent::Scroll(delta));
+}
+
+pub fn handle_cursor_moved(position: winit::dpi::PhysicalPosition<f64>, resources: &mut Resources) {
+    let mut state = resources.get_mut::<InputState>().unwrap();
+    state.apply(&InputEvent::CursorMoved(na::Vector2::new(
+        position.x as f32,
+        position.y as f32,
+    )));
 }
 
 pub fn handle_mouse_movement(delta: (f64, f64), resources: &mut Resources) {
     let delta = na::Vector2::<f32>::new(delta.0 as f32, delta.1 as f32);
     let mut state = resources.get_mut::<InputState>().unwrap();
-    state.mouse_delta = delta;
+    state.apply(&InputEvent::MouseMotion(delta));
 }
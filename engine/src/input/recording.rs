@@ -0,0 +1,244 @@
+use std::{path::Path, time::Duration};
+
+use eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{InputBindings, InputEvent, InputState};
+use crate::spacetime::Time;
+
+/// The input of a single frame
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecordedFrame {
+    /// How long the frame took
+    pub delta: Duration,
+    pub events: Vec<InputEvent>,
+    /// How many physics steps were taken during the frame
+    pub physics_steps: u8,
+}
+
+/// A play session, which can be replayed to get the same results
+/// (as long as the world starts in the same state).
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Recording {
+    /// The bindings used while recording, since they change what the inputs do
+    pub bindings: InputBindings,
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let str = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read recording: {:?}", path))?;
+        ron::from_str(&str).wrap_err_with(|| format!("Invalid recording: {:?}", path))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let str = ron::to_string(self).wrap_err("Error while serializing recording")?;
+        std::fs::write(path, str).wrap_err_with(|| format!("Could not write file: {:?}", path))
+    }
+}
+
+/// Records the input of every frame
+pub struct InputRecorder {
+    recording: Recording,
+}
+
+impl InputRecorder {
+    pub fn new(bindings: InputBindings) -> Self {
+        InputRecorder {
+            recording: Recording {
+                bindings,
+                frames: Vec::new(),
+            },
+        }
+    }
+
+    /// Remembers the frame's input. Call it every frame, before the frame's fixed updates.
+    pub fn record_frame(&mut self, time: &Time, input: &InputState) {
+        self.recording.frames.push(RecordedFrame {
+            delta: time.delta,
+            events: input.frame_events().to_vec(),
+            physics_steps: 0,
+        });
+    }
+
+    /// Counts a physics step of the last recorded frame. Call it on every fixed update.
+    pub fn record_physics_step(&mut self) {
+        if let Some(frame) = self.recording.frames.last_mut() {
+            frame.physics_steps += 1;
+        }
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    pub fn finish(self) -> Recording {
+        self.recording
+    }
+}
+
+/// Plays a Recording back, frame by frame
+pub struct InputPlayer {
+    recording: Recording,
+    next_frame: usize,
+    /// The input as it was while recording (unaffected by the real input)
+    state: InputState,
+    /// Physics steps taken since the last played frame
+    physics_steps: u8,
+}
+
+impl InputPlayer {
+    pub fn new(recording: Recording) -> Self {
+        let state = InputState::new(recording.bindings.clone());
+        InputPlayer {
+            recording,
+            next_frame: 0,
+            state,
+            physics_steps: 0,
+        }
+    }
+
+    /// Replaces `input` and `time` with the ones of the next recorded frame, overriding
+    /// `input::prepare` and `spacetime::prepare` (the frame `spacetime::prepare` started is
+    /// replaced, so `time.current` follows the recording). Call it every frame, before the
+    /// frame's fixed updates. Returns None when the recording is over.
+    pub fn play_frame(
+        &mut self,
+        time: &mut Time,
+        input: &mut InputState,
+    ) -> Option<&RecordedFrame> {
+        let frame = self.recording.frames.get(self.next_frame)?;
+        self.next_frame += 1;
+        self.physics_steps = 0;
+
+        self.state.new_frame();
+        for event in &frame.events {
+            self.state.apply(event);
+        }
+        input.clone_from(&self.state);
+        time.set_frame(frame.delta);
+        Some(frame)
+    }
+
+    /// Counts a physics step of the last played frame. Call it on every fixed update.
+    pub fn play_physics_step(&mut self) {
+        self.physics_steps += 1;
+    }
+
+    /// Checks whether the last played frame took as many physics steps as it did while
+    /// recording (so call it after the frame's fixed updates).
+    /// If it didn't, the replay has diverged from the recording.
+    pub fn is_in_sync(&self) -> bool {
+        self.next_frame
+            .checked_sub(1)
+            .and_then(|i| self.recording.frames.get(i))
+            .is_none_or(|frame| frame.physics_steps == self.physics_steps)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next_frame >= self.recording.frames.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        physics,
+        spacetime::{self, PhysicsTimer, Position},
+    };
+    use legion::{system, IntoQuery, Resources, Schedule, World};
+    use winit::event::{ElementState::*, VirtualKeyCode};
+
+    const STEP_TIME: f64 = 0.01;
+
+    /// Moves every Position along x by the "right" axis
+    #[system(for_each)]
+    fn drive(
+        position: &mut Position,
+        #[resource] input: &InputState,
+        #[resource] p_timer: &PhysicsTimer,
+    ) {
+        position.future_mut().translation.vector.x +=
+            input.axis("right") * p_timer.step_time() as f32;
+    }
+
+    /// The world, its resources and a fixed update schedule
+    fn setup(bindings: InputBindings) -> (World, Resources, Schedule) {
+        let mut world = World::default();
+        world.push((Position::from(na::Isometry3::identity()),));
+        let mut resources = Resources::default();
+        resources.insert(InputState::new(bindings));
+        resources.insert(Time::default());
+        resources.insert(PhysicsTimer::new(STEP_TIME));
+        let schedule = Schedule::builder()
+            .add_system(physics::step_system())
+            .add_system(drive_system())
+            .build();
+        (world, resources, schedule)
+    }
+
+    fn final_position(world: &World) -> na::Isometry3<f32> {
+        *<&Position>::query().iter(world).next().unwrap().future()
+    }
+
+    #[test]
+    fn test_replay_reproduces_positions() {
+        let bindings: InputBindings =
+            ron::from_str("(axes: { \"right\": [KeyboardAxis(D, A)] })").unwrap();
+        let (mut world, mut resources, mut schedule) = setup(bindings.clone());
+        let mut recorder = InputRecorder::new(bindings);
+
+        // Uneven frame times, with the key held for a few frames
+        let deltas = [0.016, 0.033, 0.004, 0.021, 0.016, 0.05, 0.009];
+        for (i, &delta) in deltas.iter().enumerate() {
+            {
+                let mut input = resources.get_mut::<InputState>().unwrap();
+                input.new_frame();
+                match i {
+                    1 => input.apply(&InputEvent::Key(VirtualKeyCode::D, Pressed)),
+                    4 => input.apply(&InputEvent::Key(VirtualKeyCode::D, Released)),
+                    _ => {}
+                }
+                let mut time = resources.get_mut::<Time>().unwrap();
+                time.advance(Duration::from_secs_f64(delta));
+                recorder.record_frame(&time, &input);
+            }
+            for _ in 0..spacetime::update_physics_timer(&resources) {
+                schedule.execute(&mut world, &mut resources);
+                recorder.record_physics_step();
+            }
+        }
+        let recorded = final_position(&world);
+        assert!(recorded.translation.vector.x > 0.0);
+
+        // Replay after a round trip through RON, with different (unused) bindings
+        let recording: Recording =
+            ron::from_str(&ron::to_string(recorder.recording()).unwrap()).unwrap();
+        let (mut world, mut resources, mut schedule) = setup(InputBindings::default());
+        let mut player = InputPlayer::new(recording);
+        let start = resources.get::<Time>().unwrap().current;
+        while !player.is_finished() {
+            {
+                let mut time = resources.get_mut::<Time>().unwrap();
+                // The real clock has already started the frame, like in the game
+                time.update();
+                let mut input = resources.get_mut::<InputState>().unwrap();
+                player.play_frame(&mut time, &mut input).unwrap();
+            }
+            for _ in 0..spacetime::update_physics_timer(&resources) {
+                schedule.execute(&mut world, &mut resources);
+                player.play_physics_step();
+            }
+            assert!(player.is_in_sync());
+        }
+        assert_eq!(final_position(&world), recorded);
+        // Game time only moved by the recorded frames
+        let replayed = resources.get::<Time>().unwrap().current - start;
+        let recorded_time: Duration = deltas.iter().map(|&d| Duration::from_secs_f64(d)).sum();
+        assert_eq!(replayed, recorded_time);
+    }
+}
@@ -1,32 +1,217 @@
-use winit::event::{ElementState, VirtualKeyCode};
+use std::collections::HashSet;
 
-use super::*;
+use winit::event::{ElementState, MouseButton, VirtualKeyCode};
 
-/// Tracks which keys are pressed
-#[derive(Default, Debug)]
+use super::{gamepad::AxisValues, *};
+
+/// A set of keys, one bit per key
+#[derive(Default, Debug, Clone, Copy)]
+struct KeySet([u32; 8]);
+
+impl KeySet {
+    fn bit(keycode: &VirtualKeyCode) -> (usize, u32) {
+        let offset = *keycode as u32 / 32;
+        (offset as usize, 1 << (*keycode as u32 - (offset * 32)))
+    }
+
+    fn insert(&mut self, keycode: &VirtualKeyCode) {
+        let (i, bit) = Self::bit(keycode);
+        self.0[i] |= bit;
+    }
+
+    fn remove(&mut self, keycode: &VirtualKeyCode) {
+        let (i, bit) = Self::bit(keycode);
+        self.0[i] &= !bit;
+    }
+
+    fn contains(&self, keycode: &VirtualKeyCode) -> bool {
+        let (i, bit) = Self::bit(keycode);
+        self.0[i] & bit != 0
+    }
+}
+
+/// Tracks which keys and mouse buttons are pressed, and which got pressed or released
+/// during the current frame
+#[derive(Default, Debug, Clone)]
 pub struct InputState {
+    /// Position of the cursor in the window, in physical pixels
     pub cursor: na::Vector2<f32>,
     pub mouse_delta: na::Vector2<f32>,
+    /// Lines scrolled this frame (x: horizontal, y: vertical)
+    pub scroll_delta: na::Vector2<f32>,
+
+    pressed_keys: KeySet,
+    pressed_mouse_buttons: HashSet<MouseButton>,
+    // Reset every frame by `new_frame`. A key can be in both if it was tapped quickly.
+    just_pressed_keys: KeySet,
+    just_released_keys: KeySet,
+    just_pressed_mouse_buttons: HashSet<MouseButton>,
+    just_released_mouse_buttons: HashSet<MouseButton>,
+
+    /// Every event applied since the frame started
+    frame_events: Vec<InputEvent>,
 
-    pressed_keys: [u32; 8],
+    gamepad_axes: AxisValues,
+    pressed_gamepad_buttons: HashSet<GamepadButton>,
+    just_pressed_gamepad_buttons: HashSet<GamepadButton>,
+    just_released_gamepad_buttons: HashSet<GamepadButton>,
+    bindings: InputBindings,
 }
 
 impl InputState {
+    pub fn new(bindings: InputBindings) -> Self {
+        InputState {
+            bindings,
+            ..Default::default()
+        }
+    }
+
+    pub fn bindings(&self) -> &InputBindings {
+        &self.bindings
+    }
+
+    /// Replaces the bindings, keeping the state of the inputs
+    pub fn set_bindings(&mut self, bindings: InputBindings) {
+        self.bindings = bindings;
+    }
+
+    /// Forgets the inputs pressed and released during the previous frame,
+    /// and zeroes the per-frame deltas
+    pub fn new_frame(&mut self) {
+        self.mouse_delta = na::zero();
+        self.scroll_delta = na::zero();
+        self.just_pressed_keys = KeySet::default();
+        self.just_released_keys = KeySet::default();
+        self.just_pressed_mouse_buttons.clear();
+        self.just_released_mouse_buttons.clear();
+        self.just_pressed_gamepad_buttons.clear();
+        self.just_released_gamepad_buttons.clear();
+        self.frame_events.clear();
+    }
+
+    /// Updates the state with an event, remembering it in `frame_events`
+    pub fn apply(&mut self, event: &InputEvent) {
+        match *event {
+            InputEvent::Key(keycode, state) => self.handle_key_event(&keycode, &state),
+            InputEvent::MouseButton(button, state) => {
+                self.handle_mouse_button_event(&button, &state)
+            }
+            InputEvent::MouseMotion(delta) => self.mouse_delta = delta,
+            InputEvent::Scroll(delta) => self.scroll_delta += delta,
+            InputEvent::CursorMoved(position) => self.cursor = position,
+            InputEvent::Gamepad(gamepad_event) => self.handle_gamepad_event(&gamepad_event),
+        }
+        self.frame_events.push(*event);
+    }
+
+    /// The events applied during this frame, in order
+    pub fn frame_events(&self) -> &[InputEvent] {
+        &self.frame_events
+    }
+
     pub fn handle_key_event(&mut self, keycode: &VirtualKeyCode, state: &ElementState) {
-        let offset = *keycode as u32 / 32;
         match state {
             ElementState::Pressed => {
-                self.pressed_keys[offset as usize] |= 1 << (*keycode as u32 - (offset * 32))
+                // Held keys repeat their Pressed events
+                if !self.pressed_keys.contains(keycode) {
+                    self.just_pressed_keys.insert(keycode);
+                }
+                self.pressed_keys.insert(keycode);
             }
             ElementState::Released => {
-                self.pressed_keys[offset as usize] &= !(1 << (*keycode as u32 - (offset * 32)))
+                if self.pressed_keys.contains(keycode) {
+                    self.just_released_keys.insert(keycode);
+                }
+                self.pressed_keys.remove(keycode);
             }
         }
     }
 
     pub fn is_key_pressed(&self, keycode: &VirtualKeyCode) -> bool {
-        let offset = *keycode as u32 / 32;
-        self.pressed_keys[offset as usize] & 1 << (*keycode as u32 - (offset * 32)) != 0
+        self.pressed_keys.contains(keycode)
+    }
+
+    pub fn was_key_pressed_this_frame(&self, keycode: &VirtualKeyCode) -> bool {
+        self.just_pressed_keys.contains(keycode)
+    }
+
+    pub fn was_key_released_this_frame(&self, keycode: &VirtualKeyCode) -> bool {
+        self.just_released_keys.contains(keycode)
+    }
+
+    pub fn handle_mouse_button_event(&mut self, button: &MouseButton, state: &ElementState) {
+        match state {
+            ElementState::Pressed => {
+                if self.pressed_mouse_buttons.insert(*button) {
+                    self.just_pressed_mouse_buttons.insert(*button);
+                }
+            }
+            ElementState::Released => {
+                if self.pressed_mouse_buttons.remove(button) {
+                    self.just_released_mouse_buttons.insert(*button);
+                }
+            }
+        }
+    }
+
+    pub fn is_mouse_button_pressed(&self, button: &MouseButton) -> bool {
+        self.pressed_mouse_buttons.contains(button)
+    }
+
+    pub fn was_mouse_button_pressed_this_frame(&self, button: &MouseButton) -> bool {
+        self.just_pressed_mouse_buttons.contains(button)
+    }
+
+    pub fn was_mouse_button_released_this_frame(&self, button: &MouseButton) -> bool {
+        self.just_released_mouse_buttons.contains(button)
+    }
+
+    pub fn handle_gamepad_event(&mut self, event: &GamepadEvent) {
+        match *event {
+            GamepadEvent::ButtonPressed(button) => {
+                if self.pressed_gamepad_buttons.insert(button) {
+                    self.just_pressed_gamepad_buttons.insert(button);
+                }
+            }
+            GamepadEvent::ButtonReleased(button) => {
+                if self.pressed_gamepad_buttons.remove(&button) {
+                    self.just_released_gamepad_buttons.insert(button);
+                }
+            }
+            GamepadEvent::AxisChanged(axis, value) => {
+                self.gamepad_axes.set(axis, value);
+                // Pulling a trigger far enough presses its button
+                let button = match axis {
+                    GamepadAxis::LeftTrigger => GamepadButton::LeftTrigger,
+                    GamepadAxis::RightTrigger => GamepadButton::RightTrigger,
+                    _ => return,
+                };
+                let state = if value >= self.bindings.gamepad.trigger_threshold {
+                    GamepadEvent::ButtonPressed(button)
+                } else {
+                    GamepadEvent::ButtonReleased(button)
+                };
+                self.handle_gamepad_event(&state);
+            }
+        }
+    }
+
+    pub fn is_gamepad_button_pressed(&self, button: &GamepadButton) -> bool {
+        self.pressed_gamepad_buttons.contains(button)
+    }
+
+    pub fn was_gamepad_button_pressed_this_frame(&self, button: &GamepadButton) -> bool {
+        self.just_pressed_gamepad_buttons.contains(button)
+    }
+
+    pub fn was_gamepad_button_released_this_frame(&self, button: &GamepadButton) -> bool {
+        self.just_released_gamepad_buttons.contains(button)
+    }
+
+    /// The value of a gamepad axis, after applying the deadzone and response curve
+    /// from the bindings' GamepadSettings
+    pub fn gamepad_axis(&self, axis: GamepadAxis) -> f32 {
+        self.gamepad_axes.get(axis, &self.bindings.gamepad)
     }
 
     pub fn modifiers(&self) -> egui::Modifiers {
@@ -52,6 +237,8 @@ impl InputState {
                     (false, true) => -1.0,
                 }
             }
+            Axis::MouseWheelAxis => self.scroll_delta.y.clamp(-1.0, 1.0),
+            Axis::GamepadAxis(axis) => self.gamepad_axis(*axis),
         }
     }
 
@@ -59,6 +246,172 @@ impl InputState {
     pub fn is_action_pressed(&self, action: &Action) -> bool {
         match action {
             Action::KeyboardAction(key) => self.is_key_pressed(key),
+            Action::MouseAction(button) => self.is_mouse_button_pressed(button),
+            Action::GamepadAction(button) => self.is_gamepad_button_pressed(button),
+        }
+    }
+
+    /// Checks if a given action got pressed during this frame
+    pub fn was_pressed_this_frame(&self, action: &Action) -> bool {
+        match action {
+            Action::KeyboardAction(key) => self.was_key_pressed_this_frame(key),
+            Action::MouseAction(button) => self.was_mouse_button_pressed_this_frame(button),
+            Action::GamepadAction(button) => self.was_gamepad_button_pressed_this_frame(button),
         }
     }
+
+    /// Checks if a given action got released during this frame
+    pub fn was_released_this_frame(&self, action: &Action) -> bool {
+        match action {
+            Action::KeyboardAction(key) => self.was_key_released_this_frame(key),
+            Action::MouseAction(button) => self.was_mouse_button_released_this_frame(button),
+            Action::GamepadAction(button) => self.was_gamepad_button_released_this_frame(button),
+        }
+    }
+
+    /// Get the state of an axis by its name in the bindings.
+    /// When several inputs are bound to it, their values are added up.
+    /// Returns a value from -1.0 to 1.0 (0.0 for unbound axes)
+    pub fn axis(&self, name: &str) -> f32 {
+        self.bindings
+            .axis(name)
+            .iter()
+            .map(|axis| self.get_axis_state(axis))
+            .sum::<f32>()
+            .clamp(-1.0, 1.0)
+    }
+
+    /// Checks if any input bound to the named action is pressed
+    pub fn action(&self, name: &str) -> bool {
+        self.bindings
+            .action(name)
+            .iter()
+            .any(|action| self.is_action_pressed(action))
+    }
+
+    /// Checks if any input bound to the named action got pressed during this frame
+    pub fn action_pressed_this_frame(&self, name: &str) -> bool {
+        self.bindings
+            .action(name)
+            .iter()
+            .any(|action| self.was_pressed_this_frame(action))
+    }
+
+    /// Checks if any input bound to the named action got released during this frame
+    pub fn action_released_this_frame(&self, name: &str) -> bool {
+        self.bindings
+            .action(name)
+            .iter()
+            .any(|action| self.was_released_this_frame(action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bindings_are_queried_by_name() {
+        let bindings: InputBindings = ron::from_str(
+            "(axes: { \"forward\": [KeyboardAxis(W, S), KeyboardAxis(Up, Down)] }, \
+             actions: { \"jump\": [KeyboardAction(Space), KeyboardAction(J)] })",
+        )
+        .unwrap();
+        let mut state = InputState::new(bindings);
+        assert_eq!(state.axis("forward"), 0.0);
+        assert!(!state.action("jump"));
+
+        state.handle_key_event(&VirtualKeyCode::Up, &ElementState::Pressed);
+        state.handle_key_event(&VirtualKeyCode::W, &ElementState::Pressed);
+        state.handle_key_event(&VirtualKeyCode::J, &ElementState::Pressed);
+        // Both bindings of the axis are pressed, but it stays in range
+        assert_eq!(state.axis("forward"), 1.0);
+        assert!(state.action("jump"));
+
+        // Unbound names are never active
+        assert_eq!(state.axis("side"), 0.0);
+        assert!(!state.action("fire"));
+    }
+
+    #[test]
+    fn test_mouse_buttons_and_scrolling() {
+        let bindings: InputBindings = ron::from_str(
+            "(axes: { \"zoom\": [MouseWheelAxis] }, actions: { \"fire\": [MouseAction(Left)] })",
+        )
+        .unwrap();
+        let mut state = InputState::new(bindings);
+
+        state.handle_mouse_button_event(&MouseButton::Left, &ElementState::Pressed);
+        assert!(state.action("fire"));
+        assert!(!state.is_mouse_button_pressed(&MouseButton::Right));
+        state.handle_mouse_button_event(&MouseButton::Left, &ElementState::Released);
+        assert!(!state.action("fire"));
+
+        // Scrolling several lines at once still fits the axis' range
+        state.scroll_delta = na::Vector2::new(0.0, -3.0);
+        assert_eq!(state.axis("zoom"), -1.0);
+    }
+
+    #[test]
+    fn test_presses_and_releases_last_a_frame() {
+        use winit::event::VirtualKeyCode::Space;
+        let bindings: InputBindings =
+            ron::from_str("(actions: { \"jump\": [KeyboardAction(Space)] })").unwrap();
+        let mut state = InputState::new(bindings);
+
+        state.handle_key_event(&Space, &ElementState::Pressed);
+        assert!(state.was_key_pressed_this_frame(&Space));
+        assert!(state.action_pressed_this_frame("jump"));
+        state.new_frame();
+        // Still held (with the key repeating), but not pressed again
+        state.handle_key_event(&Space, &ElementState::Pressed);
+        assert!(state.is_key_pressed(&Space));
+        assert!(!state.action_pressed_this_frame("jump"));
+
+        state.new_frame();
+        state.handle_key_event(&Space, &ElementState::Released);
+        assert!(state.action_released_this_frame("jump"));
+        assert!(!state.action("jump"));
+        state.new_frame();
+        assert!(!state.action_released_this_frame("jump"));
+
+        // A quick click is both pressed and released in the same frame
+        state.handle_mouse_button_event(&MouseButton::Left, &ElementState::Pressed);
+        state.handle_mouse_button_event(&MouseButton::Left, &ElementState::Released);
+        let click = Action::MouseAction(MouseButton::Left);
+        assert!(state.was_pressed_this_frame(&click));
+        assert!(state.was_released_this_frame(&click));
+        assert!(!state.is_action_pressed(&click));
+    }
+
+    #[test]
+    fn test_synthetic_gamepad_events() {
+        let bindings: InputBindings = ron::from_str(
+            "(axes: { \"forward\": [KeyboardAxis(W, S), GamepadAxis(LeftStickY)] }, \
+             actions: { \"jump\": [GamepadAction(South)], \"fire\": [GamepadAction(RightTrigger)] }, \
+             gamepad: (stick_deadzone: 0.2))",
+        )
+        .unwrap();
+        let mut state = InputState::new(bindings);
+
+        // Inside the deadzone
+        state.handle_gamepad_event(&GamepadEvent::AxisChanged(GamepadAxis::LeftStickY, 0.1));
+        assert_eq!(state.axis("forward"), 0.0);
+        state.handle_gamepad_event(&GamepadEvent::AxisChanged(GamepadAxis::LeftStickY, -1.0));
+        assert_eq!(state.axis("forward"), -1.0);
+
+        state.handle_gamepad_event(&GamepadEvent::ButtonPressed(GamepadButton::South));
+        assert!(state.action("jump"));
+        assert!(state.action_pressed_this_frame("jump"));
+
+        // Triggers press their buttons once pulled past the threshold
+        let trigger = |value| GamepadEvent::AxisChanged(GamepadAxis::RightTrigger, value);
+        state.handle_gamepad_event(&trigger(0.3));
+        assert!(!state.action("fire"));
+        state.handle_gamepad_event(&trigger(0.8));
+        assert!(state.action("fire"));
+        state.new_frame();
+        state.handle_gamepad_event(&trigger(0.0));
+        assert!(state.action_released_this_frame("fire"));
+    }
 }
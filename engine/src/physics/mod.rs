@@ -1,54 +1,431 @@
-use crate::spacetime::{Child, PhysicsTimer, Position, Time};
+use crate::spacetime::{Child, PhysicsTimer, Position};
 use legion::{system, world::SubWorld};
 use legion::{Entity, IntoQuery};
+use nc::pipeline::{CollisionGroups, CollisionWorld, GeometricQueryType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 // Shamelessly stolen from nphysics (https://www.nphysics.org/rustdoc/nphysics3d/algebra/struct.Velocity3.html)
 mod velocity;
 pub use velocity::Velocity;
 
+mod trace;
+pub use trace::*;
+
+/// Margin used by the broad phase when enlarging the AABBs of colliders
+const COLLISION_MARGIN: f32 = 0.02;
+/// Collision groups of bodies that can and can't be moved by collision response
+const MOVABLE_GROUP: usize = 0;
+const IMMOVABLE_GROUP: usize = 1;
+
 pub struct Collider {
     pub handle: nc::shape::ShapeHandle<f32>,
+    /// Transform of the shape relative to the entity's Position
+    /// (useful for shapes like capsules, which are always aligned with the y axis)
+    pub offset: na::Isometry3<f32>,
+}
+
+impl Collider {
+    pub fn with_offset(shape: nc::shape::ShapeHandle<f32>, offset: na::Isometry3<f32>) -> Self {
+        Self {
+            handle: shape,
+            offset,
+        }
+    }
+
+    /// The world-space transform of the shape when its entity is at `position`
+    pub fn world_position(&self, position: &na::Isometry3<f32>) -> na::Isometry3<f32> {
+        position * self.offset
+    }
 }
 
 impl From<nc::shape::ShapeHandle<f32>> for Collider {
     fn from(shape: nc::shape::ShapeHandle<f32>) -> Self {
-        Self { handle: shape }
+        Self::with_offset(shape, na::Isometry3::identity())
+    }
+}
+
+/// Simple collider shapes that can be written in scene files
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ColliderShape {
+    /// Radius
+    Ball(f32),
+    /// Half extents along x, y and z
+    Cuboid(f32, f32, f32),
+    /// `(total height, radius)`, the height including both caps (unlike ncollide's
+    /// `Capsule`, which takes half the height of the cylinder); stands along the z axis
+    Capsule(f32, f32),
+}
+
+impl ColliderShape {
+    /// The shape of a Collider, if it's one of the simple shapes
+    pub fn of(collider: &Collider) -> Option<Self> {
+        use nc::shape::{Ball, Capsule, Cuboid};
+
+        let handle = &collider.handle;
+        if let Some(ball) = handle.as_shape::<Ball<f32>>() {
+            Some(ColliderShape::Ball(ball.radius))
+        } else if let Some(cuboid) = handle.as_shape::<Cuboid<f32>>() {
+            let half = cuboid.half_extents;
+            Some(ColliderShape::Cuboid(half.x, half.y, half.z))
+        } else {
+            handle.as_shape::<Capsule<f32>>().map(|capsule| {
+                let radius = capsule.radius;
+                ColliderShape::Capsule(2.0 * (capsule.half_height + radius), radius)
+            })
+        }
+    }
+}
+
+impl From<ColliderShape> for Collider {
+    fn from(shape: ColliderShape) -> Self {
+        use nc::shape::{Ball, Capsule, Cuboid, ShapeHandle};
+
+        match shape {
+            ColliderShape::Ball(radius) => ShapeHandle::new(Ball::new(radius)).into(),
+            ColliderShape::Cuboid(x, y, z) => {
+                ShapeHandle::new(Cuboid::new(na::Vector3::new(x, y, z))).into()
+            }
+            ColliderShape::Capsule(height, radius) => Collider::with_offset(
+                ShapeHandle::new(Capsule::new((height / 2.0 - radius).max(0.0), radius)),
+                na::Isometry3::rotation(na::Vector3::x() * std::f32::consts::FRAC_PI_2),
+            ),
+        }
     }
 }
 
+/// A marker component for entities which are moved by their own systems (like the player).
+/// Kinematic entities don't get their Velocity integrated and are never pushed by collision
+/// response, but other bodies still get pushed out of them.
+#[derive(Clone, Copy, Debug)]
+pub struct Kinematic;
+
+/// Takes a single physics step of `PhysicsTimer::step_time`.
+/// Belongs in a fixed update schedule (see `State::fixed_update`).
 #[system]
+#[read_component(Entity)]
 #[read_component(Collider)]
+#[read_component(Kinematic)]
 #[write_component(Position)]
 #[write_component(Velocity)]
 pub fn step(
-    #[resource] p_timer: &mut PhysicsTimer,
-    #[resource] time: &Time,
+    #[resource] p_timer: &PhysicsTimer,
     //#[resource] physics_settings: &PhysicsSettings,
     world: &mut SubWorld,
 ) {
-    p_timer.update(time.delta.as_secs_f64());
-    for _ in 0..p_timer.steps_due() {
-        // Update Positions
-        <&mut Position>::query().for_each_mut(world, |p| {
-            let future = p.future();
-            *p.past_mut() = *future;
+    let dt = p_timer.step_time() as f32;
+    // Update Positions
+    <&mut Position>::query().for_each_mut(world, |p| {
+        let future = p.future();
+        *p.past_mut() = *future;
+    });
+    integrate(world, dt);
+    resolve_collisions(world);
+}
+
+/// Moves every non-kinematic entity with a Velocity by `velocity * dt`
+fn integrate(world: &mut SubWorld, dt: f32) {
+    <(&mut Position, &Velocity)>::query()
+        .filter(!legion::query::component::<Kinematic>())
+        .for_each_mut(world, |(p, v)| {
+            let displacement = v.integrate(dt);
+            let future = p.future_mut();
+            future.translation.vector += displacement.translation.vector;
+            future.rotation = displacement.rotation * future.rotation;
         });
+}
+
+/// Runs the broad and narrow phase over every entity with a Collider and pushes
+/// penetrating bodies apart along the contact normal.
+///
+/// Entities with a Velocity (and without Kinematic) are movable; everything else is
+/// treated as static level geometry. When two movable bodies collide, each one gets
+/// pushed out by half of the penetration depth.
+fn resolve_collisions(world: &mut SubWorld) {
+    let mut collision_world = CollisionWorld::<f32, (Entity, bool)>::new(COLLISION_MARGIN);
+
+    <(
+        Entity,
+        &Position,
+        &Collider,
+        Option<&Velocity>,
+        Option<&Kinematic>,
+    )>::query()
+    .for_each(
+        world,
+        |(entity, position, collider, velocity, kinematic)| {
+            let movable = velocity.is_some() && kinematic.is_none();
+            // Pairs of immovable objects (like two parts of the level) would never get resolved
+            // anyway, so don't even let them reach the narrow phase
+            let groups = if movable {
+                CollisionGroups::new().with_membership(&[MOVABLE_GROUP])
+            } else {
+                CollisionGroups::new()
+                    .with_membership(&[IMMOVABLE_GROUP])
+                    .with_blacklist(&[IMMOVABLE_GROUP])
+            };
+            collision_world.add(
+                collider.world_position(position.future()),
+                collider.handle.clone(),
+                groups,
+                GeometricQueryType::Contacts(0.0, 0.0),
+                (*entity, movable),
+            );
+        },
+    );
+
+    collision_world.update();
+
+    // The world can't be modified while the contacts are borrowed, so gather the corrections first
+    let mut corrections: Vec<(Entity, na::Vector3<f32>)> = Vec::new();
+    for (handle_a, handle_b, _, manifold) in collision_world.contact_pairs(true) {
+        let contact = match manifold.deepest_contact() {
+            Some(tracked) if tracked.contact.depth > 0.0 => tracked.contact,
+            _ => continue,
+        };
+        let (entity_a, movable_a) = *collision_world.collision_object(handle_a).unwrap().data();
+        let (entity_b, movable_b) = *collision_world.collision_object(handle_b).unwrap().data();
+
+        let share = match (movable_a, movable_b) {
+            (true, true) => 0.5,
+            (true, false) | (false, true) => 1.0,
+            (false, false) => continue,
+        };
+        // The contact normal points from the first object towards the second one
+        let push = contact.normal.into_inner() * contact.depth * share;
+        if movable_a {
+            corrections.push((entity_a, -push));
+        }
+        if movable_b {
+            corrections.push((entity_b, push));
+        }
+    }
+
+    let mut body_query = <(&mut Position, &mut Velocity)>::query();
+    for (entity, correction) in corrections {
+        if let Ok((position, velocity)) = body_query.get_mut(world, entity) {
+            position.future_mut().translation.vector += correction;
+            // Cancel the part of the velocity that points into the contact
+            let normal = correction.normalize();
+            let into_contact = velocity.linear.dot(&normal);
+            if into_contact < 0.0 {
+                velocity.linear -= normal * into_contact;
+            }
+        }
     }
 }
 
+/// Moves every entity with a Child component to its parent's Position (with the offset applied).
+/// Parents are always updated before their children, so chains of any length work.
+/// Children whose parent is gone (or doesn't have a Position) stay where they are.
 #[system]
 #[write_component(Position)]
 #[read_component(Child)]
 #[read_component(Entity)]
 pub fn children_update(world: &mut SubWorld) {
-    let child_query = <(Entity, &Child)>::query();
-    let (w_a, mut w_b) = world.split_for_query(&child_query);
-
-    <(Entity, &Child)>::query().for_each(&w_a, |(e, c)| {
-        let parent_pos = *<&Position>::query()
-            .get(&w_b, c.parent)
-            .expect("Parent entity doesn't exist or doesn't have a Position");
-        let position = <&mut Position>::query().get_mut(&mut w_b, *e).unwrap();
-        *position = parent_pos;
-    });
+    let links: HashMap<Entity, (Entity, Position)> = <(Entity, &Child)>::query()
+        .iter(world)
+        .map(|(entity, child)| (*entity, (child.parent, child.offset)))
+        .collect();
+
+    let mut parent_query = <&Position>::query();
+    let mut child_query = <&mut Position>::query();
+    for entity in hierarchy_order(&links) {
+        let (parent, offset) = links[&entity];
+        let parent_position = match parent_query.get(world, parent) {
+            Ok(position) => *position,
+            Err(_) => continue,
+        };
+        if let Ok(position) = child_query.get_mut(world, entity) {
+            *position = parent_position * offset;
+        }
+    }
+}
+
+/// Orders the children so that every parent comes before its own children.
+/// Children caught in a parenting cycle are left out.
+fn hierarchy_order(links: &HashMap<Entity, (Entity, Position)>) -> Vec<Entity> {
+    let mut ordered: Vec<(usize, Entity)> = links
+        .keys()
+        .filter_map(|&entity| {
+            let mut depth = 0;
+            let mut current = entity;
+            while let Some((parent, _)) = links.get(&current) {
+                depth += 1;
+                if depth > links.len() {
+                    log::warn!("Entity {:?} is its own ancestor", entity);
+                    return None;
+                }
+                current = *parent;
+            }
+            Some((depth, entity))
+        })
+        .collect();
+    ordered.sort_by_key(|(depth, _)| *depth);
+    ordered.into_iter().map(|(_, entity)| entity).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use legion::{Resources, Schedule, World};
+    use nc::shape::{Ball, Cuboid, ShapeHandle};
+
+    const STEP_TIME: f64 = 0.015;
+
+    /// Runs the physics step system exactly once
+    fn run_one_step(world: &mut World) {
+        let mut resources = Resources::default();
+        resources.insert(PhysicsTimer::new(STEP_TIME));
+
+        let mut schedule = Schedule::builder().add_system(step_system()).build();
+        schedule.execute(world, &mut resources);
+    }
+
+    fn translation(world: &World, entity: Entity) -> na::Vector3<f32> {
+        <&Position>::query()
+            .get(world, entity)
+            .unwrap()
+            .future()
+            .translation
+            .vector
+    }
+
+    #[test]
+    fn test_overlapping_bodies_separate() {
+        let mut world = World::default();
+        let a = world.push((
+            Position::from(na::Isometry3::translation(0.0, 0.0, 0.0)),
+            Collider::from(ShapeHandle::new(Ball::new(1.0))),
+            Velocity::<f32>::zero(),
+        ));
+        let b = world.push((
+            Position::from(na::Isometry3::translation(1.5, 0.0, 0.0)),
+            Collider::from(ShapeHandle::new(Ball::new(1.0))),
+            Velocity::<f32>::zero(),
+        ));
+
+        run_one_step(&mut world);
+
+        let (pos_a, pos_b) = (translation(&world, a), translation(&world, b));
+        assert!((pos_b - pos_a).norm() >= 2.0 - 1.0e-4);
+        // Both bodies are movable, so they should share the correction
+        assert!(approx::relative_eq!(pos_a.x, -0.25, epsilon = 1.0e-4));
+        assert!(approx::relative_eq!(pos_b.x, 1.75, epsilon = 1.0e-4));
+    }
+
+    #[test]
+    fn test_static_collider_pushes_body_out() {
+        let mut world = World::default();
+        let floor = world.push((
+            Position::from(na::Isometry3::translation(0.0, 0.0, -1.0)),
+            Collider::from(ShapeHandle::new(Cuboid::new(na::Vector3::new(
+                10.0, 10.0, 1.0,
+            )))),
+        ));
+        let body = world.push((
+            Position::from(na::Isometry3::translation(0.0, 0.0, 0.25)),
+            Collider::from(ShapeHandle::new(Ball::new(0.5))),
+            Velocity::<f32>::linear(0.0, 0.0, -1.0),
+        ));
+
+        run_one_step(&mut world);
+
+        // The floor doesn't move
+        assert_eq!(translation(&world, floor), na::Vector3::new(0.0, 0.0, -1.0));
+        // The ball rests on top of the floor and doesn't fall through it anymore
+        assert!(approx::relative_eq!(
+            translation(&world, body).z,
+            0.5,
+            epsilon = 1.0e-4
+        ));
+        let velocity = <&Velocity>::query().get(&world, body).unwrap();
+        assert!(velocity.linear.z.abs() < 1.0e-4);
+    }
+
+    #[test]
+    fn test_kinematic_bodies_are_not_moved() {
+        let mut world = World::default();
+        let kinematic = world.push((
+            Position::from(na::Isometry3::translation(0.0, 0.0, 0.0)),
+            Collider::from(ShapeHandle::new(Ball::new(1.0))),
+            Velocity::<f32>::linear(1.0, 0.0, 0.0),
+            Kinematic,
+        ));
+        world.push((
+            Position::from(na::Isometry3::translation(1.0, 0.0, 0.0)),
+            Collider::from(ShapeHandle::new(Ball::new(1.0))),
+        ));
+
+        run_one_step(&mut world);
+
+        assert_eq!(translation(&world, kinematic), na::Vector3::zeros());
+    }
+
+    fn run_children_update(world: &mut World) {
+        let mut resources = Resources::default();
+        let mut schedule = Schedule::builder()
+            .add_system(children_update_system())
+            .build();
+        schedule.execute(world, &mut resources);
+    }
+
+    #[test]
+    fn test_child_chain_follows_parents() {
+        let mut world = World::default();
+        let quarter_turn =
+            na::UnitQuaternion::from_axis_angle(&na::Vector3::z_axis(), 90.0_f32.to_radians());
+        let root = world.push((Position::from(na::Isometry3::from_parts(
+            na::Translation3::new(1.0, 0.0, 0.0),
+            quarter_turn,
+        )),));
+        let offset = Position::from(na::Isometry3::translation(1.0, 0.0, 0.0));
+        // Push the grandchild first, so it can't get updated before its parent by accident
+        let grandchild = world.push((Position::from(na::Isometry3::identity()),));
+        let child = world.push((
+            Position::from(na::Isometry3::identity()),
+            Child {
+                parent: root,
+                offset,
+            },
+        ));
+        world.entry(grandchild).unwrap().add_component(Child {
+            parent: child,
+            offset,
+        });
+
+        run_children_update(&mut world);
+
+        // The root's rotation turns the offsets to point along y
+        let child_pos = translation(&world, child);
+        assert!(approx::relative_eq!(
+            child_pos,
+            na::Vector3::new(1.0, 1.0, 0.0),
+            epsilon = 1.0e-5
+        ));
+        let grandchild_pos = translation(&world, grandchild);
+        assert!(approx::relative_eq!(
+            grandchild_pos,
+            na::Vector3::new(1.0, 2.0, 0.0),
+            epsilon = 1.0e-5
+        ));
+    }
+
+    #[test]
+    fn test_orphans_stay_in_place() {
+        let mut world = World::default();
+        let parent = world.push((Position::from(na::Isometry3::translation(5.0, 0.0, 0.0)),));
+        let child = world.push((
+            Position::from(na::Isometry3::translation(0.0, 3.0, 0.0)),
+            Child {
+                parent,
+                offset: na::Isometry3::identity().into(),
+            },
+        ));
+        world.remove(parent);
+
+        run_children_update(&mut world);
+
+        assert_eq!(translation(&world, child), na::Vector3::new(0.0, 3.0, 0.0));
+    }
 }
@@ -0,0 +1,226 @@
+use legion::{Entity, EntityStore, IntoQuery};
+use nc::query::{DefaultTOIDispatcher, TOIStatus};
+use nc::shape::Shape;
+
+use super::Collider;
+use crate::spacetime::Position;
+
+/// Traces stop this far away from the surfaces they hit, so the next trace
+/// doesn't start already touching the surface
+pub const TRACE_SKIN: f32 = 0.005;
+/// Motions which are this close to parallel (the cosine of the angle between the motion and
+/// the surface's normal) with a surface slide along it instead of hitting it, which hides
+/// the imprecision of the normals computed for shapes that are barely touching
+const PARALLEL_EPSILON: f32 = 1.0e-3;
+
+/// The result of sweeping a shape through the world
+#[derive(Clone, Copy, Debug)]
+pub struct Trace {
+    /// How much of the motion was completed before hitting something, in range [0, 1]
+    pub fraction: f32,
+    /// The translation the shape should be moved by to end up where it stopped
+    pub motion: na::Vector3<f32>,
+    /// The normal of the surface that was hit, pointing away from the surface
+    pub normal: Option<na::Unit<na::Vector3<f32>>>,
+    /// The entity that was hit
+    pub entity: Option<Entity>,
+    /// The shape was already stuck inside a collider when the trace started
+    pub start_solid: bool,
+}
+
+impl Trace {
+    pub fn hit(&self) -> bool {
+        self.entity.is_some()
+    }
+}
+
+/// Sweeps `shape` from `start` along `motion` and stops at the first Collider in the way.
+///
+/// Colliders which the shape is already touching only block the motion if it goes
+/// into them, so it's always possible to slide along (or move away from) a surface.
+/// The `ignore` entity (usually the one that is moving) is skipped.
+pub fn trace<W: EntityStore>(
+    world: &W,
+    shape: &dyn Shape<f32>,
+    start: &na::Isometry3<f32>,
+    motion: &na::Vector3<f32>,
+    ignore: Option<Entity>,
+) -> Trace {
+    let mut result = Trace {
+        fraction: 1.0,
+        motion: *motion,
+        normal: None,
+        entity: None,
+        start_solid: false,
+    };
+
+    let mut query = <(Entity, &Position, &Collider)>::query();
+    for (entity, position, collider) in query.iter(world) {
+        if Some(*entity) == ignore {
+            continue;
+        }
+        let collider_pos = collider.world_position(position.future());
+
+        let toi = nc::query::time_of_impact(
+            &DefaultTOIDispatcher,
+            start,
+            motion,
+            shape,
+            &collider_pos,
+            &na::zero(),
+            collider.handle.as_ref(),
+            1.0,
+            TRACE_SKIN,
+        );
+        let toi = match toi {
+            Ok(Some(toi)) if toi.toi < result.fraction => toi,
+            _ => continue,
+        };
+
+        let normal = if toi.status == TOIStatus::Penetrating || toi.toi <= 0.0 {
+            // The shapes are already touching, so the TOI witnesses are useless;
+            // find out which way is out of the collider instead
+            let contact = match nc::query::contact(
+                start,
+                shape,
+                &collider_pos,
+                collider.handle.as_ref(),
+                TRACE_SKIN,
+            ) {
+                Some(contact) => contact,
+                None => continue,
+            };
+            if contact.depth > TRACE_SKIN && motion.dot(&contact.normal) > 0.0 {
+                result.start_solid = true;
+            }
+            // The contact normal points from the shape towards the collider
+            -contact.normal
+        } else {
+            -(start.rotation * toi.normal1)
+        };
+        if motion.dot(&normal) >= -PARALLEL_EPSILON * motion.norm() {
+            // Moving along or away from the surface (which can happen when the
+            // shape starts within TRACE_SKIN of it)
+            continue;
+        }
+
+        result.fraction = toi.toi.max(0.0);
+        result.normal = Some(normal);
+        result.entity = Some(*entity);
+    }
+
+    result.motion = motion * result.fraction;
+    result
+}
+
+/// Checks whether `shape` placed at `position` overlaps any Collider (except `ignore`)
+pub fn overlaps<W: EntityStore>(
+    world: &W,
+    shape: &dyn Shape<f32>,
+    position: &na::Isometry3<f32>,
+    ignore: Option<Entity>,
+) -> bool {
+    let mut query = <(Entity, &Position, &Collider)>::query();
+    query
+        .iter(world)
+        .any(|(entity, collider_position, collider)| {
+            Some(*entity) != ignore
+                && nc::query::proximity(
+                    position,
+                    shape,
+                    &collider.world_position(collider_position.future()),
+                    collider.handle.as_ref(),
+                    0.0,
+                ) == nc::query::Proximity::Intersecting
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use legion::World;
+    use nc::shape::{Ball, Cuboid, ShapeHandle};
+
+    /// A 20x20 floor with its top at z = 0
+    fn floor(world: &mut World) -> Entity {
+        world.push((
+            Position::from(na::Isometry3::translation(0.0, 0.0, -1.0)),
+            Collider::from(ShapeHandle::new(Cuboid::new(na::Vector3::new(
+                10.0, 10.0, 1.0,
+            )))),
+        ))
+    }
+
+    #[test]
+    fn test_trace_stops_at_surface() {
+        let mut world = World::default();
+        let floor = floor(&mut world);
+
+        let trace = trace(
+            &world,
+            &Ball::new(0.5),
+            &na::Isometry3::translation(0.0, 0.0, 2.0),
+            &na::Vector3::new(0.0, 0.0, -4.0),
+            None,
+        );
+
+        assert_eq!(trace.entity, Some(floor));
+        assert!(!trace.start_solid);
+        let end_z = 2.0 + trace.motion.z;
+        assert!((0.5..0.5 + 2.0 * TRACE_SKIN).contains(&end_z));
+        let normal = trace.normal.unwrap();
+        assert!(approx::relative_eq!(normal.z, 1.0, epsilon = 1.0e-4));
+    }
+
+    #[test]
+    fn test_trace_slides_along_touched_surface() {
+        let mut world = World::default();
+        floor(&mut world);
+        let resting = na::Isometry3::translation(0.0, 0.0, 0.5);
+
+        // Moving along the floor isn't blocked by it
+        let along = trace(&world, &Ball::new(0.5), &resting, &na::Vector3::x(), None);
+        assert!(!along.hit());
+        assert_eq!(along.fraction, 1.0);
+
+        // But moving into it is
+        let into = trace(&world, &Ball::new(0.5), &resting, &-na::Vector3::z(), None);
+        assert!(into.hit());
+        assert_eq!(into.fraction, 0.0);
+    }
+
+    #[test]
+    fn test_trace_ignores_entity() {
+        let mut world = World::default();
+        let floor = floor(&mut world);
+
+        let trace = trace(
+            &world,
+            &Ball::new(0.5),
+            &na::Isometry3::translation(0.0, 0.0, 2.0),
+            &na::Vector3::new(0.0, 0.0, -4.0),
+            Some(floor),
+        );
+        assert!(!trace.hit());
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let mut world = World::default();
+        floor(&mut world);
+
+        let ball = Ball::new(0.5);
+        assert!(overlaps(
+            &world,
+            &ball,
+            &na::Isometry3::translation(0.0, 0.0, 0.25),
+            None
+        ));
+        assert!(!overlaps(
+            &world,
+            &ball,
+            &na::Isometry3::translation(0.0, 0.0, 0.75),
+            None
+        ));
+    }
+}
@@ -2,11 +2,12 @@
 
 use na::storage::Storage;
 use na::{self, Isometry3, RealField, UnitQuaternion, Vector, Vector3, Vector6, U6};
+use serde::{Deserialize, Serialize};
 use std::ops::{Add, AddAssign, Mul, Sub, SubAssign};
 
 /// A velocity structure combining both the linear angular velocities of a point.
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Velocity<N: RealField = f32> {
     /// The linear velocity.
     pub linear: Vector3<N>,
@@ -9,6 +9,7 @@ pub use position::*;
 pub type Scale = na::Vector3<f32>;
 
 /// A component that makes an entity copy the Position of another entity with an offset
+#[derive(Clone, Copy, Debug)]
 pub struct Child {
     pub parent: Entity,
     pub offset: Position,
@@ -18,3 +19,15 @@ pub fn prepare(resources: &mut Resources) {
     let mut time = resources.get_mut::<Time>().unwrap();
     time.update();
 }
+
+/// Advances the PhysicsTimer (if there is one) by the length of the frame.
+/// Returns how many fixed updates are due this frame.
+pub fn update_physics_timer(resources: &Resources) -> u8 {
+    match (resources.get::<Time>(), resources.get_mut::<PhysicsTimer>()) {
+        (Some(time), Some(mut p_timer)) => {
+            p_timer.update(time.delta.as_secs_f64());
+            p_timer.steps_due()
+        }
+        _ => 0,
+    }
+}
@@ -33,6 +33,19 @@ impl Position {
     }
 }
 
+/// Composes both the past and future transforms, so `parent * offset` is the
+/// position of something attached to `parent` at `offset`
+impl std::ops::Mul for Position {
+    type Output = Position;
+
+    fn mul(self, rhs: Position) -> Position {
+        Position {
+            past: self.past * rhs.past,
+            future: self.future * rhs.future,
+        }
+    }
+}
+
 impl From<na::Isometry3<f32>> for Position {
     fn from(iso: na::Isometry3<f32>) -> Self {
         Position {
@@ -8,52 +8,202 @@ use std::time::{Duration, Instant};
 /// the two physics states to get the current state to render.
 /// The second number is used to inform other systems whether they can do any physics calculations,
 /// and how many physics steps can be executed this frame.
+///
+/// After a long stall (like a breakpoint or dragging the window) at most `max_steps` are taken
+/// in a frame; the rest of the time is dropped, so the game slows down instead of freezing
+/// while it catches up.
 pub struct PhysicsTimer {
     timer: f64,
     steps_due: u8,
     step_time: f64,
+    max_steps: u8,
 }
 impl PhysicsTimer {
+    pub const DEFAULT_MAX_STEPS: u8 = 8;
+
     pub fn new(step_time: f64) -> Self {
         PhysicsTimer {
             timer: 0.0,
             steps_due: 0,
             step_time,
+            max_steps: Self::DEFAULT_MAX_STEPS,
         }
     }
     pub fn update(&mut self, delta: f64) {
         self.timer += delta;
-        self.steps_due = 0;
 
-        let steps = self.timer / self.step_time;
-        self.steps_due = steps.floor() as u8;
-        self.timer -= steps.floor() * self.step_time;
+        let steps = (self.timer / self.step_time).floor();
+        self.timer -= steps * self.step_time;
+        if steps > self.max_steps as f64 {
+            log::debug!(
+                "Dropping {} physics steps to catch up",
+                steps - self.max_steps as f64
+            );
+        }
+        self.steps_due = steps.min(self.max_steps as f64) as u8;
     }
     pub fn steps_due(&self) -> u8 {
         self.steps_due
     }
+    pub fn step_time(&self) -> f64 {
+        self.step_time
+    }
+    /// Changes the length of a step, keeping the time accumulated so far
+    pub fn set_step_time(&mut self, step_time: f64) {
+        self.step_time = step_time;
+    }
+    pub fn max_steps(&self) -> u8 {
+        self.max_steps
+    }
+    /// Changes how many steps can be taken in a single frame
+    pub fn set_max_steps(&mut self, max_steps: u8) {
+        self.max_steps = max_steps;
+    }
     pub fn lerp(&self) -> f64 {
         self.timer / self.step_time
     }
 }
 
+/// Where Time gets the length of each frame from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    /// The time that actually passed
+    Real,
+    /// The time that actually passed, multiplied (0.5 is slow motion at half speed)
+    Scaled(f64),
+    /// Every frame takes exactly this long, no matter how long it really took
+    /// (for tests and offline rendering)
+    Fixed(Duration),
+    /// No time passes at all
+    Paused,
+}
+
+/// The game's clock. `delta` is the length of the current frame according to the `Clock`;
+/// `current` only moves forward by the deltas, so it is game time rather than wall time.
+/// Can also be driven manually with `advance`.
 pub struct Time {
     pub current: Instant,
     pub delta: Duration,
+    clock: Clock,
+    /// When the last frame started in real time
+    last_frame: Instant,
 }
 
 impl Time {
+    pub fn new(clock: Clock) -> Self {
+        let now = Instant::now();
+        Time {
+            current: now,
+            delta: Duration::default(),
+            clock,
+            last_frame: now,
+        }
+    }
+
+    /// Starts a new frame
     pub fn update(&mut self) {
-        self.delta = self.current.elapsed();
-        self.current = Instant::now();
+        let now = Instant::now();
+        let real = now - self.last_frame;
+        self.last_frame = now;
+        let delta = match self.clock {
+            Clock::Real => real,
+            Clock::Scaled(scale) => real.mul_f64(scale.max(0.0)),
+            Clock::Fixed(delta) => delta,
+            Clock::Paused => Duration::ZERO,
+        };
+        self.advance(delta);
+    }
+
+    /// Moves the clock forward by `delta` instead of asking the Clock how much time passed
+    /// (e.g. to replay a recording)
+    pub fn advance(&mut self, delta: Duration) {
+        self.delta = delta;
+        self.current += delta;
+    }
+
+    /// Replaces the length of the current frame, which `update` or `advance` already started,
+    /// so `current` ends up `delta` after the previous frame (e.g. to replay a recording)
+    pub fn set_frame(&mut self, delta: Duration) {
+        self.current -= self.delta;
+        self.advance(delta);
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// Switches to another Clock, starting with the next frame
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
     }
 }
 
 impl Default for Time {
     fn default() -> Self {
-        Time {
-            current: Instant::now(),
-            delta: Duration::default(),
-        }
+        Time::new(Clock::Real)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clocks() {
+        let mut time = Time::new(Clock::Fixed(Duration::from_millis(10)));
+        let start = time.current;
+        time.update();
+        time.update();
+        assert_eq!(time.delta, Duration::from_millis(10));
+        assert_eq!(time.current - start, Duration::from_millis(20));
+
+        time.set_clock(Clock::Paused);
+        time.update();
+        assert_eq!(time.delta, Duration::ZERO);
+        assert_eq!(time.current - start, Duration::from_millis(20));
+
+        // Nothing can make scaled time go backwards
+        time.set_clock(Clock::Scaled(-1.0));
+        time.update();
+        assert_eq!(time.delta, Duration::ZERO);
+
+        time.set_clock(Clock::Scaled(0.5));
+        std::thread::sleep(Duration::from_millis(2));
+        let last_frame = time.last_frame;
+        time.update();
+        let real = time.last_frame - last_frame;
+        assert_eq!(time.delta, real.mul_f64(0.5));
+    }
+
+    #[test]
+    fn test_set_frame_replaces_the_frame() {
+        let mut time = Time::new(Clock::Fixed(Duration::from_millis(10)));
+        let start = time.current;
+        time.update();
+        time.set_frame(Duration::from_millis(4));
+        assert_eq!(time.delta, Duration::from_millis(4));
+        assert_eq!(time.current - start, Duration::from_millis(4));
+
+        time.update();
+        time.set_frame(Duration::from_millis(25));
+        assert_eq!(time.current - start, Duration::from_millis(29));
+    }
+
+    #[test]
+    fn test_physics_timer_catch_up_is_capped() {
+        let mut timer = PhysicsTimer::new(0.01);
+        timer.update(0.025);
+        assert_eq!(timer.steps_due(), 2);
+
+        // A stall long enough to wrap a u8
+        timer.update(3.0);
+        assert_eq!(timer.steps_due(), PhysicsTimer::DEFAULT_MAX_STEPS);
+        assert!(timer.lerp() < 1.0);
+        timer.update(0.0);
+        assert_eq!(timer.steps_due(), 0);
+
+        timer.set_max_steps(1);
+        timer.update(0.05);
+        assert_eq!(timer.steps_due(), 1);
     }
 }
@@ -10,6 +10,9 @@ pub struct Scoped {
 #[derive(Debug)]
 pub enum CustomEvent {
     Exit,
+    /// A file in the assets directory was created or modified (see `AssetLoader::watch`).
+    /// The path is relative to the assets directory.
+    AssetChanged(std::path::PathBuf),
 }
 
 pub enum Transition {
@@ -33,9 +36,19 @@ pub trait State {
     ) -> Transition {
         Transition::None
     }
+    /// Called once per frame, before the frame's fixed updates (so it can change the input
+    /// or the Time they see). Use it for anything that depends on the framerate, like UI.
+    /// Returning a transition skips the frame's fixed and late updates.
     fn update(&mut self, _world: &mut World, _resources: &mut Resources) -> Transition {
         Transition::None
     }
+    /// Called once per physics step (`PhysicsTimer::steps_due` times per frame), so the
+    /// simulation always moves in steps of `PhysicsTimer::step_time`.
+    /// Only called while there is a PhysicsTimer resource.
+    fn fixed_update(&mut self, _world: &mut World, _resources: &mut Resources) {}
+    /// Called once per frame, after the frame's fixed updates, for anything that has to
+    /// see where the physics steps moved things (like the camera following the player)
+    fn late_update(&mut self, _world: &mut World, _resources: &mut Resources) {}
     fn update_inactive(&mut self, _world: &mut World, _resources: &mut Resources) {}
 }
 
@@ -75,7 +88,17 @@ impl<'s> StateMachine<'s> {
 
     pub fn update(&mut self, world: &mut World, resources: &mut Resources) {
         let trans = match self.stack.last_mut() {
-            Some(state) => state.update(world, resources),
+            Some(state) => {
+                let trans = state.update(world, resources);
+                // A state which is on its way out doesn't step any further
+                if let Transition::None = trans {
+                    for _ in 0..crate::spacetime::update_physics_timer(resources) {
+                        state.fixed_update(world, resources);
+                    }
+                    state.late_update(world, resources);
+                }
+                trans
+            }
             None => Transition::None,
         };
         for state in self.stack.iter_mut() {
@@ -126,3 +149,65 @@ impl<'s> StateMachine<'s> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spacetime::{PhysicsTimer, Time};
+    use std::{cell::RefCell, time::Duration};
+
+    /// Writes down which of its updates were called; pops itself on update if `pop` is set
+    struct Recorder<'a> {
+        calls: &'a RefCell<Vec<&'static str>>,
+        pop: bool,
+    }
+
+    impl<'a> State for Recorder<'a> {
+        fn update(&mut self, _world: &mut World, _resources: &mut Resources) -> Transition {
+            self.calls.borrow_mut().push("update");
+            if self.pop {
+                Transition::Pop
+            } else {
+                Transition::None
+            }
+        }
+
+        fn fixed_update(&mut self, _world: &mut World, _resources: &mut Resources) {
+            self.calls.borrow_mut().push("fixed_update");
+        }
+
+        fn late_update(&mut self, _world: &mut World, _resources: &mut Resources) {
+            self.calls.borrow_mut().push("late_update");
+        }
+    }
+
+    #[test]
+    fn test_late_update_runs_once_after_the_fixed_updates() {
+        let mut world = World::default();
+        let mut resources = Resources::default();
+        let mut time = Time::default();
+        time.delta = Duration::from_secs_f64(1.0);
+        resources.insert(time);
+        resources.insert(PhysicsTimer::new(0.5));
+
+        let calls = RefCell::new(Vec::new());
+        let mut state_machine = StateMachine::new(Recorder {
+            calls: &calls,
+            pop: false,
+        });
+        state_machine.update(&mut world, &mut resources);
+        assert_eq!(
+            *calls.borrow(),
+            vec!["update", "fixed_update", "fixed_update", "late_update"]
+        );
+
+        // A state that leaves during its update doesn't get stepped anymore
+        let calls = RefCell::new(Vec::new());
+        let mut state_machine = StateMachine::new(Recorder {
+            calls: &calls,
+            pop: true,
+        });
+        state_machine.update(&mut world, &mut resources);
+        assert_eq!(*calls.borrow(), vec!["update"]);
+    }
+}
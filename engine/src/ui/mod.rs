@@ -9,4 +9,41 @@ pub struct FPSWindow {
     pub fps: f32,
 }
 
-pub struct LoadingWindow;
+use crate::assets::Progress;
+
+#[derive(Default)]
+pub struct LoadingWindow {
+    pub progress: Progress,
+    /// Shown instead of the progress if loading failed
+    pub error: Option<String>,
+}
+
+impl LoadingWindow {
+    /// Draws the window; returns true when the user wants to go back after an error
+    pub fn show(&self, ctx: &egui::CtxRef) -> bool {
+        let response = egui::Window::new("Loading")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| match &self.error {
+                Some(error) => {
+                    ui.label("Loading failed:");
+                    ui.label(error);
+                    ui.button("Back").clicked()
+                }
+                None => {
+                    let progress = &self.progress;
+                    ui.add(egui::ProgressBar::new(progress.fraction()).show_percentage());
+                    ui.label(format!(
+                        "{}/{} files, {:.1}/{:.1} MB",
+                        progress.files_done,
+                        progress.files_total,
+                        progress.bytes_done as f64 / 1.0e6,
+                        progress.bytes_total as f64 / 1.0e6,
+                    ));
+                    false
+                }
+            });
+        response.and_then(|r| r.inner).unwrap_or(false)
+    }
+}
@@ -15,7 +15,7 @@ use engine::{
     state::{CustomEvent, StateMachine},
 };
 
-use eyre::Result;
+use eyre::{eyre::WrapErr, Result};
 use legion::{Resources, World};
 
 use futures::executor::block_on;
@@ -35,19 +35,71 @@ pub fn main() -> Result<()> {
     // Create the resource storage
     let mut resources = Resources::default();
 
+    // `--assets <dir>` loads the assets from somewhere else than the copy next to the executable
+    // (like the source directory, so changes to it get picked up by `--watch`)
+    let args: Vec<String> = std::env::args().collect();
+    let arg_value = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|i| args.get(i + 1))
+    };
+    let assets_dir = arg_value("--assets");
+    let watch_assets = args.iter().any(|arg| arg == "--watch");
+    // `--record <file>` saves the input of the game to a file, `--replay <file>` plays it back
+    let session = match (arg_value("--record"), arg_value("--replay")) {
+        (_, Some(path)) => state::Session::Replay(path.into()),
+        (Some(path), None) => state::Session::Record(path.into()),
+        (None, None) => state::Session::Play,
+    };
+    resources.insert(session);
+
     // AssetLoader is already needed to load shaders
-    let loader = AssetLoader::from_relative_exe_path(std::path::Path::new("assets"))?;
+    let mut loader = match assets_dir {
+        // Asset paths get joined onto the root more than once, so it has to be absolute
+        Some(dir) => AssetLoader::new(
+            std::fs::canonicalize(dir)
+                .wrap_err_with(|| format!("Assets directory not found: {:?}", dir))?,
+        ),
+        None => AssetLoader::from_relative_exe_path(std::path::Path::new("assets"))?,
+    };
+    // Let scenes use the game's own components
+    player::register_components(loader.components_mut());
+    let watcher_loader = loader.clone();
     resources.insert(loader);
 
+    // `--screenshot <file>` renders the start of the game to a file without opening a window
+    if let Some(path) = arg_value("--screenshot") {
+        return state::screenshot(std::path::Path::new(path), &mut world, &mut resources);
+    }
+
     // Set up graphics (window, wgpu)
     let (mut graphics, event_loop) = block_on(graphics::setup(&mut world, &mut resources))?;
 
+    // Send changes to the assets to the state machine (and graphics, for reloading shaders)
+    let _watcher = watch_assets.then(|| {
+        info!("Watching {:?} for changes", watcher_loader.root_path());
+        let proxy = event_loop.create_proxy();
+        watcher_loader.watch(std::time::Duration::from_millis(500), move |path| {
+            // Fails only when the event loop is gone
+            let _ = proxy.send_event(CustomEvent::AssetChanged(path));
+        })
+    });
+
     // Set up essential resources
-    let input_state = input::InputState::default();
+    let bindings = resources
+        .get::<AssetLoader>()
+        .unwrap()
+        .load::<input::InputBindings>(settings::BINDINGS_PATH)?;
+    let input_state = input::InputState::new(bindings);
     let time = spacetime::Time::default();
     resources.insert(input_state);
     resources.insert(time);
 
+    #[cfg(feature = "gamepad")]
+    let mut gamepads = input::Gamepads::new()
+        .map_err(|e| log::warn!("{:?}", e))
+        .ok();
+
     let mut state_machine = StateMachine::new(state::MainState::new());
     state_machine.start(&mut world, &mut resources)?;
 
@@ -71,6 +123,8 @@ pub fn main() -> Result<()> {
 
     info!("Running the event loop");
     event_loop.run(move |event, _, control_flow| {
+        // Keep watching for as long as the event loop runs
+        let _ = &_watcher;
         *control_flow = ControlFlow::Poll;
         //input::handle_egui_event(&event, &mut egui_event_vec);
         egui.handle_event(&event);
@@ -79,6 +133,10 @@ pub fn main() -> Result<()> {
                 // Reset input to values before any events get handled
                 // (for example zero the mouse delta)
                 input::prepare(&mut resources);
+                #[cfg(feature = "gamepad")]
+                if let Some(gamepads) = &mut gamepads {
+                    gamepads.poll(&mut resources);
+                }
                 // Update UI frame timings
                 graphics.prepare(&mut resources);
                 // Update frame timings
@@ -102,6 +160,11 @@ pub fn main() -> Result<()> {
                 state_machine.stop(&mut world, &mut resources);
                 *control_flow = ControlFlow::Exit
             }
+            Event::UserEvent(CustomEvent::AssetChanged(path)) => {
+                if let Err(e) = graphics.asset_changed(path, &resources) {
+                    log::error!("Failed to reload {:?}: {:?}", path, e);
+                }
+            }
             // Handle window resizing
             &Event::WindowEvent {
                 event: WindowEvent::Resized(size),
@@ -118,6 +181,23 @@ pub fn main() -> Result<()> {
                 event: DeviceEvent::MouseMotion { delta },
                 ..
             } => input::handle_mouse_movement(delta, &mut resources),
+            &Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                ..
+            } => input::handle_mouse_input(
+                state,
+                button,
+                egui.captures_event(&event),
+                &mut resources,
+            ),
+            &Event::WindowEvent {
+                event: WindowEvent::MouseWheel { delta, .. },
+                ..
+            } => input::handle_mouse_wheel(delta, egui.captures_event(&event), &mut resources),
+            &Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            } => input::handle_cursor_moved(position, &mut resources),
             // Event::Suspended
             // Event::Resumed
             // Emitted when all of the event loop's input events have been processed and redraw processing is about to begin.
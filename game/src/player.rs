@@ -15,7 +15,7 @@
 // just isn't that extensible.
 // OPTION 1 CHOSEN
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum PlayerState {
     Normal,    // Can accelerate and turn
     Noclip,    // noclip movement
@@ -23,6 +23,17 @@ pub enum PlayerState {
     Dead,      // no acceleration or turning, but still free falling
 }
 
+impl PlayerState {
+    /// The next of the modes that can be toggled at runtime (Normal -> Noclip -> Spectator)
+    pub fn cycle(self) -> Self {
+        match self {
+            PlayerState::Normal => PlayerState::Noclip,
+            PlayerState::Noclip => PlayerState::Spectator,
+            PlayerState::Spectator | PlayerState::Dead => PlayerState::Normal,
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum MovementState {
     Grounded,
@@ -32,41 +43,115 @@ pub enum MovementState {
 // Player flags:
 const PF_DUCKED: u16 = 1;
 const PF_JUMP_HELD: u16 = 2;
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Player {
     pub state: PlayerState,
+    #[serde(skip)]
     pub ground_entity: Option<Entity>,
     pub flags: u16,
     /// Looking pitch (in degrees)
     pub look_pitch: f32,
 }
 
+impl Player {
+    pub fn movement_state(&self) -> MovementState {
+        match self.ground_entity {
+            Some(_) => MovementState::Grounded,
+            None => MovementState::Airborne,
+        }
+    }
+
+    pub fn is_ducked(&self) -> bool {
+        self.flags & PF_DUCKED != 0
+    }
+
+    /// Current height of the player's capsule
+    pub fn height(
+        &self,
+        game_settings: &GameSettings,
+        movement_settings: &MovementSettings,
+    ) -> f32 {
+        if self.is_ducked() {
+            movement_settings.duck_height
+        } else {
+            game_settings.player_height
+        }
+    }
+}
+
 pub type Players = Vec<Entity>;
 
+/// Marks the place (the entity's Position) where the player gets spawned, and in which state
+#[derive(Clone, Copy, Debug)]
+pub struct SpawnPoint {
+    pub state: PlayerState,
+}
+
+/// Lets scene objects list the player's components, e.g. `components: [SpawnPoint(Normal)]`
+pub fn register_components(registry: &mut ComponentRegistry) {
+    registry.register::<Player>("Player");
+    registry.register_as::<PlayerState, SpawnPoint>(
+        "SpawnPoint",
+        |&state| SpawnPoint { state },
+        |spawn_point| Some(spawn_point.state),
+    );
+}
+
 use crate::{
     settings::*,
-    spacetime::{Position, Time},
+    spacetime::{PhysicsTimer, Position},
 };
+use engine::assets::ComponentRegistry;
 use engine::graphics::MainCamera;
-use engine::input::{self, InputState};
+use engine::input::InputState;
 use engine::physics::*;
 use legion::{system, world::SubWorld, Entity, IntoQuery};
+use nc::shape::{Ball, Capsule, ShapeHandle};
+use serde::{Deserialize, Serialize};
+
+/// Eye height as a fraction of the player's (current) height
+const EYE_HEIGHT: f32 = 0.9;
+/// Surfaces steeper than this (normal.z below it) can't be walked on
+const MIN_WALK_NORMAL: f32 = 0.7;
+/// Surfaces with normal.z above this are considered flat
+const FLAT_NORMAL: f32 = 0.999;
+/// How far below the player to look for ground
+const GROUND_TRACE_DISTANCE: f32 = 0.05;
+/// Radius of the thin probe used to find ground below the edges the player stands on
+const GROUND_PROBE_RADIUS: f32 = 0.01;
+/// Velocities get clipped slightly more than needed, so they point away from the surface
+const OVERCLIP: f32 = 1.001;
+/// Below this speed (units/s) friction stops the player completely
+const STOP_EPSILON: f32 = 0.025;
+/// Speed (units/s) along a clip plane below which the move is not considered to go into it
+const CLIP_EPSILON: f32 = 0.0025;
+/// Vertical speed (units/s) away from the ground at which the player is thrown off of it
+const THROWN_OFF_SPEED: f32 = 0.25;
+const MAX_CLIP_PLANES: usize = 5;
+const NUM_BUMPS: usize = 4;
+
+/// Builds the upright capsule shape of a player `height` units tall
+pub fn player_shape(height: f32, radius: f32) -> ShapeHandle<f32> {
+    ShapeHandle::new(Capsule::new((height / 2.0 - radius).max(0.0), radius))
+}
+
+/// The standing player's Collider
+pub fn player_collider(game_settings: &GameSettings) -> Collider {
+    ColliderShape::Capsule(game_settings.player_height, game_settings.player_radius).into()
+}
 
+/// Turns the player with the mouse. Runs every frame rather than at the physics rate, and
+/// turns both the past and the future Position, so looking around is never interpolated.
 #[system]
-#[read_component(Entity)]
-#[read_component(Collider)]
 #[write_component(Player)]
 #[write_component(Position)]
-#[write_component(Velocity)]
-pub fn player_movement(
-    #[resource] _physics_settings: &PhysicsSettings,
+pub fn player_look(
     #[resource] players: &Players,
     #[resource] input_state: &InputState,
-    #[resource] game_settings: &GameSettings,
-    #[resource] time: &mut Time,
     world: &mut SubWorld,
 ) {
-    let mut player_query = <(&mut Player, &mut Position, &mut Velocity)>::query();
-    let (atlas, position, velocity) = player_query.get_mut(world, players[0]).unwrap();
+    let mut player_query = <(&mut Player, &mut Position)>::query();
+    let (atlas, position) = player_query.get_mut(world, players[0]).unwrap();
 
     // TODO: There is something wrong with all this - the rotation
     // seems completely linear. Small movements of the mouse are okay,
@@ -81,12 +166,11 @@ pub fn player_movement(
             let d_pitch_deg = input_state.mouse_delta.y * 0.05;
             log::debug!("yaw_pitch_deg: ({:.5}, {:.5})", d_yaw_deg, d_pitch_deg);
             let yaw_deg = (yaw.to_degrees() + d_yaw_deg) % 360.0; // * game_settings.mouse_sensitivity; // * time.delta.as_secs_f32();
-            let pitch_deg = (atlas.look_pitch.to_degrees() + d_pitch_deg)
-                .max(-89.0)
-                .min(89.0); // * game_settings.mouse_sensitivity; // * time.delta.as_secs_f32();
+            let pitch_deg = (atlas.look_pitch.to_degrees() + d_pitch_deg).clamp(-89.0, 89.0); // * game_settings.mouse_sensitivity; // * time.delta.as_secs_f32();
             atlas.look_pitch = pitch_deg.to_radians();
-            position.future_mut().rotation =
-                na::UnitQuaternion::from_euler_angles(0.0, 0.0, yaw_deg.to_radians());
+            let rotation = na::UnitQuaternion::from_euler_angles(0.0, 0.0, yaw_deg.to_radians());
+            position.past_mut().rotation = rotation;
+            position.future_mut().rotation = rotation;
         }
         //let offset: na::Vector2<f32> =
         //    input_state.mouse_delta * game_settings.mouse_sensitivity * time.delta.as_secs_f32();
@@ -108,6 +192,26 @@ pub fn player_movement(
 
         //position.future_mut().rotation = zrot * position.future_mut().rotation * xrot;
     }
+}
+
+#[system]
+#[read_component(Entity)]
+#[write_component(Collider)]
+#[write_component(Player)]
+#[write_component(Position)]
+#[write_component(Velocity)]
+pub fn player_movement(
+    #[resource] physics_settings: &PhysicsSettings,
+    #[resource] movement_settings: &MovementSettings,
+    #[resource] players: &Players,
+    #[resource] input_state: &InputState,
+    #[resource] game_settings: &GameSettings,
+    #[resource] p_timer: &PhysicsTimer,
+    world: &mut SubWorld,
+) {
+    let mut player_query = <(&mut Player, &mut Position, &mut Velocity, &mut Collider)>::query();
+    let (atlas, position, velocity, collider) = player_query.get_mut(world, players[0]).unwrap();
+    let dt = p_timer.step_time() as f32;
 
     // Finally, handle movement modes
     match atlas.state {
@@ -117,67 +221,18 @@ pub fn player_movement(
         // TODO: Make corpses fly
         // In Source, Dead isn't even an option (REVIEW: Remove?)
         PlayerState::Dead => {}
-        PlayerState::Spectator => {}
-        /*PlayerState::Spectator => {
-            //check_duck(input_state, player);
-            //apply_friction(&player, velocity, time);
-            //  wishvel
-            let forward = *position * na::Vector3::x();
-            let right = *position * na::Vector3::y();
-            let scale = 1.0;
-
-            let mut wishvel = forward * scale + right * scale;
-
-            wishvel.z += scale * input_state.get_axis_state(&input::UP_AXIS);
-
-            let wishdir = wishvel;
-            let wishspeed = wishdir.norm();
-            let currentspeed = na::Vector3::dot(&velocity.linear, &wishdir);
-            let addspeed = wishspeed - currentspeed;
-            let accel = 10.0;
-            if addspeed > 0.0 {
-                let accelspeed = accel * time.delta as f32 * wishspeed;
-                velocity.linear += wishdir * accelspeed;
-            }
-            position.translation.vector += velocity.linear;
-            //return;
-        }*/
         PlayerState::Noclip => {
-            // Accelerate
-            {
-                let wishdir = na::Vector3::new(
-                    input_state.get_axis_state(&input::SIDE_AXIS),
-                    input_state.get_axis_state(&input::FWD_AXIS),
-                    input_state.get_axis_state(&input::UP_AXIS),
-                    //).normalize();
-                );
-                //let current_speed = velocity.linear.dot(&wishdir);
-
-                //let wishspeed = 0.0;
-                //// Reduce wishspeed by the amount of veer
-                //let addspeed = wishspeed - current_speed;
-
-                //// If not going to add any speed, done
-                //let mut accelspeed = 0.0;
-                //if addspeed > 0.0 {
-                //    accelspeed = NOCLIP_ACCELERATE * time.delta * wishspeed * FRICTION;
-                //}
-
-                //// Cap at addspeed
-                //if accelspeed > addspeed { accelspeed = addspeed }
-
-                // Finally, adjust velocity
-                let accelspeed = 3.0;
-                velocity.linear = position.future_mut().rotation * wishdir * accelspeed;
-                if input_state.is_action_pressed(&input::SPRINT_ACTION) {
-                    velocity.linear *= game_settings.sprint_multiplier;
-                }
-            } // Accelerate
+            velocity.linear = fly_velocity(
+                &position.future().rotation,
+                input_state,
+                game_settings,
+                movement_settings,
+            );
 
             // Bleeding off speed(?)
 
             // Just move
-            position.future_mut().translation.vector += velocity.linear * time.delta.as_secs_f32();
+            position.future_mut().translation.vector += velocity.linear * dt;
 
             // PM_NoclipMove();
             // PM_DropTimers();
@@ -192,16 +247,529 @@ pub fn player_movement(
             //);
             //camera.position.translation.vector += direction * speed;
         }
-        // No movement at all
-        PlayerState::Normal => {
-            // PM_CheckDuck();
-            // PM_GroundTrace();
-            // PM_DropTimers();
-            // PM_GroundTrace();
-            // PM_Weapon();
-            // PM_Footsteps();
+        PlayerState::Normal | PlayerState::Spectator => {
+            let spectating = atlas.state == PlayerState::Spectator;
+            // The move has to trace against the rest of the world,
+            // so it works on a copy of the player's state
+            let mut pm = PlayerMove {
+                entity: players[0],
+                origin: position.future().translation.vector,
+                rotation: position.future().rotation,
+                velocity: velocity.linear,
+                flags: atlas.flags,
+                ground_entity: atlas.ground_entity,
+                ground_normal: None,
+                shape: collider.handle.clone(),
+                shape_offset: collider.offset,
+                dt,
+                gravity: physics_settings.gravity,
+                game_settings,
+                settings: movement_settings,
+            };
+            if spectating {
+                pm.fly_move(world, input_state);
+            } else {
+                pm.run(world, input_state);
+            }
+
+            let (atlas, position, velocity, collider) =
+                player_query.get_mut(world, players[0]).unwrap();
+            position.future_mut().translation.vector = pm.origin;
+            velocity.linear = pm.velocity;
+            atlas.flags = pm.flags;
+            atlas.ground_entity = pm.ground_entity;
+            collider.handle = pm.shape;
+        }
+    }
+}
+
+/// State of a single Normal-mode player move, modelled after Quake 3's pmove
+struct PlayerMove<'a> {
+    entity: Entity,
+    origin: na::Vector3<f32>,
+    rotation: na::UnitQuaternion<f32>,
+    velocity: na::Vector3<f32>,
+    flags: u16,
+    ground_entity: Option<Entity>,
+    /// Normal of the surface below the player, even if it's too steep to walk on
+    ground_normal: Option<na::Vector3<f32>>,
+    shape: ShapeHandle<f32>,
+    shape_offset: na::Isometry3<f32>,
+    dt: f32,
+    gravity: f32,
+    game_settings: &'a GameSettings,
+    settings: &'a MovementSettings,
+}
+
+impl<'a> PlayerMove<'a> {
+    fn run(&mut self, world: &SubWorld, input_state: &InputState) {
+        if !input_state.action("jump") {
+            self.flags &= !PF_JUMP_HELD;
+        }
+
+        self.check_duck(world, input_state);
+        self.ground_trace(world);
+
+        if self.ground_entity.is_some() {
+            self.walk_move(world, input_state);
+        } else {
+            self.air_move(world, input_state);
+        }
+
+        // The move may have put the player on (or taken it off) the ground
+        self.ground_trace(world);
+    }
+
+    /// Spectator movement: flies around like noclip, but slides along the world
+    /// instead of passing through it
+    fn fly_move(&mut self, world: &SubWorld, input_state: &InputState) {
+        self.ground_entity = None;
+        self.ground_normal = None;
+        self.velocity = fly_velocity(
+            &self.rotation,
+            input_state,
+            self.game_settings,
+            self.settings,
+        );
+        self.slide_move(world, false);
+    }
+
+    fn shape_position(&self, origin: &na::Vector3<f32>) -> na::Isometry3<f32> {
+        na::Isometry3::from_parts(
+            na::Translation3::from(*origin),
+            na::UnitQuaternion::identity(),
+        ) * self.shape_offset
+    }
+
+    fn trace(
+        &self,
+        world: &SubWorld,
+        origin: &na::Vector3<f32>,
+        motion: &na::Vector3<f32>,
+    ) -> Trace {
+        trace(
+            world,
+            self.shape.as_ref(),
+            &self.shape_position(origin),
+            motion,
+            Some(self.entity),
+        )
+    }
+
+    /// Offset of the capsule's center when switching between standing and ducked heights.
+    /// On the ground the feet stay in place, in the air the head does (so ducking
+    /// while jumping pulls the legs up).
+    fn duck_shift(&self) -> f32 {
+        let shift = (self.game_settings.player_height - self.settings.duck_height) / 2.0;
+        if self.ground_entity.is_some() {
+            -shift
+        } else {
+            shift
+        }
+    }
+
+    fn check_duck(&mut self, world: &SubWorld, input_state: &InputState) {
+        let radius = self.game_settings.player_radius;
+        if input_state.action("duck") {
+            if self.flags & PF_DUCKED == 0 {
+                self.flags |= PF_DUCKED;
+                self.origin.z += self.duck_shift();
+                self.shape = player_shape(self.settings.duck_height, radius);
+            }
+        } else if self.flags & PF_DUCKED != 0 {
+            // Only stand up if there is room for it
+            let standing = player_shape(self.game_settings.player_height, radius);
+            let origin = self.origin - na::Vector3::z() * self.duck_shift();
+            if !overlaps(
+                world,
+                standing.as_ref(),
+                &self.shape_position(&origin),
+                Some(self.entity),
+            ) {
+                self.flags &= !PF_DUCKED;
+                self.origin = origin;
+                self.shape = standing;
+            }
+        }
+    }
+
+    fn ground_trace(&mut self, world: &SubWorld) {
+        let trace = self.trace(
+            world,
+            &self.origin,
+            &na::Vector3::new(0.0, 0.0, -GROUND_TRACE_DISTANCE),
+        );
+        let normal = match trace.normal {
+            Some(normal) => normal.into_inner(),
+            None => {
+                // Free falling
+                self.ground_entity = None;
+                self.ground_normal = None;
+                return;
+            }
+        };
+
+        // Check if getting thrown off the ground (by a jump or a steep ramp)
+        if self.velocity.z > 0.0 && self.velocity.dot(&normal) > THROWN_OFF_SPEED {
+            self.ground_entity = None;
+            self.ground_normal = None;
+            return;
+        }
+
+        // The capsule's round bottom makes the edges of steps look like slopes,
+        // so prefer the surface right below the player
+        if normal.z < FLAT_NORMAL {
+            if let Some((entity, normal)) = self.ground_below(world) {
+                self.ground_entity = Some(entity);
+                self.ground_normal = Some(normal);
+                return;
+            }
+        }
+
+        if normal.z < MIN_WALK_NORMAL {
+            // Slopes that are too steep are not considered ground, the player just slides down them
+            self.ground_entity = None;
+            self.ground_normal = Some(normal);
+            return;
+        }
+
+        self.ground_entity = trace.entity;
+        self.ground_normal = Some(normal);
+    }
+
+    /// Looks for walkable ground (at most a step away) straight below the center of the player
+    fn ground_below(&self, world: &SubWorld) -> Option<(Entity, na::Vector3<f32>)> {
+        let height = if self.flags & PF_DUCKED != 0 {
+            self.settings.duck_height
+        } else {
+            self.game_settings.player_height
+        };
+        let trace = trace(
+            world,
+            &Ball::new(GROUND_PROBE_RADIUS),
+            &na::Isometry3::from_parts(
+                na::Translation3::from(self.origin),
+                na::UnitQuaternion::identity(),
+            ),
+            &na::Vector3::new(0.0, 0.0, -(height / 2.0 + self.settings.step_height)),
+            Some(self.entity),
+        );
+        match (trace.entity, trace.normal) {
+            (Some(entity), Some(normal)) if normal.z >= MIN_WALK_NORMAL => {
+                Some((entity, normal.into_inner()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if the player jumped
+    fn check_jump(&mut self, input_state: &InputState) -> bool {
+        if !input_state.action("jump") {
+            return false;
+        }
+        // Jump has to be released before jumping again
+        if self.flags & PF_JUMP_HELD != 0 {
+            return false;
+        }
+
+        self.ground_entity = None;
+        self.ground_normal = None;
+        self.velocity.z = self.settings.jump_speed;
+        self.flags |= PF_JUMP_HELD;
+        true
+    }
+
+    /// Direction and speed the player wants to move at. When `plane` is given,
+    /// the direction gets projected onto it (so walking up a slope isn't slower)
+    fn wish(
+        &self,
+        input_state: &InputState,
+        plane: Option<&na::Vector3<f32>>,
+    ) -> (na::Vector3<f32>, f32) {
+        let fmove = input_state.axis("forward");
+        let smove = input_state.axis("side");
+
+        let flatten = |v: na::Vector3<f32>| {
+            let mut v = na::Vector3::new(v.x, v.y, 0.0);
+            if let Some(plane) = plane {
+                v = clip_velocity(&v, plane, OVERCLIP);
+            }
+            v.try_normalize(f32::EPSILON).unwrap_or_else(na::zero)
+        };
+        let forward = flatten(self.rotation * na::Vector3::y());
+        let right = flatten(self.rotation * na::Vector3::x());
+
+        let wishdir = (forward * fmove + right * smove)
+            .try_normalize(f32::EPSILON)
+            .unwrap_or_else(na::zero);
+        // Moving diagonally isn't faster than moving straight
+        let mut wishspeed = self.settings.max_speed * fmove.abs().max(smove.abs()).min(1.0);
+        if self.flags & PF_DUCKED != 0 {
+            wishspeed = wishspeed.min(self.settings.max_speed * self.settings.duck_scale);
+        }
+        (wishdir, wishspeed)
+    }
+
+    /// Only the speed along `wishdir` is limited, which is what makes strafe jumping work
+    fn accelerate(&mut self, wishdir: &na::Vector3<f32>, wishspeed: f32, accel: f32) {
+        let current_speed = self.velocity.dot(wishdir);
+        let add_speed = wishspeed - current_speed;
+        if add_speed <= 0.0 {
+            return;
+        }
+        let accel_speed = (accel * self.dt * wishspeed).min(add_speed);
+        self.velocity += wishdir * accel_speed;
+    }
+
+    fn friction(&mut self) {
+        // Ignore slope movement
+        let speed = self.velocity.xy().norm();
+        if speed < STOP_EPSILON {
+            self.velocity.x = 0.0;
+            self.velocity.y = 0.0;
+            return;
+        }
+
+        let control = speed.max(self.settings.stop_speed);
+        let drop = control * self.settings.friction * self.dt;
+        self.velocity *= (speed - drop).max(0.0) / speed;
+    }
+
+    fn walk_move(&mut self, world: &SubWorld, input_state: &InputState) {
+        if self.check_jump(input_state) {
+            self.air_move(world, input_state);
+            return;
+        }
+
+        self.friction();
+
+        let normal = self.ground_normal.unwrap_or_else(na::Vector3::z);
+        let (wishdir, wishspeed) = self.wish(input_state, Some(&normal));
+        self.accelerate(&wishdir, wishspeed, self.settings.accelerate);
+
+        // Slide along the ground plane, but don't lose speed when going up or down a slope
+        let speed = self.velocity.norm();
+        self.velocity = clip_velocity(&self.velocity, &normal, OVERCLIP);
+        if let Some(direction) = self.velocity.try_normalize(f32::EPSILON) {
+            self.velocity = direction * speed;
+        }
+
+        if self.velocity.x == 0.0 && self.velocity.y == 0.0 {
+            return;
+        }
+        self.step_slide_move(world, false);
+
+        // Sliding over edges (the capsule's bottom is round) shouldn't launch the player
+        self.velocity = clip_velocity(&self.velocity, &normal, OVERCLIP);
+    }
+
+    fn air_move(&mut self, world: &SubWorld, input_state: &InputState) {
+        let (wishdir, wishspeed) = self.wish(input_state, None);
+        self.accelerate(&wishdir, wishspeed, self.settings.air_accelerate);
+
+        // Slide along steep slopes
+        if let Some(normal) = self.ground_normal {
+            self.velocity = clip_velocity(&self.velocity, &normal, OVERCLIP);
+        }
+
+        self.step_slide_move(world, true);
+    }
+
+    /// Slides along the world, and if blocked tries to step up onto whatever is in the way
+    fn step_slide_move(&mut self, world: &SubWorld, gravity: bool) {
+        let start_origin = self.origin;
+        let start_velocity = self.velocity;
+
+        if !self.slide_move(world, gravity) {
+            // Got exactly where we wanted to go on the first try
+            return;
+        }
+
+        let step = na::Vector3::new(0.0, 0.0, self.settings.step_height);
+        let down = self.trace(world, &start_origin, &-step);
+        // Never step up while still moving up (jumping)
+        let on_walkable = matches!(down.normal, Some(normal) if normal.z >= MIN_WALK_NORMAL);
+        if self.ground_entity.is_none() && start_velocity.z > 0.0 && !on_walkable {
+            return;
+        }
+
+        let up = self.trace(world, &start_origin, &step);
+        if up.start_solid {
+            // Can't step up
+            return;
+        }
+        let step_size = up.motion.z;
+        let (slide_origin, slide_velocity) = (self.origin, self.velocity);
+
+        // Try the slide move again from the higher position
+        self.origin = start_origin + up.motion;
+        self.velocity = start_velocity;
+        self.slide_move(world, gravity);
+
+        // Push back down the amount that was stepped up
+        let down = self.trace(world, &self.origin, &na::Vector3::new(0.0, 0.0, -step_size));
+        if !down.start_solid {
+            self.origin += down.motion;
+        }
+        match down.normal {
+            Some(normal) if normal.z >= MIN_WALK_NORMAL => {
+                self.velocity = clip_velocity(&self.velocity, &normal, OVERCLIP);
+            }
+            // Landing on the edge of the step shouldn't stop the player
+            Some(_) if self.ground_below(world).is_some() => {}
+            _ => {
+                // Didn't end up on top of anything, so stepping was pointless
+                self.origin = slide_origin;
+                self.velocity = slide_velocity;
+            }
         }
     }
+
+    /// Moves the player by its velocity, sliding along everything it hits.
+    /// Returns true if the player hit something along the way.
+    fn slide_move(&mut self, world: &SubWorld, gravity: bool) -> bool {
+        let mut end_velocity = self.velocity;
+        if gravity {
+            end_velocity.z += self.gravity * self.dt;
+            // Use the average velocity over the frame
+            self.velocity.z = (self.velocity.z + end_velocity.z) * 0.5;
+            if let Some(normal) = self.ground_normal {
+                // Slide along the ground plane
+                self.velocity = clip_velocity(&self.velocity, &normal, OVERCLIP);
+            }
+        }
+
+        let mut time_left = self.dt;
+        let mut planes: Vec<na::Vector3<f32>> = Vec::with_capacity(MAX_CLIP_PLANES);
+        // Never turn against the ground plane
+        if let Some(normal) = self.ground_normal {
+            planes.push(normal);
+        }
+        // Never turn against the original velocity
+        if let Some(direction) = self.velocity.try_normalize(f32::EPSILON) {
+            planes.push(direction);
+        }
+
+        let mut bumps = 0;
+        while bumps < NUM_BUMPS {
+            let trace = self.trace(world, &self.origin, &(self.velocity * time_left));
+            if trace.start_solid {
+                // Stuck inside something, don't build up falling speed
+                self.velocity.z = 0.0;
+                return true;
+            }
+
+            self.origin += trace.motion;
+            let normal = match trace.normal {
+                Some(normal) => normal.into_inner(),
+                // Moved the entire distance
+                None => break,
+            };
+            bumps += 1;
+            time_left -= time_left * trace.fraction;
+
+            if planes.len() >= MAX_CLIP_PLANES {
+                // This shouldn't really happen
+                self.velocity = na::zero();
+                return true;
+            }
+
+            // If this is the same plane we hit before, nudge the velocity out along it,
+            // which fixes some epsilon issues with non-axial planes
+            if planes.iter().any(|plane| normal.dot(plane) > 0.99) {
+                self.velocity += normal * STOP_EPSILON;
+                continue;
+            }
+            planes.push(normal);
+
+            // Modify the velocity so it parallels all of the clip planes
+            for (i, plane_i) in planes.iter().enumerate() {
+                if self.velocity.dot(plane_i) >= CLIP_EPSILON {
+                    // The move doesn't interact with this plane
+                    continue;
+                }
+
+                let mut clip = clip_velocity(&self.velocity, plane_i, OVERCLIP);
+                let mut end_clip = clip_velocity(&end_velocity, plane_i, OVERCLIP);
+
+                // See if there is a second plane that the new move enters
+                for (j, plane_j) in planes.iter().enumerate() {
+                    if j == i || clip.dot(plane_j) >= CLIP_EPSILON {
+                        continue;
+                    }
+
+                    clip = clip_velocity(&clip, plane_j, OVERCLIP);
+                    end_clip = clip_velocity(&end_clip, plane_j, OVERCLIP);
+
+                    // See if it goes back into the first plane
+                    if clip.dot(plane_i) >= 0.0 {
+                        continue;
+                    }
+
+                    // Slide the original velocity along the crease
+                    let crease = plane_i.cross(plane_j).normalize();
+                    clip = crease * crease.dot(&self.velocity);
+                    end_clip = crease * crease.dot(&end_velocity);
+
+                    // Stop dead at a triple plane interaction
+                    let third = planes
+                        .iter()
+                        .enumerate()
+                        .any(|(k, plane_k)| k != i && k != j && clip.dot(plane_k) < CLIP_EPSILON);
+                    if third {
+                        self.velocity = na::zero();
+                        return true;
+                    }
+                }
+
+                // All interactions are fixed, try another move
+                self.velocity = clip;
+                end_velocity = end_clip;
+                break;
+            }
+        }
+
+        if gravity {
+            self.velocity = end_velocity;
+        }
+        bumps != 0
+    }
+}
+
+/// Velocity of the free flying modes (Noclip and Spectator)
+fn fly_velocity(
+    rotation: &na::UnitQuaternion<f32>,
+    input_state: &InputState,
+    game_settings: &GameSettings,
+    movement_settings: &MovementSettings,
+) -> na::Vector3<f32> {
+    let wish = na::Vector3::new(
+        input_state.axis("side"),
+        input_state.axis("forward"),
+        input_state.axis("up"),
+    );
+    // Moving along several axes at once isn't faster than moving along one
+    let wishdir = wish.try_normalize(f32::EPSILON).unwrap_or_else(na::zero);
+    let mut wishspeed = movement_settings.fly_speed * wish.norm().min(1.0);
+    if input_state.action("sprint") {
+        wishspeed *= game_settings.sprint_multiplier;
+    }
+    rotation * wishdir * wishspeed
+}
+
+/// Removes the part of `velocity` which goes into the plane, scaled by `overbounce`
+fn clip_velocity(
+    velocity: &na::Vector3<f32>,
+    normal: &na::Vector3<f32>,
+    overbounce: f32,
+) -> na::Vector3<f32> {
+    let mut backoff = velocity.dot(normal);
+    if backoff < 0.0 {
+        backoff *= overbounce;
+    } else {
+        backoff /= overbounce;
+    }
+    velocity - normal * backoff
 }
 
 // MainCamera sync system
@@ -212,10 +780,275 @@ pub fn player_movement(
 pub fn camera_sync(
     #[resource] players: &Players,
     #[resource] main_cam: &mut MainCamera,
+    #[resource] game_settings: &GameSettings,
+    #[resource] movement_settings: &MovementSettings,
     world: &mut SubWorld,
 ) {
     let mut player_query = <(&Player, &Position)>::query();
-    let (_player, position) = player_query.get(world, players[0]).unwrap();
+    let (player, position) = player_query.get(world, players[0]).unwrap();
+
+    // Positions are at the center of the capsule, so lift the camera up to the eyes
+    let height = player.height(game_settings, movement_settings);
+    let eye = na::Vector3::z() * height * (EYE_HEIGHT - 0.5);
+    let mut camera_position = *position;
+    camera_position.past_mut().translation.vector += eye;
+    camera_position.future_mut().translation.vector += eye;
+    main_cam.position = camera_position;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use engine::input::{Action, Axis, InputBindings, InputEvent};
+    use legion::World;
+    use nc::shape::Cuboid;
+    use winit::event::{ElementState, VirtualKeyCode};
+
+    const DT: f32 = 0.015;
+
+    fn game_settings() -> GameSettings {
+        GameSettings {
+            mouse_sensitivity: 20.0,
+            sprint_multiplier: 2.0,
+            vsync: true,
+            window_width: 800,
+            window_height: 600,
+            player_height: 2.0,
+            player_radius: 0.4,
+        }
+    }
+
+    fn movement_settings() -> MovementSettings {
+        MovementSettings {
+            max_speed: 8.0,
+            stop_speed: 2.5,
+            friction: 6.0,
+            accelerate: 10.0,
+            air_accelerate: 1.0,
+            jump_speed: 6.75,
+            duck_scale: 0.25,
+            duck_height: 1.2,
+            step_height: 0.45,
+            fly_speed: 3.0,
+        }
+    }
+
+    /// A 20x20 box with its top at `top`
+    fn slab(world: &mut World, top: f32) -> Entity {
+        world.push((
+            Position::from(na::Isometry3::translation(0.0, 0.0, top - 1.0)),
+            Collider::from(ShapeHandle::new(Cuboid::new(na::Vector3::new(
+                10.0, 10.0, 1.0,
+            )))),
+        ))
+    }
+
+    /// Input with the given keys bound to the actions of the same name, and held down
+    fn input(pressed: &[(&str, VirtualKeyCode)]) -> InputState {
+        let mut bindings = InputBindings::default();
+        for (name, key) in pressed {
+            bindings
+                .actions
+                .insert(name.to_string(), vec![Action::KeyboardAction(*key)]);
+        }
+        let mut input_state = InputState::new(bindings);
+        for (_, key) in pressed {
+            input_state.apply(&InputEvent::Key(*key, ElementState::Pressed));
+        }
+        input_state
+    }
+
+    /// A standing player with the center of its capsule at `z`
+    fn player_move<'a>(
+        world: &mut World,
+        z: f32,
+        game_settings: &'a GameSettings,
+        settings: &'a MovementSettings,
+    ) -> PlayerMove<'a> {
+        let collider = player_collider(game_settings);
+        PlayerMove {
+            entity: world.push((Position::from(na::Isometry3::translation(0.0, 0.0, z)),)),
+            origin: na::Vector3::new(0.0, 0.0, z),
+            rotation: na::UnitQuaternion::identity(),
+            velocity: na::zero(),
+            flags: 0,
+            ground_entity: None,
+            ground_normal: None,
+            shape: collider.handle,
+            shape_offset: collider.offset,
+            dt: DT,
+            gravity: -20.0,
+            game_settings,
+            settings,
+        }
+    }
+
+    fn capsule_height(pm: &PlayerMove) -> f32 {
+        let capsule = pm.shape.as_shape::<Capsule<f32>>().unwrap();
+        2.0 * (capsule.half_height + capsule.radius)
+    }
+
+    #[test]
+    fn test_ground_trace() {
+        let (game_settings, settings) = (game_settings(), movement_settings());
+        let mut world = World::default();
+        let floor = slab(&mut world, 0.0);
+        let mut standing = player_move(&mut world, 1.01, &game_settings, &settings);
+        let mut falling = player_move(&mut world, 3.0, &game_settings, &settings);
+        let mut jumping = player_move(&mut world, 1.01, &game_settings, &settings);
+        jumping.velocity.z = settings.jump_speed;
+
+        let (world, _) = world.split::<(&Position, &Collider)>();
+        standing.ground_trace(&world);
+        falling.ground_trace(&world);
+        jumping.ground_trace(&world);
+
+        assert_eq!(standing.ground_entity, Some(floor));
+        assert_relative_eq!(standing.ground_normal.unwrap().z, 1.0, epsilon = 1.0e-4);
+        assert_eq!(falling.ground_entity, None);
+        assert_eq!(falling.ground_normal, None);
+        // Moving away from the ground fast enough takes the player off of it
+        assert_eq!(jumping.ground_entity, None);
+    }
+
+    #[test]
+    fn test_friction() {
+        let (game_settings, settings) = (game_settings(), movement_settings());
+        let mut world = World::default();
+        let mut pm = player_move(&mut world, 1.0, &game_settings, &settings);
+
+        pm.velocity = na::Vector3::new(4.0, 0.0, 0.0);
+        pm.friction();
+        assert_relative_eq!(pm.velocity.x, 4.0 - 4.0 * settings.friction * DT);
+
+        // Slow players stop as if they were moving at stop_speed
+        pm.velocity = na::Vector3::new(0.0, 1.0, 0.0);
+        pm.friction();
+        assert_relative_eq!(
+            pm.velocity.y,
+            1.0 - settings.stop_speed * settings.friction * DT
+        );
+
+        pm.velocity = na::Vector3::new(STOP_EPSILON / 2.0, 0.0, 0.0);
+        pm.friction();
+        assert_eq!(pm.velocity, na::Vector3::zeros());
+    }
+
+    #[test]
+    fn test_accelerate() {
+        let (game_settings, settings) = (game_settings(), movement_settings());
+        let mut world = World::default();
+        let mut pm = player_move(&mut world, 1.0, &game_settings, &settings);
+        let wishdir = na::Vector3::x();
 
-    main_cam.position = *position;
+        pm.accelerate(&wishdir, 8.0, settings.accelerate);
+        assert_relative_eq!(pm.velocity, na::Vector3::new(8.0 * 10.0 * DT, 0.0, 0.0));
+
+        // Never accelerates past the wished speed along the wished direction
+        pm.velocity = na::Vector3::new(7.9, 0.0, 0.0);
+        pm.accelerate(&wishdir, 8.0, settings.accelerate);
+        assert_relative_eq!(pm.velocity, na::Vector3::new(8.0, 0.0, 0.0));
+        pm.accelerate(&wishdir, 8.0, settings.accelerate);
+        assert_relative_eq!(pm.velocity, na::Vector3::new(8.0, 0.0, 0.0));
+
+        // But speed along other directions is kept, which allows strafe jumping
+        pm.velocity = na::Vector3::new(0.0, 8.0, 0.0);
+        pm.accelerate(&wishdir, 8.0, settings.accelerate);
+        assert_relative_eq!(pm.velocity, na::Vector3::new(8.0 * 10.0 * DT, 8.0, 0.0));
+    }
+
+    #[test]
+    fn test_jump_only_when_grounded() {
+        let (game_settings, settings) = (game_settings(), movement_settings());
+        let mut world = World::default();
+        slab(&mut world, 0.0);
+        let mut grounded = player_move(&mut world, 1.01, &game_settings, &settings);
+        let mut airborne = player_move(&mut world, 3.0, &game_settings, &settings);
+        let mut held = player_move(&mut world, 1.01, &game_settings, &settings);
+        held.flags |= PF_JUMP_HELD;
+        let input_state = input(&[("jump", VirtualKeyCode::Space)]);
+
+        let (world, _) = world.split::<(&Position, &Collider)>();
+        grounded.run(&world, &input_state);
+        airborne.run(&world, &input_state);
+        held.run(&world, &input_state);
+
+        assert!(grounded.velocity.z > 0.0);
+        assert_eq!(grounded.ground_entity, None);
+        assert_ne!(grounded.flags & PF_JUMP_HELD, 0);
+        // Only gravity
+        assert!(airborne.velocity.z < 0.0);
+        // Jump has to be released before jumping again
+        assert_relative_eq!(held.velocity.z, 0.0);
+        assert!(held.ground_entity.is_some());
+    }
+
+    #[test]
+    fn test_duck_changes_height() {
+        let (game_settings, settings) = (game_settings(), movement_settings());
+        let mut world = World::default();
+        let floor = slab(&mut world, 0.0);
+        // Resting on the floor, like after a move
+        let z = game_settings.player_height / 2.0 + TRACE_SKIN;
+        let mut pm = player_move(&mut world, z, &game_settings, &settings);
+        pm.ground_entity = Some(floor);
+        let mut under_ceiling = player_move(&mut world, z, &game_settings, &settings);
+        under_ceiling.ground_entity = Some(floor);
+        let ducking = input(&[("duck", VirtualKeyCode::LControl)]);
+        let standing = input(&[]);
+
+        {
+            let (world, _) = world.split::<(&Position, &Collider)>();
+            pm.check_duck(&world, &ducking);
+            under_ceiling.check_duck(&world, &ducking);
+        }
+        // The feet stay on the ground
+        assert_ne!(pm.flags & PF_DUCKED, 0);
+        assert_relative_eq!(capsule_height(&pm), settings.duck_height);
+        assert_relative_eq!(pm.origin.z, settings.duck_height / 2.0 + TRACE_SKIN);
+
+        {
+            let (world, _) = world.split::<(&Position, &Collider)>();
+            pm.check_duck(&world, &standing);
+        }
+        assert_eq!(pm.flags & PF_DUCKED, 0);
+        assert_relative_eq!(capsule_height(&pm), game_settings.player_height);
+        assert_relative_eq!(pm.origin.z, z);
+
+        // There's no room to stand up below a ceiling lower than the player
+        let ceiling_bottom = (settings.duck_height + game_settings.player_height) / 2.0;
+        slab(&mut world, ceiling_bottom + 2.0);
+        let (world, _) = world.split::<(&Position, &Collider)>();
+        under_ceiling.check_duck(&world, &standing);
+        assert_ne!(under_ceiling.flags & PF_DUCKED, 0);
+        assert_relative_eq!(capsule_height(&under_ceiling), settings.duck_height);
+    }
+
+    #[test]
+    fn test_flying_diagonally_isnt_faster() {
+        let (game_settings, settings) = (game_settings(), movement_settings());
+        let mut bindings = InputBindings::default();
+        for (name, positive, negative) in [
+            ("forward", VirtualKeyCode::W, VirtualKeyCode::S),
+            ("side", VirtualKeyCode::D, VirtualKeyCode::A),
+            ("up", VirtualKeyCode::Space, VirtualKeyCode::LControl),
+        ] {
+            bindings.axes.insert(
+                name.to_string(),
+                vec![Axis::KeyboardAxis(positive, negative)],
+            );
+        }
+        let mut input_state = InputState::new(bindings);
+        let rotation = na::UnitQuaternion::from_euler_angles(0.0, 0.0, 1.0);
+
+        let idle = fly_velocity(&rotation, &input_state, &game_settings, &settings);
+        assert_eq!(idle, na::Vector3::zeros());
+
+        for key in [VirtualKeyCode::W, VirtualKeyCode::D, VirtualKeyCode::Space] {
+            input_state.apply(&InputEvent::Key(key, ElementState::Pressed));
+            let velocity = fly_velocity(&rotation, &input_state, &game_settings, &settings);
+            assert_relative_eq!(velocity.norm(), settings.fly_speed);
+        }
+    }
 }
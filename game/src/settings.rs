@@ -1,8 +1,21 @@
+use std::path::Path;
+
+use engine::{
+    assets::AssetLoader,
+    input::{InputBindings, InputState},
+    spacetime::PhysicsTimer,
+};
+use eyre::Result;
+use legion::Resources;
 use serde::{Deserialize, Serialize};
 
+pub const GAME_SETTINGS_PATH: &str = "settings/game.ron";
+pub const PHYSICS_SETTINGS_PATH: &str = "settings/physics.ron";
+pub const MOVEMENT_SETTINGS_PATH: &str = "settings/movement.ron";
+pub const BINDINGS_PATH: &str = "settings/bindings.ron";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GameSettings {
-    pub noclip_speed: f32,
     pub mouse_sensitivity: f32,
     pub sprint_multiplier: f32,
     pub vsync: bool,
@@ -15,7 +28,67 @@ pub struct GameSettings {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PhysicsSettings {
+    /// Acceleration along the z axis, in units/s^2 (not per physics step, so it doesn't
+    /// depend on step_time)
     pub gravity: f32,
     pub air_friction: f32,
     pub step_time: f64,
 }
+
+/// Player movement tunables, named after their Quake counterparts.
+/// Speeds are in units/s; accelerations are in wished speeds per second.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MovementSettings {
+    pub max_speed: f32,
+    /// Below this speed friction is applied as if the player was moving this fast,
+    /// so slow players stop quickly
+    pub stop_speed: f32,
+    pub friction: f32,
+    pub accelerate: f32,
+    pub air_accelerate: f32,
+    pub jump_speed: f32,
+    /// Max speed multiplier when ducked
+    pub duck_scale: f32,
+    pub duck_height: f32,
+    /// Maximum height of a stair step the player can walk onto
+    pub step_height: f32,
+    /// Speed of the free flying modes (Noclip and Spectator)
+    pub fly_speed: f32,
+}
+
+/// Loads the settings file at `path` again and replaces its resource in place, if it's
+/// one of the settings files. Returns whether anything was reloaded.
+/// Settings read every frame take effect immediately; the ones only used when spawning
+/// (like the player's size) apply the next time the game starts.
+pub fn reload(path: &Path, resources: &mut Resources) -> Result<bool> {
+    let loader = resources.get::<AssetLoader>().unwrap().clone();
+    if path == Path::new(GAME_SETTINGS_PATH) {
+        replace(resources, loader.load::<GameSettings>(GAME_SETTINGS_PATH)?);
+    } else if path == Path::new(PHYSICS_SETTINGS_PATH) {
+        let settings = loader.load::<PhysicsSettings>(PHYSICS_SETTINGS_PATH)?;
+        if let Some(mut timer) = resources.get_mut::<PhysicsTimer>() {
+            timer.set_step_time(settings.step_time);
+        }
+        replace(resources, settings);
+    } else if path == Path::new(MOVEMENT_SETTINGS_PATH) {
+        replace(
+            resources,
+            loader.load::<MovementSettings>(MOVEMENT_SETTINGS_PATH)?,
+        );
+    } else if path == Path::new(BINDINGS_PATH) {
+        let bindings = loader.load::<InputBindings>(BINDINGS_PATH)?;
+        if let Some(mut input_state) = resources.get_mut::<InputState>() {
+            input_state.set_bindings(bindings);
+        }
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Overwrites an existing resource; settings which aren't loaded are left alone
+fn replace<T: 'static>(resources: &mut Resources, value: T) {
+    if let Some(mut resource) = resources.get_mut::<T>() {
+        *resource = value;
+    }
+}
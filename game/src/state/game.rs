@@ -1,28 +1,64 @@
 use std::any::TypeId;
 
-use crate::{settings::*, spacetime::PhysicsTimer};
+use crate::{
+    player::{Player, Players},
+    settings::*,
+    spacetime::PhysicsTimer,
+};
 use engine::graphics::{color::Rgba, debug::DebugLines, GraphicsShared};
+use engine::input::{InputPlayer, InputRecorder, InputState, Recording};
+use engine::spacetime::Time;
 use legion::{Entity, Resources, Schedule, World};
-use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use std::path::{Path, PathBuf};
+use winit::event::{Event, VirtualKeyCode};
 
 use engine::state::*;
 
+/// What happens to the input of a game (chosen with `--record <file>` or `--replay <file>`)
+pub enum Session {
+    Play,
+    /// Save the input to a file when the game stops
+    Record(PathBuf),
+    /// Play the input saved in a file instead of the real input
+    Replay(PathBuf),
+}
+
+enum Playback {
+    None,
+    Recording(InputRecorder, PathBuf),
+    Replaying(Box<InputPlayer>),
+}
+
 pub struct GameState {
     cursor_grabbed: bool,
+    /// Runs every frame
     schedule: Schedule,
+    /// Runs on every physics step
+    fixed_schedule: Schedule,
+    /// Runs every frame, after the physics steps
+    late_schedule: Schedule,
+    playback: Playback,
 }
 
 impl GameState {
     pub fn new() -> Self {
         let schedule = Schedule::builder()
+            .add_system(crate::player::player_look_system())
+            .build();
+        let fixed_schedule = Schedule::builder()
             .add_system(engine::physics::step_system())
             .add_system(crate::player::player_movement_system())
-            .add_system(crate::player::camera_sync_system())
             .add_system(engine::physics::children_update_system())
             .build();
+        let late_schedule = Schedule::builder()
+            .add_system(crate::player::camera_sync_system())
+            .build();
         GameState {
             schedule,
+            fixed_schedule,
+            late_schedule,
             cursor_grabbed: false,
+            playback: Playback::None,
         }
     }
 }
@@ -54,15 +90,36 @@ impl State for GameState {
             opened: true,
             fps: 0.0,
         });
-        let graphics = resources.get::<GraphicsShared>().unwrap();
-        graphics.window.set_cursor_grab(true).unwrap();
-        graphics.window.set_cursor_visible(false);
+        self.playback = match resources.get::<Session>().as_deref() {
+            Some(Session::Record(path)) => {
+                let bindings = resources.get::<InputState>().unwrap().bindings().clone();
+                Playback::Recording(InputRecorder::new(bindings), path.clone())
+            }
+            Some(Session::Replay(path)) => match Recording::load(path) {
+                Ok(recording) => Playback::Replaying(Box::new(InputPlayer::new(recording))),
+                Err(e) => {
+                    log::error!("Failed to load the recording: {:?}", e);
+                    Playback::None
+                }
+            },
+            Some(Session::Play) | None => Playback::None,
+        };
+        grab_cursor(resources, true);
         self.cursor_grabbed = true;
     }
 
     fn on_stop(&mut self, world: &mut legion::World, resources: &mut legion::Resources) {
+        if let Playback::Recording(recorder, path) =
+            std::mem::replace(&mut self.playback, Playback::None)
+        {
+            match recorder.finish().save(&path) {
+                Ok(()) => log::info!("Input recorded to {:?}", path),
+                Err(e) => log::error!("Failed to save the recording: {:?}", e),
+            }
+        }
         resources.remove::<GameSettings>();
         resources.remove::<PhysicsSettings>();
+        resources.remove::<MovementSettings>();
         resources.remove::<PhysicsTimer>();
         //resources.remove::<Atlas>();
 
@@ -78,51 +135,126 @@ impl State for GameState {
         to_remove.into_iter().for_each(|e| {
             world.remove(e);
         });
-        let graphics = resources.get::<GraphicsShared>().unwrap();
-        graphics.window.set_cursor_grab(false).unwrap();
-        graphics.window.set_cursor_visible(true);
+        grab_cursor(resources, false);
     }
 
     fn handle_event(
         &mut self,
-        _world: &mut World,
+        world: &mut World,
         resources: &mut Resources,
         event: winit::event::Event<CustomEvent>,
     ) -> Transition {
         match event {
-            Event::WindowEvent {
-                event:
-                    WindowEvent::KeyboardInput {
-                        input:
-                            KeyboardInput {
-                                state: ElementState::Pressed,
-                                virtual_keycode: Some(code),
-                                ..
-                            },
-                        ..
-                    },
-                ..
-            } => match code {
-                VirtualKeyCode::Back => Transition::Pop,
-                VirtualKeyCode::Escape => {
-                    let graphics = resources.get::<GraphicsShared>().unwrap();
-                    graphics
-                        .window
-                        .set_cursor_grab(!self.cursor_grabbed)
-                        .unwrap();
-                    graphics.window.set_cursor_visible(self.cursor_grabbed);
-                    self.cursor_grabbed = !self.cursor_grabbed;
-                    // Pause the game
-                    Transition::None
+            Event::UserEvent(CustomEvent::AssetChanged(path))
+                if path == Path::new(super::loading::SCENE_PATH) =>
+            {
+                reload_scene(world, resources);
+                Transition::None
+            }
+            Event::UserEvent(CustomEvent::AssetChanged(path)) => {
+                match crate::settings::reload(&path, resources) {
+                    Ok(true) => log::info!("Reloaded settings from {:?}", path),
+                    Ok(false) => {}
+                    // Keep playing with the old settings
+                    Err(e) => log::error!("Failed to reload settings: {:?}", e),
                 }
-                _ => Transition::None,
-            },
+                Transition::None
+            }
             _ => Transition::None,
         }
     }
 
+    fn fixed_update(&mut self, world: &mut World, resources: &mut Resources) {
+        self.fixed_schedule.execute(world, resources);
+        match &mut self.playback {
+            Playback::Recording(recorder, _) => recorder.record_physics_step(),
+            Playback::Replaying(player) => player.play_physics_step(),
+            Playback::None => {}
+        }
+    }
+
+    fn late_update(&mut self, world: &mut World, resources: &mut Resources) {
+        // The camera follows the player wherever the physics steps moved it
+        self.late_schedule.execute(world, resources);
+    }
+
     fn update(&mut self, world: &mut World, resources: &mut Resources) -> Transition {
+        // Record this frame's input, or replace it and the timing with the recorded ones,
+        // before the frame's fixed updates see them
+        match &mut self.playback {
+            Playback::Recording(recorder, _) => recorder.record_frame(
+                &resources.get::<Time>().unwrap(),
+                &resources.get::<InputState>().unwrap(),
+            ),
+            Playback::Replaying(player) => {
+                if !player.is_in_sync() {
+                    log::warn!("Replay is out of sync with the recording");
+                }
+                let mut time = resources.get_mut::<Time>().unwrap();
+                let mut input_state = resources.get_mut::<InputState>().unwrap();
+                if player.play_frame(&mut time, &mut input_state).is_none() {
+                    log::info!("Replay finished");
+                    self.playback = Playback::None;
+                }
+            }
+            Playback::None => {}
+        }
+
         self.schedule.execute(world, resources);
+
+        let input_state = resources.get::<InputState>().unwrap();
+        let pressed = |key| input_state.was_key_pressed_this_frame(&key);
+        if pressed(VirtualKeyCode::Back) {
+            return Transition::Pop;
+        }
+        if pressed(VirtualKeyCode::Escape) {
+            self.cursor_grabbed = !self.cursor_grabbed;
+            grab_cursor(resources, self.cursor_grabbed);
+            // Pause the game
+        }
+        if pressed(VirtualKeyCode::F5) {
+            let loader = resources.get::<engine::assets::AssetLoader>().unwrap();
+            match loader.save_scene(world, super::loading::SCENE_PATH) {
+                Ok(path) => log::info!("Scene saved to {:?}", path),
+                Err(e) => log::error!("Failed to save the scene: {:?}", e),
+            }
+        }
+        if pressed(VirtualKeyCode::V) {
+            // Cycle between walking, noclip and spectating
+            let players = resources.get::<Players>().unwrap();
+            if let Some(mut entry) = world.entry(players[0]) {
+                let player = entry.get_component_mut::<Player>().unwrap();
+                player.state = player.state.cycle();
+                log::info!("Player mode: {:?}", player.state);
+            }
+        }
         Transition::None
     }
 }
+
+/// Respawns the scene's objects from the changed scene file, keeping the old ones
+/// if it can't be loaded. The player isn't part of the scene, so it stays where it is.
+fn reload_scene(world: &mut World, resources: &Resources) {
+    let loader = resources
+        .get::<engine::assets::AssetLoader>()
+        .unwrap()
+        .clone();
+    let graphics = resources.get::<GraphicsShared>().unwrap().clone();
+    let scope = Scoped {
+        id: TypeId::of::<GameState>(),
+    };
+    match loader.reload_scene(world, &graphics, super::loading::SCENE_PATH, Some(scope)) {
+        Ok(()) => log::info!("Reloaded the scene from {:?}", super::loading::SCENE_PATH),
+        // Keep playing in the old scene
+        Err(e) => log::error!("Failed to reload the scene: {:?}", e),
+    }
+}
+
+/// Locks the cursor in the window and hides it, or releases it
+fn grab_cursor(resources: &Resources, grab: bool) {
+    let graphics = resources.get::<GraphicsShared>().unwrap();
+    if let Some(window) = &graphics.window {
+        window.set_cursor_grab(grab).unwrap();
+        window.set_cursor_visible(!grab);
+    }
+}
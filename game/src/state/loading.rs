@@ -1,30 +1,56 @@
+use std::path::Path;
+
 use engine::graphics::{Camera, MainCamera};
+use eyre::{eyre::WrapErr, Result};
+use futures::executor::block_on;
+use legion::{IntoQuery, Schedule};
 
 use crate::{
-    player::{Player, PlayerState},
-    settings::{GameSettings, PhysicsSettings},
+    player::{self, Player, PlayerState, SpawnPoint},
+    settings::{self, GameSettings, MovementSettings, PhysicsSettings},
     spacetime::{PhysicsTimer, Position},
 };
 
 use engine::{
+    assets::{AssetLoader, SceneLoad},
     graphics, physics,
     state::{CustomEvent, Scoped, State, Transition},
+    ui::LoadingWindow,
 };
 
 use super::game::GameState;
 
+/// The scene loaded on start (and saved to with F5)
+pub const SCENE_PATH: &str = "scenes/test.ron";
+
+/// Loads the settings and the scene (in the background) before the game starts
 pub struct LoadingState {
-    done: bool,
+    scene: Option<SceneLoad>,
+    window: LoadingWindow,
 }
 
 impl LoadingState {
     pub fn new() -> Self {
-        LoadingState { done: false }
+        LoadingState {
+            scene: None,
+            window: LoadingWindow::default(),
+        }
     }
 }
 
 impl State for LoadingState {
-    fn on_start(&mut self, _world: &mut legion::World, _resources: &mut legion::Resources) {}
+    fn on_start(&mut self, _world: &mut legion::World, resources: &mut legion::Resources) {
+        if let Err(error) = load_settings(resources) {
+            self.fail(error);
+            return;
+        }
+
+        let loader = resources.get::<AssetLoader>().unwrap();
+        let scope = Scoped {
+            id: std::any::TypeId::of::<GameState>(),
+        };
+        self.scene = Some(loader.start_loading_scene(SCENE_PATH, Some(scope)));
+    }
 
     fn handle_event(
         &mut self,
@@ -40,10 +66,26 @@ impl State for LoadingState {
         world: &mut legion::World,
         resources: &mut legion::Resources,
     ) -> Transition {
-        self.continue_loading(world, resources);
+        if let Some(scene) = &mut self.scene {
+            let graphics = resources.get::<graphics::GraphicsShared>().unwrap().clone();
+            match scene.poll(world, &graphics) {
+                Ok(true) => {
+                    spawn_player(world, resources);
+                    return Transition::Switch(Box::new(GameState::new()));
+                }
+                Ok(false) => self.window.progress = scene.progress(),
+                Err(error) => self.fail(error),
+            }
+        }
 
-        if self.done {
-            Transition::Switch(Box::new(GameState::new()))
+        let back = {
+            let ctx = resources.get::<egui::CtxRef>().unwrap();
+            self.window.show(&ctx)
+        };
+        if back {
+            // The game never started, so its resources have to be removed here
+            remove_settings(resources);
+            Transition::Pop
         } else {
             Transition::None
         }
@@ -51,77 +93,130 @@ impl State for LoadingState {
 }
 
 impl LoadingState {
-    fn continue_loading(&mut self, world: &mut legion::World, resources: &mut legion::Resources) {
-        // Load settings
-        let (settings, p_settings) = {
-            let asset_loader = resources.get::<engine::assets::AssetLoader>().unwrap();
-
-            let settings = asset_loader
-                .load::<GameSettings>("settings/game.ron")
-                .unwrap();
-            let p_settings = asset_loader
-                .load::<PhysicsSettings>("settings/physics.ron")
-                .unwrap();
-
-            (settings, p_settings)
-        };
-        resources.insert(settings);
-
-        let timer = PhysicsTimer::new(p_settings.step_time);
-        resources.insert(timer);
-        resources.insert(p_settings);
+    /// Stops loading and shows the error
+    fn fail(&mut self, error: eyre::Report) {
+        log::error!("Loading failed: {:?}", error);
+        self.scene = None;
+        self.window.error = Some(format!("{:#}", error));
+    }
+}
 
-        // Player
-        let pos: Position = na::Isometry3::from_parts(
-            na::Translation3::new(0.0, -2.0, 0.1),
-            na::UnitQuaternion::from_axis_angle(&na::Vector3::z_axis(), -90.0_f32.to_radians()),
+fn load_settings(resources: &mut legion::Resources) -> Result<()> {
+    let (settings, p_settings, m_settings) = {
+        let asset_loader = resources.get::<AssetLoader>().unwrap();
+        (
+            asset_loader.load::<GameSettings>(settings::GAME_SETTINGS_PATH)?,
+            asset_loader.load::<PhysicsSettings>(settings::PHYSICS_SETTINGS_PATH)?,
+            asset_loader.load::<MovementSettings>(settings::MOVEMENT_SETTINGS_PATH)?,
         )
-        .into();
-        use nc::shape::{Capsule, ShapeHandle};
-        let collider = physics::Collider::from(
-            // TODO: Load from settings
-            ShapeHandle::new(Capsule::new(2.0, 0.4)),
-        );
-        let vel = physics::Velocity::new(na::Vector3::repeat(0.0_f32), na::Vector3::repeat(0.0));
-        let player = Player {
-            state: PlayerState::Noclip,
-            ground_entity: None,
-            flags: 0,
-            look_pitch: 0.0,
-        };
-
-        // Scene
-        let camera = {
-            let graphics = resources.get::<graphics::GraphicsShared>().unwrap();
-            let loader = resources.get::<engine::assets::AssetLoader>().unwrap();
-            let scope = Scoped {
-                id: std::any::TypeId::of::<GameState>(),
-            };
-            loader
-                .load_scene(world, &graphics, "scenes/test.ron", Some(scope))
-                .unwrap();
-
-            // Camera
-            // Set up the camera
-            let size = graphics.window.inner_size();
-            let aspect = size.width as f32 / size.height as f32;
-            Camera::new(aspect, 45_f32.to_radians(), 0.001, 1000.0)
-        };
-        // TODO: Maybe move to GameState
-        let main_camera = MainCamera {
-            camera,
-            position: na::Isometry3::identity().into(),
-        };
-        resources.insert(main_camera);
+    };
+
+    let timer = PhysicsTimer::new(p_settings.step_time);
+    resources.insert(timer);
+    resources.insert(settings);
+    resources.insert(p_settings);
+    resources.insert(m_settings);
+    Ok(())
+}
 
-        // Add the player to the world and keep it's Entity (an ID)
-        // so we can add it to a Resource to track the single main player
-        let atlas = world.push((pos, collider, vel, player));
+fn remove_settings(resources: &mut legion::Resources) {
+    resources.remove::<GameSettings>();
+    resources.remove::<PhysicsSettings>();
+    resources.remove::<MovementSettings>();
+    resources.remove::<PhysicsTimer>();
+}
 
-        let players: crate::player::Players = vec![atlas];
-        resources.insert(players);
+/// Sets up the camera and the player, once the scene is loaded
+fn spawn_player(world: &mut legion::World, resources: &mut legion::Resources) {
+    let (collider, player_height, window_size) = {
+        let settings = resources.get::<GameSettings>().unwrap();
+        (
+            player::player_collider(&settings),
+            settings.player_height,
+            (settings.window_width as f32, settings.window_height as f32),
+        )
+    };
+
+    // Camera
+    let camera = {
+        let graphics = resources.get::<graphics::GraphicsShared>().unwrap();
+        // Set up the camera
+        let (width, height) = match &graphics.window {
+            Some(window) => (
+                window.inner_size().width as f32,
+                window.inner_size().height as f32,
+            ),
+            // Headless graphics render at the window size from the settings
+            None => window_size,
+        };
+        Camera::new(width / height, 45_f32.to_radians(), 0.001, 1000.0)
+    };
+    // TODO: Maybe move to GameState
+    let main_camera = MainCamera {
+        camera,
+        position: na::Isometry3::identity().into(),
+    };
+    resources.insert(main_camera);
+
+    // Player
+    // Spawn at the scene's SpawnPoint, or just above the floor if the scene doesn't have one
+    // (Positions are at the center of the player's capsule)
+    let (pos, state) = <(&Position, &SpawnPoint)>::query()
+        .iter(world)
+        .next()
+        .map(|(pos, spawn_point)| (*pos, spawn_point.state))
+        .unwrap_or_else(|| {
+            let pos = na::Isometry3::from_parts(
+                na::Translation3::new(0.0, -2.0, player_height / 2.0 + 0.1),
+                na::UnitQuaternion::from_axis_angle(&na::Vector3::z_axis(), -90.0_f32.to_radians()),
+            );
+            (pos.into(), PlayerState::Normal)
+        });
+    let vel = physics::Velocity::new(na::Vector3::repeat(0.0_f32), na::Vector3::repeat(0.0));
+    let player = Player {
+        state,
+        ground_entity: None,
+        flags: 0,
+        look_pitch: 0.0,
+    };
+
+    // Add the player to the world and keep it's Entity (an ID)
+    // so we can add it to a Resource to track the single main player
+    // The player moves itself, so the physics step should only treat it as an obstacle
+    let atlas = world.push((pos, collider, vel, player, physics::Kinematic));
+
+    let players: crate::player::Players = vec![atlas];
+    resources.insert(players);
+}
 
-        //resources.insert(atlas);
-        self.done = true;
-    }
+/// Loads the scene like the game does and renders the player's view of it to an image file,
+/// without opening a window (for `--screenshot <file>`). Renders at the window size from
+/// the settings.
+pub fn screenshot(
+    path: &Path,
+    world: &mut legion::World,
+    resources: &mut legion::Resources,
+) -> Result<()> {
+    load_settings(resources)?;
+    let (width, height) = {
+        let settings = resources.get::<GameSettings>().unwrap();
+        (settings.window_width as u32, settings.window_height as u32)
+    };
+    let mut graphics = block_on(graphics::setup_headless(width, height, world, resources))?;
+
+    let loader = resources.get::<AssetLoader>().unwrap().clone();
+    loader.load_scene(world, &graphics.shared, SCENE_PATH, None)?;
+    spawn_player(world, resources);
+    // Look through the player's eyes
+    Schedule::builder()
+        .add_system(player::camera_sync_system())
+        .build()
+        .execute(world, resources);
+
+    let image = graphics.render(world, resources)?;
+    image
+        .save(path)
+        .wrap_err_with(|| format!("Failed to save the screenshot to {:?}", path))?;
+    log::info!("Screenshot saved to {:?}", path);
+    Ok(())
 }
@@ -1,8 +1,8 @@
 use legion::{Resources, Schedule, World};
-use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event::VirtualKeyCode;
 
 use super::loading::LoadingState;
-use engine::ui::StartWindow;
+use engine::{input::InputState, ui::StartWindow};
 
 use engine::state::{CustomEvent, State, Transition};
 
@@ -42,44 +42,29 @@ impl State for MainState {
         start.play_pressed = false;
     }
 
-    fn handle_event(
-        &mut self,
-        _world: &mut World,
-        resources: &mut Resources,
-        event: winit::event::Event<CustomEvent>,
-    ) -> Transition {
-        match event {
-            Event::WindowEvent {
-                event:
-                    WindowEvent::KeyboardInput {
-                        input:
-                            KeyboardInput {
-                                state: ElementState::Pressed,
-                                virtual_keycode,
-                                ..
-                            },
-                        ..
-                    },
-                ..
-            } => match virtual_keycode {
-                Some(VirtualKeyCode::Escape) => {
-                    resources
-                        .get::<winit::event_loop::EventLoopProxy<CustomEvent>>()
-                        .unwrap()
-                        .send_event(CustomEvent::Exit)
-                        .unwrap();
-                    // Doesn't matter
-                    Transition::None
-                }
-                Some(VirtualKeyCode::Return) => Transition::Push(Box::new(LoadingState::new())),
-                _ => Transition::None,
-            },
-            _ => Transition::None,
-        }
-    }
-
     fn update(&mut self, world: &mut World, resources: &mut Resources) -> Transition {
         self.schedule.execute(world, resources);
+
+        let (exit, start) = {
+            let input_state = resources.get::<InputState>().unwrap();
+            (
+                input_state.was_key_pressed_this_frame(&VirtualKeyCode::Escape),
+                input_state.was_key_pressed_this_frame(&VirtualKeyCode::Return),
+            )
+        };
+        if exit {
+            resources
+                .get::<winit::event_loop::EventLoopProxy<CustomEvent>>()
+                .unwrap()
+                .send_event(CustomEvent::Exit)
+                .unwrap();
+            // Doesn't matter
+            return Transition::None;
+        }
+        if start {
+            return Transition::Push(Box::new(LoadingState::new()));
+        }
+
         // Draw UI
         {
             let ctx = resources.get::<egui::CtxRef>().unwrap();
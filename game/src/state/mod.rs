@@ -2,6 +2,6 @@ mod game;
 mod loading;
 mod main;
 
-pub use game::GameState;
-pub use loading::LoadingState;
+pub use game::Session;
+pub use loading::screenshot;
 pub use main::MainState;